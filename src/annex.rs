@@ -0,0 +1,538 @@
+//! git-annex external special remote, letting git-annex keep annexed content in any store.
+//!
+//! git-annex runs `git-annex-remote-pigeonhole` for remotes set up with
+//! `git annex initremote <name> type=external externaltype=pigeonhole store=<location>`, where
+//! the location is anything [`store::open`] accepts, and speaks the external special remote
+//! protocol with it over standard input and output. Keys are derived from the password in
+//! `PIGEONHOLE_PASSWORD` and a random salt, which `initremote` keeps in the remote's config, so
+//! remotes sharing a password share no keys.
+//!
+//! Annexed files are stored like any other file, as chunks encrypted with a [`FileEncryptor`].
+//! The [`FileManifest`] of each annex key is stored as an encrypted blob that also records the
+//! key, and the content id of that blob is kept in the remote's per-key state in the git-annex
+//! branch, so every clone of the repository can find it.
+//!
+//! [`FileEncryptor`]: crate::crypto::aead::FileEncryptor
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto,
+    error::{Error, Result},
+    file::FileManifest,
+    store::{self, hex, parse_hex, ChunkStore, ContentId},
+    zeroize_allocator::Zeroing,
+};
+
+const PASSWORD_VARIABLE: &str = "PIGEONHOLE_PASSWORD";
+const PRK_NAME: &str = "git-annex";
+
+/// What is stored for each annex key, binding the manifest to the key it was stored under so a
+/// state value copied to another key is rejected.
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    key: String,
+    manifest: Vec<u8>,
+}
+
+struct Prepared {
+    store: Box<dyn ChunkStore>,
+    prk: Zeroing<[u8; 32]>,
+}
+
+struct SpecialRemote<R, W> {
+    reader: R,
+    writer: W,
+    password: Option<String>,
+    prepared: Option<Prepared>,
+}
+
+/// Runs the special remote on standard input and output until git-annex closes them.
+pub fn run_special_remote() -> io::Result<()> {
+    let password = std::env::var(PASSWORD_VARIABLE).ok();
+    SpecialRemote::new(io::stdin().lock(), io::stdout().lock(), password)
+        .run()
+        .map_err(io::Error::from)
+}
+
+fn reply(response: &str, result: Result<()>) -> String {
+    match result {
+        Ok(()) => format!("{response}-SUCCESS"),
+        Err(e) => format!("{response}-FAILURE {e}"),
+    }
+}
+
+impl<R: BufRead, W: Write> SpecialRemote<R, W> {
+    fn new(reader: R, writer: W, password: Option<String>) -> Self {
+        Self {
+            reader,
+            writer,
+            password,
+            prepared: None,
+        }
+    }
+
+    /// Sends `line`, with any line feeds in error messages it includes replaced.
+    fn send(&mut self, line: &str) -> Result<()> {
+        writeln!(self.writer, "{}", line.replace('\n', " "))?;
+        Ok(self.writer.flush()?)
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_owned()))
+    }
+
+    /// Asks git-annex for a value, such as a config setting or the state of a key.
+    fn query(&mut self, request: &str) -> Result<String> {
+        self.send(request)?;
+        let Some(response) = self.read_line()? else {
+            return Err(Error::Remote("git-annex closed its output".to_owned()));
+        };
+        match response.split_once(' ').unwrap_or((&response, "")) {
+            ("VALUE", value) => Ok(value.to_owned()),
+            _ => Err(Error::Remote(format!(
+                "unexpected git-annex response to {request}: {response:?}"
+            ))),
+        }
+    }
+
+    fn run(mut self) -> Result<()> {
+        self.send("VERSION 1")?;
+        while let Some(line) = self.read_line()? {
+            let (command, args) = line.split_once(' ').unwrap_or((&line, ""));
+            let response = match command {
+                "EXTENSIONS" => "EXTENSIONS".to_owned(),
+                "INITREMOTE" => reply("INITREMOTE", self.init()),
+                "PREPARE" => reply("PREPARE", self.prepare()),
+                "TRANSFER" => {
+                    let mut args = args.splitn(3, ' ');
+                    let (direction, key, file) = (
+                        args.next().unwrap_or_default(),
+                        args.next().unwrap_or_default(),
+                        args.next().unwrap_or_default(),
+                    );
+                    let result = match direction {
+                        "STORE" => self.store(key, file),
+                        "RETRIEVE" => self.retrieve(key, file),
+                        _ => {
+                            self.send("UNSUPPORTED-REQUEST")?;
+                            continue;
+                        }
+                    };
+                    match result {
+                        Ok(()) => format!("TRANSFER-SUCCESS {direction} {key}"),
+                        Err(e) => format!("TRANSFER-FAILURE {direction} {key} {e}"),
+                    }
+                }
+                "CHECKPRESENT" => match self.check_present(args) {
+                    Ok(true) => format!("CHECKPRESENT-SUCCESS {args}"),
+                    Ok(false) => format!("CHECKPRESENT-FAILURE {args}"),
+                    Err(e) => format!("CHECKPRESENT-UNKNOWN {args} {e}"),
+                },
+                "REMOVE" => match self.remove(args) {
+                    Ok(()) => format!("REMOVE-SUCCESS {args}"),
+                    Err(e) => format!("REMOVE-FAILURE {args} {e}"),
+                },
+                "ERROR" => return Err(Error::Remote(format!("git-annex failed: {args}"))),
+                _ => "UNSUPPORTED-REQUEST".to_owned(),
+            };
+            self.send(&response)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the remote up with a new salt, unless it is enabled again with the salt it had, and
+    /// prepares it.
+    fn init(&mut self) -> Result<()> {
+        let salt = match parse_hex(&self.query("GETCONFIG salt")?) {
+            Some(salt) => salt,
+            None => {
+                let mut salt = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut salt);
+                self.send(&format!("SETCONFIG salt {}", hex(&salt)))?;
+                salt
+            }
+        };
+        self.open(&salt)
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        let salt = self.query("GETCONFIG salt")?;
+        let salt = parse_hex(&salt).ok_or_else(|| {
+            Error::Remote(format!(
+                "invalid salt {salt:?}, the remote was not set up by initremote"
+            ))
+        })?;
+        self.open(&salt)
+    }
+
+    /// Opens the configured store and derives the keys from the password and `salt`.
+    fn open(&mut self, salt: &[u8]) -> Result<()> {
+        let location = self.query("GETCONFIG store")?;
+        let password = self
+            .password
+            .as_ref()
+            .ok_or(Error::MissingEnvironment(PASSWORD_VARIABLE))?;
+        let prk = crypto::derive_prk(&crypto::generate_salted_prk(password, salt)?, PRK_NAME)?;
+        self.prepared = Some(Prepared {
+            store: store::open(&location)?,
+            prk,
+        });
+        Ok(())
+    }
+
+    fn prepared(&self) -> Result<&Prepared> {
+        self.prepared
+            .as_ref()
+            .ok_or_else(|| Error::Remote("remote was not prepared".to_owned()))
+    }
+
+    /// Content id of the blob stored for `key`, if git-annex recorded one.
+    fn state(&mut self, key: &str) -> Result<Option<ContentId>> {
+        self.prepared()?;
+        let state = self.query(&format!("GETSTATE {key}"))?;
+        if state.is_empty() {
+            return Ok(None);
+        }
+        match parse_hex(&state) {
+            Some(id) => Ok(Some(id)),
+            None => Err(Error::Remote(format!("invalid state {state:?} for {key}"))),
+        }
+    }
+
+    fn manifest(&self, key: &str, id: &ContentId) -> Result<FileManifest> {
+        let prepared = self.prepared()?;
+        let stored: StoredKey =
+            bincode::deserialize(&store::get_blob(&prepared.store, &prepared.prk, id)?)?;
+        if stored.key != key {
+            return Err(Error::Remote(format!(
+                "state of {key} refers to the content of {}",
+                stored.key
+            )));
+        }
+        FileManifest::parse(&stored.manifest)
+    }
+
+    fn store(&mut self, key: &str, file: &str) -> Result<()> {
+        if self.check_present(key)? {
+            return Ok(());
+        }
+        let prepared = self.prepared()?;
+        let manifest = store::put_file(&prepared.store, &prepared.prk, fs::File::open(file)?)?;
+        let stored = StoredKey {
+            key: key.to_owned(),
+            manifest: manifest.to_bytes(),
+        };
+        let id = store::put_blob(
+            &prepared.store,
+            &prepared.prk,
+            &bincode::serialize(&stored)?,
+        )?;
+        self.send(&format!("SETSTATE {key} {}", hex(&id)))
+    }
+
+    fn retrieve(&mut self, key: &str, file: &str) -> Result<()> {
+        let id = self
+            .state(key)?
+            .ok_or_else(|| Error::Remote(format!("{key} is not stored in this remote")))?;
+        let manifest = self.manifest(key, &id)?;
+        let prepared = self.prepared()?;
+        let mut writer = io::BufWriter::new(fs::File::create(file)?);
+        store::get_file(&prepared.store, &prepared.prk, &manifest, &mut writer)?;
+        Ok(writer.into_inner().map_err(io::Error::from)?.sync_all()?)
+    }
+
+    fn check_present(&mut self, key: &str) -> Result<bool> {
+        match self.state(key)? {
+            Some(id) => self.prepared()?.store.has(&id),
+            None => Ok(false),
+        }
+    }
+
+    /// Deletes the blob for `key` before its chunks, so an interrupted removal never leaves the
+    /// key looking present with chunks missing.
+    fn remove(&mut self, key: &str) -> Result<()> {
+        let Some(id) = self.state(key)? else {
+            return Ok(());
+        };
+        match self.manifest(key, &id) {
+            Ok(manifest) => {
+                let store = &self.prepared()?.store;
+                store.delete(&id)?;
+                for chunk in manifest.content_ids() {
+                    store.delete(chunk)?;
+                }
+            }
+            Err(Error::ChunkNotFound(missing)) if missing == id => {}
+            Err(e) => return Err(e),
+        }
+        self.send(&format!("SETSTATE {key} "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::BufReader,
+        os::unix::net::UnixStream,
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+    use crate::store::local::LocalStore;
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    /// Plays the part of git-annex, answering the remote's queries from its config and state.
+    struct Annex {
+        reader: BufReader<UnixStream>,
+        writer: UnixStream,
+        config: HashMap<String, String>,
+        state: HashMap<String, String>,
+        remote: Option<JoinHandle<Result<()>>>,
+    }
+
+    impl Annex {
+        fn start(store: &str, password: Option<&str>) -> Self {
+            let (annex, remote) = UnixStream::pair().unwrap();
+            let password = password.map(str::to_owned);
+            let remote = thread::spawn(move || {
+                let reader = BufReader::new(remote.try_clone().unwrap());
+                SpecialRemote::new(reader, remote, password).run()
+            });
+            let mut annex = Self {
+                reader: BufReader::new(annex.try_clone().unwrap()),
+                writer: annex,
+                config: HashMap::from([("store".to_owned(), store.to_owned())]),
+                state: HashMap::new(),
+                remote: Some(remote),
+            };
+            assert_eq!(annex.read_line(), "VERSION 1");
+            annex
+        }
+
+        fn read_line(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            line.trim_end().to_owned()
+        }
+
+        /// Sends `request`, returning the response once the remote is done querying.
+        fn request(&mut self, request: &str) -> String {
+            writeln!(self.writer, "{request}").unwrap();
+            loop {
+                let line = self.read_line();
+                let mut words = line.splitn(3, ' ');
+                let value = match (words.next().unwrap(), words.next(), words.next()) {
+                    ("GETCONFIG", Some(name), None) => self.config.get(name),
+                    ("SETCONFIG", Some(name), Some(value)) => {
+                        self.config.insert(name.to_owned(), value.to_owned());
+                        continue;
+                    }
+                    ("GETSTATE", Some(key), None) => self.state.get(key),
+                    ("SETSTATE", Some(key), value) => {
+                        self.state
+                            .insert(key.to_owned(), value.unwrap_or_default().to_owned());
+                        continue;
+                    }
+                    _ => return line,
+                };
+                let value = value.cloned().unwrap_or_default();
+                writeln!(self.writer, "VALUE {value}").unwrap();
+            }
+        }
+
+        fn finish(mut self) -> Result<()> {
+            self.writer.shutdown(std::net::Shutdown::Both).unwrap();
+            self.remote.take().unwrap().join().unwrap()
+        }
+    }
+
+    fn local(dir: &tempfile::TempDir) -> String {
+        format!("local:{}", dir.path().join("store").display())
+    }
+
+    #[test]
+    fn stores_retrieves_and_removes_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut annex = Annex::start(&local(&dir), Some("password"));
+        let key = "SHA256E-s77--6a1e.txt";
+
+        assert_eq!(annex.request("EXTENSIONS INFO ASYNC"), "EXTENSIONS");
+        assert_eq!(annex.request("INITREMOTE"), "INITREMOTE-SUCCESS");
+        assert_eq!(annex.request("PREPARE"), "PREPARE-SUCCESS");
+        assert_eq!(
+            annex.request(&format!("CHECKPRESENT {key}")),
+            format!("CHECKPRESENT-FAILURE {key}")
+        );
+        assert_eq!(
+            annex.request(&format!("TRANSFER STORE {key} {PATH}")),
+            format!("TRANSFER-SUCCESS STORE {key}")
+        );
+        assert_eq!(
+            annex.request(&format!("CHECKPRESENT {key}")),
+            format!("CHECKPRESENT-SUCCESS {key}")
+        );
+
+        // The content is chunked and only readable through the state git-annex keeps
+        let store = LocalStore::new(dir.path().join("store")).unwrap();
+        assert!(store.list().unwrap().len() > 2);
+        assert_eq!(annex.state[key].len(), 64);
+
+        let retrieved = dir.path().join("retrieved file");
+        assert_eq!(
+            annex.request(&format!("TRANSFER RETRIEVE {key} {}", retrieved.display())),
+            format!("TRANSFER-SUCCESS RETRIEVE {key}")
+        );
+        assert_eq!(fs::read(&retrieved).unwrap(), fs::read(PATH).unwrap());
+
+        assert_eq!(
+            annex.request(&format!("REMOVE {key}")),
+            format!("REMOVE-SUCCESS {key}")
+        );
+        assert_eq!(
+            annex.request(&format!("REMOVE {key}")),
+            format!("REMOVE-SUCCESS {key}")
+        );
+        assert_eq!(
+            annex.request(&format!("CHECKPRESENT {key}")),
+            format!("CHECKPRESENT-FAILURE {key}")
+        );
+        assert!(store.list().unwrap().is_empty());
+        annex.finish().unwrap();
+    }
+
+    #[test]
+    fn rejects_state_copied_from_another_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut annex = Annex::start(&local(&dir), Some("password"));
+        assert_eq!(annex.request("INITREMOTE"), "INITREMOTE-SUCCESS");
+        annex.request(&format!("TRANSFER STORE a {PATH}"));
+
+        let state = annex.state["a"].clone();
+        annex.state.insert("b".to_owned(), state);
+        let retrieved = dir.path().join("b");
+        let response = annex.request(&format!("TRANSFER RETRIEVE b {}", retrieved.display()));
+        assert!(
+            response.starts_with("TRANSFER-FAILURE RETRIEVE b "),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn reports_wrapped_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut annex = Annex::start(&local(&dir), Some("password"));
+        assert_eq!(annex.request("INITREMOTE"), "INITREMOTE-SUCCESS");
+        let missing = dir.path().join("missing");
+        assert_eq!(
+            annex.request(&format!("TRANSFER STORE a {}", missing.display())),
+            format!(
+                "TRANSFER-FAILURE STORE a {}",
+                io::Error::from_raw_os_error(2)
+            )
+        );
+    }
+
+    #[test]
+    fn cannot_read_content_with_another_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut annex = Annex::start(&local(&dir), Some("password"));
+        assert_eq!(annex.request("INITREMOTE"), "INITREMOTE-SUCCESS");
+        annex.request(&format!("TRANSFER STORE a {PATH}"));
+        let (config, state) = (annex.config.clone(), annex.state.clone());
+        annex.finish().unwrap();
+
+        let mut annex = Annex::start(&local(&dir), Some("guess"));
+        (annex.config, annex.state) = (config, state);
+        assert_eq!(annex.request("PREPARE"), "PREPARE-SUCCESS");
+        let retrieved = dir.path().join("a");
+        let response = annex.request(&format!("TRANSFER RETRIEVE a {}", retrieved.display()));
+        assert!(
+            response.starts_with("TRANSFER-FAILURE RETRIEVE a "),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn fails_to_prepare_without_password_or_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut annex = Annex::start(&local(&dir), None);
+        assert_eq!(
+            annex.request("INITREMOTE"),
+            format!("INITREMOTE-FAILURE environment variable {PASSWORD_VARIABLE} is not set")
+        );
+
+        let mut annex = Annex::start("tape:/dev/st0", Some("password"));
+        assert_eq!(
+            annex.request("INITREMOTE"),
+            "INITREMOTE-FAILURE invalid store location \"tape:/dev/st0\""
+        );
+        assert!(annex
+            .request("CHECKPRESENT a")
+            .starts_with("CHECKPRESENT-UNKNOWN a "));
+    }
+
+    #[test]
+    fn salts_keys_per_remote() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut annex = Annex::start(&local(&dir), Some("password"));
+        assert_eq!(annex.request("INITREMOTE"), "INITREMOTE-SUCCESS");
+        annex.request(&format!("TRANSFER STORE a {PATH}"));
+        let (config, state) = (annex.config.clone(), annex.state.clone());
+        annex.finish().unwrap();
+
+        // Enabling the remote again keeps its salt, and so its keys
+        let mut annex = Annex::start(&local(&dir), Some("password"));
+        (annex.config, annex.state) = (config.clone(), state.clone());
+        assert_eq!(annex.request("INITREMOTE"), "INITREMOTE-SUCCESS");
+        assert_eq!(annex.config, config);
+        assert_eq!(annex.request("CHECKPRESENT a"), "CHECKPRESENT-SUCCESS a");
+        annex.finish().unwrap();
+
+        // Another remote with the same password and store cannot read the content
+        let mut annex = Annex::start(&local(&dir), Some("password"));
+        assert_eq!(annex.request("INITREMOTE"), "INITREMOTE-SUCCESS");
+        assert_ne!(annex.config["salt"], config["salt"]);
+        annex.state = state;
+        let retrieved = dir.path().join("a");
+        let response = annex.request(&format!("TRANSFER RETRIEVE a {}", retrieved.display()));
+        assert!(
+            response.starts_with("TRANSFER-FAILURE RETRIEVE a "),
+            "{response}"
+        );
+        annex.finish().unwrap();
+
+        let mut annex = Annex::start(&local(&dir), Some("password"));
+        assert_eq!(
+            annex.request("PREPARE"),
+            "PREPARE-FAILURE remote storage error: invalid salt \"\", the remote was not set up by \
+             initremote"
+        );
+    }
+
+    #[test]
+    fn declines_unsupported_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut annex = Annex::start(&local(&dir), Some("password"));
+        assert_eq!(annex.request("GETCOST"), "UNSUPPORTED-REQUEST");
+        assert_eq!(
+            annex.request("TRANSFER SIDEWAYS a b"),
+            "UNSUPPORTED-REQUEST"
+        );
+
+        writeln!(annex.writer, "ERROR protocol violation").unwrap();
+        assert!(matches!(annex.finish(), Err(Error::Remote(_))));
+    }
+}
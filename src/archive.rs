@@ -0,0 +1,542 @@
+//! Multi-file encrypted archive, for handing a directory tree over offline as one blob.
+//!
+//! All integers are little endian.
+//!
+//! | Section | Field         | Size | Description                                              |
+//! |---------|---------------|------|----------------------------------------------------------|
+//! | Header  | magic         | 4    | `PHA\0`                                                  |
+//! |         | version       | 1    | Archive format version, currently 1                      |
+//! |         | salt          | 16   | Random salt of the KDF turning the password into a key   |
+//! |         | archive key   | ...  | Record of the random archive key, wrapped with the KDF's |
+//! | Files   | chunk records | ...  | Chunk records of every file, see [`EncryptingWriter`]    |
+//! | Index   | index record  | ...  | Record of the encrypted list of [`ArchiveEntry`]s        |
+//! | Footer  | signature     | ...  | Signature over the header and the index's content id     |
+//! |         | signature len | 2    | Length of the signature                                  |
+//! |         | index offset  | 8    | Position of the index record                             |
+//!
+//! A record is a `u32` length followed by an [`EncryptedChunk`]. The records of every file end
+//! with an empty chunk. Every file entry in the index holds the [`FileManifest`] of its chunk
+//! records, so the signed index authenticates the whole archive. Listing an archive only decrypts
+//! the index. An archive needs nothing but its password, which is never a vault's, so it can be
+//! handed to anyone. It is either extracted to disk or imported into a vault by
+//! [`Vault::import_archive`].
+//!
+//! [`EncryptedChunk`]: crate::crypto::aead::EncryptedChunk
+//! [`Vault::import_archive`]: crate::vault::Vault::import_archive
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    crypto::{
+        self,
+        aead::{self, AesGcmKey, ChunkKey, EncryptedChunk},
+        asym::AsymmetricCryptoKey,
+    },
+    decrypting_reader::SeekableDecryptingReader,
+    encrypting_writer::EncryptingWriter,
+    error::{Error, Result},
+    file::FileManifest,
+    path,
+    zeroize_allocator::Zeroing,
+};
+
+/// Tag of the snapshots made by importing archives into a vault
+pub(crate) const IMPORT_TAG: &str = "import";
+
+const MAGIC: &[u8; 4] = b"PHA\0";
+const VERSION: u8 = 1;
+const SALT_SIZE: usize = 16;
+/// Magic, version and salt, followed by the archive key
+const HEADER_SIZE: usize = 4 + 1 + SALT_SIZE;
+/// Signature length and index offset
+const FOOTER_SIZE: i64 = 2 + 8;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ArchiveEntry {
+    #[serde(with = "crate::path")]
+    pub path: PathBuf,
+    pub kind: ArchiveEntryKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) enum ArchiveEntryKind {
+    Directory,
+    File {
+        file_id: Uuid,
+        /// Position of the file's first chunk record
+        offset: u64,
+        manifest: FileManifest,
+    },
+}
+
+/// Paths of the entries so far, and whether they are directories.
+#[derive(Default)]
+struct EntryPaths(HashMap<PathBuf, bool>);
+
+impl EntryPaths {
+    /// Adds `path` if it is safe, new and inside a directory added before, so the entries form a
+    /// tree that can be extracted or imported in order.
+    fn add(&mut self, path: &Path, is_directory: bool) -> Result<()> {
+        path::check_relative(path)?;
+        let parent = path.parent().unwrap_or(Path::new(""));
+        if !parent.as_os_str().is_empty() && self.0.get(parent) != Some(&true) {
+            return Err(Error::InvalidArchive("entry outside of a directory entry"));
+        }
+        if self.0.insert(path.to_owned(), is_directory).is_some() {
+            return Err(Error::InvalidArchive("repeated entry"));
+        }
+        Ok(())
+    }
+}
+
+/// Key wrapping the archive key, derived from the password with the archive's own salt.
+fn wrapping_key(password: &str, salt: &[u8]) -> Result<Zeroing<[u8; 32]>> {
+    crypto::generate_salted_prk(password, salt)
+}
+
+fn signed_data(header: &[u8], index: &EncryptedChunk) -> Vec<u8> {
+    let mut data = Vec::from(header);
+    data.extend_from_slice(&index.content_id());
+    data
+}
+
+/// Counts bytes written so entries know where their chunk records start.
+struct CountingWriter<W: Write> {
+    writer: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub(crate) struct ArchiveWriter<W: Write> {
+    writer: CountingWriter<W>,
+    prk: Zeroing<[u8; 32]>,
+    header: Vec<u8>,
+    entries: Vec<ArchiveEntry>,
+    paths: EntryPaths,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Starts an archive encrypted with a random key, which only `password` unlocks.
+    pub fn new(writer: W, password: &str) -> Result<Self> {
+        let mut salt = [0u8; SALT_SIZE];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let mut prk = Box::pin([0u8; 32]);
+        rand::thread_rng().fill_bytes(&mut *prk);
+
+        let mut header = Vec::from(&MAGIC[..]);
+        header.push(VERSION);
+        header.extend_from_slice(&salt);
+        aead::encrypt_blob(&wrapping_key(password, &salt)?, &*prk)?.write_framed(&mut header)?;
+        let mut writer = CountingWriter { writer, count: 0 };
+        writer.write_all(&header)?;
+
+        Ok(Self {
+            writer,
+            prk,
+            header,
+            entries: Vec::new(),
+            paths: EntryPaths::default(),
+        })
+    }
+
+    /// Adds the directory at `path`, whose parent must have been added before.
+    pub fn add_directory(&mut self, path: &Path) -> Result<()> {
+        self.paths.add(path, true)?;
+        self.entries.push(ArchiveEntry {
+            path: path.to_owned(),
+            kind: ArchiveEntryKind::Directory,
+        });
+        Ok(())
+    }
+
+    /// Encrypts everything read from `reader` as the file at `path` in the archive, whose parent
+    /// must have been added before.
+    pub fn add_file(&mut self, path: &Path, mut reader: impl Read) -> Result<()> {
+        self.paths.add(path, false)?;
+        let file_id = Uuid::now_v7();
+        let offset = self.writer.count;
+
+        let key = AesGcmKey::generate(Box::pin(*self.prk), file_id)?;
+        let mut writer = EncryptingWriter::new(&mut self.writer, key);
+        io::copy(&mut reader, &mut writer)?;
+        let (_, manifest) = writer.finish()?;
+
+        self.entries.push(ArchiveEntry {
+            path: path.to_owned(),
+            kind: ArchiveEntryKind::File {
+                file_id,
+                offset,
+                manifest,
+            },
+        });
+        Ok(())
+    }
+
+    /// Adds every directory and regular file below `root`, with paths relative to `root`.
+    /// Symlinks and special files are skipped.
+    pub fn add_tree(&mut self, root: &Path) -> Result<()> {
+        self.add_tree_below(root, Path::new(""))
+    }
+
+    fn add_tree_below(&mut self, root: &Path, relative: &Path) -> Result<()> {
+        for entry in path::read_dir_sorted(&root.join(relative))? {
+            let path = relative.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                self.add_directory(&path)?;
+                self.add_tree_below(root, &path)?;
+            } else if file_type.is_file() {
+                self.add_file(&path, fs::File::open(entry.path())?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the encrypted index and signed footer, returning the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        let index_offset = self.writer.count;
+        let index = aead::encrypt_blob(&self.prk, &bincode::serialize(&self.entries)?)?;
+        index.write_framed(&mut self.writer)?;
+
+        let signature = crypto::signing_key(&self.prk)?.sign(&signed_data(&self.header, &index))?;
+        self.writer.write_all(&signature)?;
+        self.writer
+            .write_all(&(signature.len() as u16).to_le_bytes())?;
+        self.writer.write_all(&index_offset.to_le_bytes())?;
+        self.writer.flush()?;
+
+        Ok(self.writer.writer)
+    }
+}
+
+pub(crate) struct ArchiveReader<R: Read + Seek> {
+    reader: R,
+    prk: Zeroing<[u8; 32]>,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    /// Opens an archive with its password, verifying and decrypting its index but none of its
+    /// files. The entries are checked to form a tree of safe paths.
+    pub fn new(mut reader: R, password: &str) -> Result<Self> {
+        let mut fixed = [0u8; HEADER_SIZE];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut fixed)?;
+        if &fixed[..4] != MAGIC {
+            return Err(Error::InvalidArchive("not a pigeonhole archive"));
+        }
+        if fixed[4] != VERSION {
+            return Err(Error::InvalidArchive("unsupported version"));
+        }
+        let wrapped = EncryptedChunk::read_framed(&mut reader)?
+            .ok_or(Error::InvalidArchive("missing archive key"))?;
+        let prk = aead::decrypt_blob(&wrapping_key(password, &fixed[5..])?, &wrapped)
+            .map_err(|_| Error::WrongPassword)?;
+        let prk: [u8; 32] = prk
+            .try_into()
+            .map_err(|_| Error::InvalidArchive("archive key has the wrong length"))?;
+        let prk = Box::pin(prk);
+        let mut header = Vec::from(fixed);
+        wrapped.write_framed(&mut header)?;
+
+        let footer_offset = reader.seek(SeekFrom::End(-FOOTER_SIZE))?;
+        let mut signature_len = [0u8; 2];
+        reader.read_exact(&mut signature_len)?;
+        let mut index_offset = [0u8; 8];
+        reader.read_exact(&mut index_offset)?;
+        let signature_len = u16::from_le_bytes(signature_len) as u64;
+        let index_offset = u64::from_le_bytes(index_offset);
+
+        let signature_offset = footer_offset
+            .checked_sub(signature_len)
+            .filter(|offset| *offset > index_offset)
+            .ok_or(Error::InvalidArchive("invalid footer"))?;
+        let mut signature = vec![0u8; signature_len as usize];
+        reader.seek(SeekFrom::Start(signature_offset))?;
+        reader.read_exact(&mut signature)?;
+
+        reader.seek(SeekFrom::Start(index_offset))?;
+        let index = EncryptedChunk::read_framed(&mut reader)?
+            .ok_or(Error::InvalidArchive("missing index"))?;
+        if !crypto::signing_key(&prk)?.verify(&signed_data(&header, &index), &signature)? {
+            return Err(Error::InvalidArchive("invalid signature"));
+        }
+        let entries: Vec<ArchiveEntry> = bincode::deserialize(&aead::decrypt_blob(&prk, &index)?)?;
+        let mut paths = EntryPaths::default();
+        for entry in &entries {
+            let is_directory = matches!(entry.kind, ArchiveEntryKind::Directory);
+            paths.add(&entry.path, is_directory)?;
+        }
+
+        Ok(Self {
+            reader,
+            prk,
+            entries,
+        })
+    }
+
+    pub fn entries(&self) -> &[ArchiveEntry] {
+        &self.entries
+    }
+
+    /// Opens the file `entry` for random access, decrypting only the chunks read.
+    pub fn open_file(&mut self, entry: &ArchiveEntry) -> Result<SeekableDecryptingReader<&mut R>> {
+        let ArchiveEntryKind::File {
+            offset, manifest, ..
+        } = &entry.kind
+        else {
+            return Err(Error::InvalidArchive("entry is not a file"));
+        };
+
+        self.reader.seek(SeekFrom::Start(*offset))?;
+        SeekableDecryptingReader::new(&mut self.reader, Box::pin(*self.prk), manifest)
+    }
+
+    /// Decrypts the file `entry` into `writer`, returning the number of bytes written.
+    pub fn read_file(&mut self, entry: &ArchiveEntry, writer: &mut impl Write) -> Result<u64> {
+        Ok(io::copy(&mut self.open_file(entry)?, writer)?)
+    }
+
+    /// Extracts every entry into `dir`. Symlinks already in `dir` are never written through: files
+    /// replace them, and entries below them are refused.
+    pub fn extract(&mut self, dir: &Path) -> Result<()> {
+        for entry in self.entries.clone() {
+            path::check_relative(&entry.path)?;
+            path::check_no_symlinks(dir, &entry.path)?;
+            let target = dir.join(&entry.path);
+            match entry.kind {
+                ArchiveEntryKind::Directory => fs::create_dir_all(&target)?,
+                ArchiveEntryKind::File { .. } => {
+                    if let Some(parent) = target.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    if fs::symlink_metadata(&target).is_ok_and(|metadata| metadata.is_symlink()) {
+                        fs::remove_file(&target)?;
+                    }
+                    self.read_file(&entry, &mut fs::File::create(&target)?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "archive password";
+
+    fn tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("docs/nested")).unwrap();
+        fs::create_dir(dir.path().join("empty")).unwrap();
+        fs::copy("test/lorem_ipsum", dir.path().join("docs/lorem_ipsum")).unwrap();
+        fs::write(dir.path().join("docs/nested/binary"), [0u8, 159, 146, 150]).unwrap();
+        fs::write(dir.path().join("top"), b"").unwrap();
+        dir
+    }
+
+    fn archive(root: &Path) -> Vec<u8> {
+        let mut writer = ArchiveWriter::new(Vec::new(), PASSWORD).unwrap();
+        writer.add_tree(root).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn open(archive: &[u8]) -> Result<ArchiveReader<io::Cursor<&[u8]>>> {
+        ArchiveReader::new(io::Cursor::new(archive), PASSWORD)
+    }
+
+    fn assert_same_file(a: &Path, b: &Path) {
+        assert_eq!(fs::read(a).unwrap(), fs::read(b).unwrap(), "{a:?}");
+    }
+
+    #[test]
+    fn lists_entries() {
+        let tree = tree();
+        let archive = archive(tree.path());
+        let reader = open(&archive).unwrap();
+
+        assert_eq!(
+            reader
+                .entries()
+                .iter()
+                .map(|e| e.path.to_str().unwrap())
+                .collect::<Vec<_>>(),
+            [
+                "docs",
+                "docs/lorem_ipsum",
+                "docs/nested",
+                "docs/nested/binary",
+                "empty",
+                "top"
+            ]
+        );
+    }
+
+    #[test]
+    fn listing_does_not_decrypt_files() {
+        let tree = tree();
+        let mut archive = archive(tree.path());
+        let ArchiveEntryKind::File { offset, .. } = open(&archive).unwrap().entries()[1].kind
+        else {
+            panic!("not a file");
+        };
+        // Corrupt the first chunk record of the first file
+        archive[offset as usize + 30] ^= 1;
+
+        let mut reader = open(&archive).unwrap();
+        assert_eq!(reader.entries().len(), 6);
+        let entry = reader.entries()[1].clone();
+        assert!(reader.read_file(&entry, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn extracts_tree() {
+        let tree = tree();
+        let archive = archive(tree.path());
+        let target = tempfile::tempdir().unwrap();
+        open(&archive).unwrap().extract(target.path()).unwrap();
+
+        for file in ["docs/lorem_ipsum", "docs/nested/binary", "top"] {
+            assert_same_file(&tree.path().join(file), &target.path().join(file));
+        }
+        assert!(target.path().join("empty").is_dir());
+    }
+
+    #[test]
+    fn refuses_to_extract_through_symlinks() {
+        let tree = tree();
+        let archive = archive(tree.path());
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("top"), b"outside").unwrap();
+
+        // Files replace symlinks instead of writing to where they lead
+        let target = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path().join("top"), target.path().join("top")).unwrap();
+        open(&archive).unwrap().extract(target.path()).unwrap();
+        assert!(!target.path().join("top").is_symlink());
+        assert_eq!(fs::read(outside.path().join("top")).unwrap(), b"outside");
+
+        // Nothing is written below a symlinked directory
+        let target = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), target.path().join("docs")).unwrap();
+        assert!(matches!(
+            open(&archive).unwrap().extract(target.path()),
+            Err(Error::UnsafePath(_))
+        ));
+        assert!(!outside.path().join("lorem_ipsum").exists());
+    }
+
+    #[test]
+    fn adds_files_from_readers() {
+        let mut writer = ArchiveWriter::new(Vec::new(), PASSWORD).unwrap();
+        writer
+            .add_file(Path::new("stdin"), &b"piped data"[..])
+            .unwrap();
+        let archive = writer.finish().unwrap();
+
+        let mut reader = open(&archive).unwrap();
+        let entry = reader.entries()[0].clone();
+        let mut data = Vec::new();
+        reader.read_file(&entry, &mut data).unwrap();
+        assert_eq!(data, b"piped data");
+    }
+
+    #[test]
+    fn reads_files_at_random() {
+        let tree = tree();
+        let archive = archive(tree.path());
+        let contents = fs::read("test/lorem_ipsum").unwrap();
+
+        let mut reader = open(&archive).unwrap();
+        let entry = reader.entries()[1].clone();
+        let mut file = reader.open_file(&entry).unwrap();
+        assert_eq!(file.len(), contents.len() as u64);
+        file.seek(SeekFrom::Start(30)).unwrap();
+        let mut data = [0u8; 20];
+        file.read_exact(&mut data).unwrap();
+        assert_eq!(data, contents[30..50]);
+
+        let directory = reader.entries()[0].clone();
+        assert!(reader.open_file(&directory).is_err());
+    }
+
+    #[test]
+    fn rejects_unsafe_paths() {
+        let mut writer = ArchiveWriter::new(Vec::new(), PASSWORD).unwrap();
+        assert!(writer.add_file(Path::new("../escape"), &b""[..]).is_err());
+        assert!(writer.add_directory(Path::new("/root")).is_err());
+    }
+
+    #[test]
+    fn rejects_entries_outside_of_the_tree() {
+        let mut writer = ArchiveWriter::new(Vec::new(), PASSWORD).unwrap();
+        writer.add_file(Path::new("file"), &b""[..]).unwrap();
+        assert!(writer
+            .add_file(Path::new("missing/file"), &b""[..])
+            .is_err());
+        assert!(writer.add_file(Path::new("file/inside"), &b""[..]).is_err());
+        assert!(writer.add_directory(Path::new("file")).is_err());
+    }
+
+    #[test]
+    fn rejects_unsafe_indexes() {
+        let entry = |path: &str| ArchiveEntry {
+            path: path.into(),
+            kind: ArchiveEntryKind::Directory,
+        };
+        for entries in [
+            vec![entry("../escape")],
+            vec![entry("missing/directory")],
+            vec![entry("twice"), entry("twice")],
+        ] {
+            // Written as a writer with fewer checks would
+            let mut writer = ArchiveWriter::new(Vec::new(), PASSWORD).unwrap();
+            writer.entries = entries;
+            let archive = writer.finish().unwrap();
+            assert!(open(&archive).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_index() {
+        let tree = tree();
+        let mut archive = archive(tree.path());
+        let len = archive.len();
+        let index_offset = u64::from_le_bytes(archive[len - 8..].try_into().unwrap()) as usize;
+        archive[index_offset + 40] ^= 1;
+
+        assert!(matches!(open(&archive), Err(Error::InvalidArchive(_))));
+    }
+
+    #[test]
+    fn rejects_wrong_password() {
+        let tree = tree();
+        let archive = archive(tree.path());
+        assert!(matches!(
+            ArchiveReader::new(io::Cursor::new(&archive), "password"),
+            Err(Error::WrongPassword)
+        ));
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    io, mem,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::{
+    crypto::aead::{ChunkKey, EncryptedChunk, FileEncryptor},
+    error::{Error, Result},
+    file,
+    zeroize_allocator::Zeroing,
+};
+
+/// Async counterpart of [`crate::buf_reader::BufReader`].
+///
+/// Reads `CHUNK_SIZE` byte chunks from any `AsyncRead`. Only the final chunk may be shorter.
+#[derive(Debug)]
+pub struct AsyncBufReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+fn new_buf() -> Vec<u8> {
+    Vec::with_capacity(file::CHUNK_SIZE as usize)
+}
+
+impl<R: AsyncRead + Unpin> AsyncBufReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: new_buf(),
+            eof: false,
+        }
+    }
+
+    /// Encrypts every chunk read with consecutive keys of the ratchet starting at `key`.
+    pub fn encrypt<K: ChunkKey>(self, key: Zeroing<K>) -> EncryptingStream<R, K> {
+        EncryptingStream {
+            chunks: self,
+            encryptor: FileEncryptor::new(key),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for AsyncBufReader<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while !this.eof && this.buf.len() < file::CHUNK_SIZE as usize {
+            let filled = this.buf.len();
+            this.buf.resize(file::CHUNK_SIZE as usize, 0);
+            let mut read_buf = ReadBuf::new(&mut this.buf[filled..]);
+
+            let poll = Pin::new(&mut this.reader).poll_read(cx, &mut read_buf);
+            let read = read_buf.filled().len();
+            this.buf.truncate(filled + read);
+
+            match poll {
+                Poll::Ready(Ok(())) => this.eof = read == 0,
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        if this.buf.is_empty() {
+            Poll::Ready(None)
+        } else {
+            Poll::Ready(Some(Ok(mem::replace(&mut this.buf, new_buf()))))
+        }
+    }
+}
+
+/// Stream of [`EncryptedChunk`]s read from an `AsyncRead`.
+///
+/// Each chunk is encrypted with the next key of the ratchet, so chunk ids are consecutive starting
+/// at the chunk id of the key the stream was created with.
+pub struct EncryptingStream<R, K: ChunkKey> {
+    chunks: AsyncBufReader<R>,
+    encryptor: FileEncryptor<K>,
+}
+
+impl<R: AsyncRead + Unpin, K: ChunkKey> Stream for EncryptingStream<R, K> {
+    type Item = Result<EncryptedChunk>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.chunks).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => Poll::Ready(Some(this.encryptor.encrypt(&chunk))),
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(Error::from(e)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::crypto::{aead::AesGcmKey, tests::PRK};
+
+    const PATH: &str = "test/lorem_ipsum";
+    fn contents() -> Vec<u8> {
+        std::fs::read(PATH).unwrap()
+    }
+
+    async fn open() -> AsyncBufReader<tokio::fs::File> {
+        AsyncBufReader::new(tokio::fs::File::open(PATH).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn async_buf_reader_creates_chunks() {
+        let chunks: Vec<_> = open().await.collect().await;
+
+        assert_eq!(
+            chunks.len(),
+            contents().len().div_ceil(file::CHUNK_SIZE as usize)
+        );
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert_eq!(chunk.as_ref().unwrap().len(), file::CHUNK_SIZE as usize);
+        }
+    }
+
+    #[tokio::test]
+    async fn async_buf_reader_reads_correct_data() {
+        let mut reader = open().await;
+        let mut data = Vec::new();
+
+        while let Some(chunk) = reader.next().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+
+        assert_eq!(data, contents());
+    }
+
+    #[tokio::test]
+    async fn async_buf_reader_reads_from_memory() {
+        let contents = contents();
+        let chunks: Vec<_> = AsyncBufReader::new(&contents[..]).collect().await;
+
+        assert_eq!(
+            chunks.into_iter().map(|c| c.unwrap()).collect::<Vec<_>>(),
+            contents
+                .chunks(file::CHUNK_SIZE as usize)
+                .map(Vec::from)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn encrypting_stream_ratchets_keys() {
+        let file_id = uuid::Uuid::now_v7();
+        let key = AesGcmKey::generate(Box::pin(PRK), file_id).unwrap();
+        let mut stream = open().await.encrypt(key);
+
+        let mut decryption_key = AesGcmKey::generate(Box::pin(PRK), file_id).unwrap();
+        let mut data = Vec::new();
+        let mut count = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.unwrap();
+            assert_eq!(chunk.file_id(), file_id);
+            assert_eq!(chunk.chunk_id(), count);
+
+            data.extend(decryption_key.decrypt(&chunk).unwrap());
+            decryption_key = decryption_key.next_key().unwrap();
+            count += 1;
+        }
+
+        assert_eq!(data, contents());
+    }
+}
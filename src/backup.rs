@@ -0,0 +1,278 @@
+//! One-way backups of a local folder into a vault.
+//!
+//! Unlike [`crate::sync`], a backup only ever reads the folder. Every backup is a new snapshot
+//! tagged [`BACKUP_TAG`], so files deleted or changed locally stay in the earlier backups until
+//! those are forgotten, for example by a [`crate::retention`] policy. Files are stored exactly as
+//! sync stores them, and a file the [`FileIndex`] of the folder knows unchanged since it was stored
+//! for the device's previous backup keeps the manifest it had there instead of being read again.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+use crate::{
+    error::{Error, Result},
+    file::FileManifest,
+    index::{FileIndex, HashingReader},
+    lock::VaultLock,
+    metadata::{MetadataPolicy, Timestamp},
+    snapshot::{Snapshot, SnapshotOptions},
+    store,
+    tree::{TreeEntryKind, TreeManifest},
+    vault::Vault,
+};
+
+/// Tag of the snapshots made by backups
+pub(crate) const BACKUP_TAG: &str = "backup";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct BackupOptions {
+    /// Name of the device backed up, recorded in its backups
+    pub device: String,
+    pub policy: MetadataPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BackupReport {
+    pub snapshot: Snapshot,
+    /// Files stored because they are new or changed
+    pub stored: usize,
+    /// Files unchanged since the previous backup
+    pub unchanged: usize,
+}
+
+/// The newest backup of `device`, or of any device if `device` is `None`.
+pub(crate) fn latest_backup(vault: &Vault, device: Option<&str>) -> Result<Option<Snapshot>> {
+    Ok(Snapshot::list(vault)?.into_iter().rev().find(|snapshot| {
+        snapshot.tags.iter().any(|tag| tag == BACKUP_TAG)
+            && device.is_none_or(|device| snapshot.device == device)
+    }))
+}
+
+/// Backs up the folder `root` into a new snapshot.
+pub(crate) fn backup(vault: &Vault, root: &Path, options: &BackupOptions) -> Result<BackupReport> {
+    let _lock = VaultLock::shared(vault)?;
+    let previous = latest_backup(vault, Some(&options.device))?;
+    let previous_files: HashMap<PathBuf, FileManifest> = match &previous {
+        Some(snapshot) => snapshot
+            .tree(vault)?
+            .entries()
+            .iter()
+            .filter_map(|entry| match &entry.kind {
+                TreeEntryKind::File(manifest) => Some((entry.path.clone(), manifest.clone())),
+                _ => None,
+            })
+            .collect(),
+        None => HashMap::new(),
+    };
+
+    let mut index = FileIndex::open(vault, root)?;
+    let (mut stored, mut unchanged) = (0, 0);
+    let tree = TreeManifest::build(root, &options.policy, |path| {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let metadata = fs::metadata(path)?;
+        // Only manifests of the previous backup are sure to still have their chunks
+        let indexed = index.get(relative, &metadata);
+        if let Some(manifest) = indexed.and_then(|entry| entry.manifest.as_ref()) {
+            if previous_files.get(relative) == Some(manifest) {
+                unchanged += 1;
+                return Ok(manifest.clone());
+            }
+        }
+        stored += 1;
+        let read = Timestamp::now();
+        let mut reader = HashingReader::new(fs::File::open(path)?);
+        let manifest = store::put_file(vault.store(), vault.data_prk(), &mut reader)?;
+        index.insert(
+            relative,
+            &metadata,
+            read,
+            reader.finish(),
+            Some(manifest.clone()),
+        );
+        Ok(manifest)
+    })?;
+    let paths: HashSet<&Path> = tree
+        .entries()
+        .iter()
+        .map(|entry| entry.path.as_path())
+        .collect();
+    index.retain(|path| paths.contains(path));
+    index.save()?;
+    let snapshot = Snapshot::commit(
+        vault,
+        &tree,
+        SnapshotOptions {
+            device: options.device.clone(),
+            parents: previous.map(|snapshot| snapshot.id).into_iter().collect(),
+            tags: vec![BACKUP_TAG.to_owned()],
+        },
+    )?;
+    Ok(BackupReport {
+        snapshot,
+        stored,
+        unchanged,
+    })
+}
+
+/// Restores `path` from the backup `id`, or from the newest backup if `id` is `None`, into the
+/// directory `target`. An empty `path` restores the whole backup. Returns the backup restored
+/// from.
+pub(crate) fn restore(
+    vault: &Vault,
+    id: Option<&Uuid>,
+    path: &Path,
+    target: &Path,
+    policy: &MetadataPolicy,
+) -> Result<Snapshot> {
+    let snapshot = match id {
+        Some(id) => Snapshot::load(vault, id)?,
+        None => latest_backup(vault, None)?.ok_or(Error::NoBackup)?,
+    };
+    snapshot.restore(vault, path, target, policy)?;
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filetime::FileTime;
+
+    use crate::{
+        sync::{self, SyncOptions},
+        vault::VaultOptions,
+    };
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    fn setup(dir: &tempfile::TempDir) -> (Vault, PathBuf) {
+        let vault = Vault::init(
+            &dir.path().join("vault"),
+            "password",
+            &VaultOptions::default(),
+        )
+        .unwrap();
+        let root = dir.path().join("folder");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::copy(PATH, root.join("docs/lorem_ipsum")).unwrap();
+        fs::write(root.join("notes"), b"first").unwrap();
+        (vault, root)
+    }
+
+    /// Moves the modification times of the files out of the window in which they are read again
+    fn age(root: &Path) {
+        let past = FileTime::from_unix_time(FileTime::now().unix_seconds() - 60, 0);
+        for path in ["docs/lorem_ipsum", "notes"] {
+            filetime::set_file_mtime(root.join(path), past).unwrap();
+        }
+    }
+
+    fn options(device: &str) -> BackupOptions {
+        BackupOptions {
+            device: device.to_owned(),
+            ..BackupOptions::default()
+        }
+    }
+
+    #[test]
+    fn keeps_history_of_deleted_and_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, root) = setup(&dir);
+        let first = backup(&vault, &root, &options("laptop")).unwrap();
+        assert_eq!((first.stored, first.unchanged), (2, 0));
+
+        fs::write(root.join("notes"), b"second").unwrap();
+        fs::remove_dir_all(root.join("docs")).unwrap();
+        let chunks = vault.store().list().unwrap().len();
+        let second = backup(&vault, &root, &options("laptop")).unwrap();
+        assert_eq!((second.stored, second.unchanged), (1, 0));
+        assert_eq!(second.snapshot.parents, [first.snapshot.id]);
+        assert!(vault.store().list().unwrap().len() > chunks);
+        assert_eq!(fs::read(root.join("notes")).unwrap(), b"second");
+
+        let target = dir.path().join("restored");
+        let policy = MetadataPolicy::default();
+        restore(
+            &vault,
+            Some(&first.snapshot.id),
+            Path::new(""),
+            &target,
+            &policy,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read(target.join("docs/lorem_ipsum")).unwrap(),
+            fs::read(PATH).unwrap()
+        );
+        assert_eq!(fs::read(target.join("notes")).unwrap(), b"first");
+
+        let latest = restore(&vault, None, Path::new("notes"), &target, &policy).unwrap();
+        assert_eq!(latest.id, second.snapshot.id);
+        assert_eq!(fs::read(target.join("notes")).unwrap(), b"second");
+    }
+
+    #[test]
+    fn stores_only_changed_files_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, root) = setup(&dir);
+        age(&root);
+        backup(&vault, &root, &options("laptop")).unwrap();
+        let chunks = vault.store().list().unwrap().len();
+
+        let report = backup(&vault, &root, &options("laptop")).unwrap();
+        assert_eq!((report.stored, report.unchanged), (0, 2));
+        // Only the new tree manifest was stored
+        assert_eq!(vault.store().list().unwrap().len(), chunks + 1);
+
+        // Rewriting a file is noticed even if its size and modification time stay the same
+        let modified = fs::metadata(root.join("notes"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        fs::write(root.join("notes"), b"fixed").unwrap();
+        filetime::set_file_mtime(root.join("notes"), modified.into()).unwrap();
+        let report = backup(&vault, &root, &options("laptop")).unwrap();
+        assert_eq!((report.stored, report.unchanged), (1, 1));
+
+        // Files modified just before they were stored are read again until that is long past
+        fs::write(root.join("notes"), b"final").unwrap();
+        backup(&vault, &root, &options("laptop")).unwrap();
+        let report = backup(&vault, &root, &options("laptop")).unwrap();
+        assert_eq!((report.stored, report.unchanged), (1, 1));
+
+        // Backups of other devices are not compared with
+        let report = backup(&vault, &root, &options("desktop")).unwrap();
+        assert_eq!((report.stored, report.unchanged), (2, 0));
+        assert_eq!(report.snapshot.parents, []);
+    }
+
+    #[test]
+    fn keeps_apart_from_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, root) = setup(&dir);
+        assert!(matches!(
+            restore(
+                &vault,
+                None,
+                Path::new(""),
+                &root,
+                &MetadataPolicy::default()
+            ),
+            Err(Error::NoBackup)
+        ));
+
+        let report = backup(&vault, &root, &options("laptop")).unwrap();
+        assert_eq!(sync::latest_sync_snapshot(&vault).unwrap(), None);
+        let state = dir.path().join("state");
+        let synced = sync::sync(&vault, &root, &state, &SyncOptions::default()).unwrap();
+        assert_eq!(
+            latest_backup(&vault, None).unwrap().unwrap(),
+            report.snapshot
+        );
+        assert_ne!(synced.snapshot, Some(report.snapshot.id));
+    }
+}
@@ -9,27 +9,33 @@ use std::{
 };
 
 #[derive(Debug)]
-pub struct BufReader {
-    reader: io::BufReader<File>,
-    buf: Rc<String>,
+pub struct BufReader<R = File> {
+    reader: io::BufReader<R>,
+    buf: Rc<Vec<u8>>,
 }
 
-fn new_buf() -> Rc<String> {
-    Rc::new(String::with_capacity(file::CHUNK_SIZE as usize))
+fn new_buf() -> Rc<Vec<u8>> {
+    Rc::new(Vec::with_capacity(file::CHUNK_SIZE as usize))
 }
 
 impl BufReader {
     pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self> {
         let file = File::open(path).map_err(Error::from)?;
-        let reader = io::BufReader::new(file);
+        Ok(Self::new(file))
+    }
+}
+
+impl<R: Read> BufReader<R> {
+    pub fn new(reader: R) -> Self {
+        let reader = io::BufReader::new(reader);
         let buf = new_buf();
 
-        Ok(Self { reader, buf })
+        Self { reader, buf }
     }
 }
 
-impl Iterator for BufReader {
-    type Item = io::Result<Rc<String>>;
+impl<R: Read> Iterator for BufReader<R> {
+    type Item = io::Result<Rc<Vec<u8>>>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let buf = match Rc::get_mut(&mut self.buf) {
@@ -46,7 +52,7 @@ impl Iterator for BufReader {
         self.reader
             .by_ref()
             .take(file::CHUNK_SIZE)
-            .read_to_string(buf)
+            .read_to_end(buf)
             .map(|u| {
                 if u == 0 {
                     None
@@ -65,8 +71,8 @@ mod tests {
     use super::*;
 
     const PATH: &str = "test/lorem_ipsum";
-    fn contents() -> String {
-        fs::read_to_string(PATH).unwrap()
+    fn contents() -> Vec<u8> {
+        fs::read(PATH).unwrap()
     }
 
     #[test]
@@ -84,13 +90,28 @@ mod tests {
     #[test]
     fn buf_reader_reads_correct_data() {
         let reader = BufReader::open(PATH).unwrap();
-        let mut data = String::new();
+        let mut data = Vec::new();
 
         for line in reader {
             let line = line.unwrap();
-            data.push_str(&line);
+            data.extend_from_slice(&line);
         }
 
         assert_eq!(data, contents());
     }
+
+    #[test]
+    fn buf_reader_reads_binary_data() {
+        let binary: Vec<u8> = (0..=255).collect();
+        let reader = BufReader::new(&binary[..]);
+        let mut data = Vec::new();
+
+        for chunk in reader {
+            let chunk = chunk.unwrap();
+            assert!(chunk.len() <= file::CHUNK_SIZE as usize);
+            data.extend_from_slice(&chunk);
+        }
+
+        assert_eq!(data, binary);
+    }
 }
@@ -0,0 +1,835 @@
+//! Command line interface for creating vaults, backing up, restoring and syncing folders,
+//! pruning and garbage collecting snapshots, exporting folders as archives, encrypting streams
+//! such as `tar c dir | pigeonhole encrypt vault > out`, and packing single files into
+//! containers, see [`crate::container`]. `serve-store` makes pigeonhole the helper program of an
+//! external store, serving another store for instance on the other end of an ssh connection, see
+//! [`crate::store::external`].
+//!
+//! The vault password is read from `PIGEONHOLE_PASSWORD`, like the git-annex special remote does,
+//! and the password of archives from `PIGEONHOLE_ARCHIVE_PASSWORD`.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use uuid::Uuid;
+
+use crate::{
+    archive::{ArchiveReader, ArchiveWriter},
+    backup::{self, BackupOptions},
+    gc,
+    metadata::{MetadataPolicy, Ownership},
+    retention::{self, RetentionPolicy},
+    store::{self, external},
+    sync::{self, ConflictResolution, SyncAction, SyncOptions},
+    vault::{Vault, VaultOptions},
+};
+
+const PASSWORD_VARIABLE: &str = "PIGEONHOLE_PASSWORD";
+
+/// Password archives are exported with, extracted and imported with, never a vault's
+const ARCHIVE_PASSWORD_VARIABLE: &str = "PIGEONHOLE_ARCHIVE_PASSWORD";
+
+/// Password `passwd` changes the vault's password to
+const NEW_PASSWORD_VARIABLE: &str = "PIGEONHOLE_NEW_PASSWORD";
+
+/// Options that take no value
+const FLAGS: &[&str] = &["--dry-run", "--merge-text"];
+
+const USAGE: &str = "usage:
+    pigeonhole init <vault> [--pack-size <bytes>]
+    pigeonhole info <vault>
+    pigeonhole passwd <vault>
+    pigeonhole backup <vault> <folder> [--device <name>] [<metadata options>]
+    pigeonhole restore <vault> <target> [--snapshot <id>] [--path <path>] [<metadata options>]
+    pigeonhole sync <vault> <folder> --state <file> [--device <name>]
+        [--conflicts keep-both|newest|device:<name>] [--merge-text] [<metadata options>]
+    pigeonhole prune <vault> [--keep-last <n>] [--keep-hourly <n>] [--keep-daily <n>]
+        [--keep-weekly <n>] [--keep-monthly <n>] [--keep-within <days>] [--keep-tag <tag>]...
+        [--dry-run]
+    pigeonhole gc <vault> [--dry-run]
+    pigeonhole export <folder>
+    pigeonhole extract <archive> <target>
+    pigeonhole import <vault> <archive> [--device <name>]
+    pigeonhole encrypt <vault>
+    pigeonhole decrypt <vault>
+    pigeonhole pack <vault> <file>
+    pigeonhole unpack <vault> <container>
+    pigeonhole serve-store <location>
+    pigeonhole annex-remote
+
+metadata options:
+    --metadata none|default|all
+    --map-uid <from>:<to>
+    --map-gid <from>:<to>";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Init {
+        vault: PathBuf,
+        options: VaultOptions,
+    },
+    /// Prints the vault's config
+    Info {
+        vault: PathBuf,
+    },
+    /// Changes the vault's password to the one in `PIGEONHOLE_NEW_PASSWORD`
+    Passwd {
+        vault: PathBuf,
+    },
+    Backup {
+        vault: PathBuf,
+        folder: PathBuf,
+        device: Option<String>,
+        policy: MetadataPolicy,
+    },
+    Restore {
+        vault: PathBuf,
+        target: PathBuf,
+        snapshot: Option<Uuid>,
+        path: PathBuf,
+        policy: MetadataPolicy,
+    },
+    Sync {
+        vault: PathBuf,
+        folder: PathBuf,
+        /// Base of this device, kept outside the folder
+        state: PathBuf,
+        device: Option<String>,
+        policy: MetadataPolicy,
+        resolution: ConflictResolution,
+        merge_text: bool,
+    },
+    Prune {
+        vault: PathBuf,
+        policy: RetentionPolicy,
+        dry_run: bool,
+    },
+    Gc {
+        vault: PathBuf,
+        dry_run: bool,
+    },
+    /// Writes an archive of `folder` to standard output
+    Export {
+        folder: PathBuf,
+    },
+    Extract {
+        archive: PathBuf,
+        target: PathBuf,
+    },
+    /// Stores the files of an archive in a new snapshot
+    Import {
+        vault: PathBuf,
+        archive: PathBuf,
+        device: Option<String>,
+    },
+    /// Encrypts standard input to standard output
+    Encrypt {
+        vault: PathBuf,
+    },
+    /// Decrypts standard input to standard output
+    Decrypt {
+        vault: PathBuf,
+    },
+    /// Writes a container of `file` to standard output
+    Pack {
+        vault: PathBuf,
+        file: PathBuf,
+    },
+    /// Writes the file in `container` to standard output
+    Unpack {
+        vault: PathBuf,
+        container: PathBuf,
+    },
+    /// Serves the store at `location` on standard input and output
+    ServeStore {
+        location: String,
+    },
+}
+
+fn usage() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, USAGE)
+}
+
+fn string(value: OsString) -> io::Result<String> {
+    value.into_string().map_err(|_| usage())
+}
+
+fn number<T: FromStr>(value: OsString) -> io::Result<T> {
+    string(value)?.parse().map_err(|_| usage())
+}
+
+/// Applies an option choosing which metadata the files are captured and restored with.
+fn metadata_option(policy: &mut MetadataPolicy, name: &str, value: OsString) -> io::Result<()> {
+    let value = string(value)?;
+    match name {
+        "--metadata" => {
+            let ownership = policy.ownership.clone();
+            *policy = match value.as_str() {
+                "none" => MetadataPolicy::none(),
+                "default" => MetadataPolicy::default(),
+                "all" => MetadataPolicy::all(),
+                _ => return Err(usage()),
+            };
+            // Keeps the ids mapped so far whichever comes first
+            if let Ownership::Map { .. } = ownership {
+                policy.ownership = ownership;
+            }
+        }
+        "--map-uid" | "--map-gid" => {
+            let (from, to) = value.split_once(':').ok_or_else(usage)?;
+            let (from, to) = (number(from.into())?, number(to.into())?);
+            let (mut uids, mut gids) =
+                match std::mem::replace(&mut policy.ownership, Ownership::Ignore) {
+                    Ownership::Map { uids, gids } => (uids, gids),
+                    _ => (HashMap::new(), HashMap::new()),
+                };
+            match name {
+                "--map-uid" => uids.insert(from, to),
+                _ => gids.insert(from, to),
+            };
+            policy.ownership = Ownership::Map { uids, gids };
+        }
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+fn parse(args: &[OsString]) -> io::Result<Command> {
+    let (command, args) = args.split_first().ok_or_else(usage)?;
+    let command = command.to_str().ok_or_else(usage)?;
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some(name) if FLAGS.contains(&name) => options.push((name, OsString::new())),
+            Some(name) if name.starts_with("--") => {
+                options.push((name, args.next().ok_or_else(usage)?.clone()))
+            }
+            _ => positional.push(PathBuf::from(arg)),
+        }
+    }
+    let arity = match command {
+        "init" | "info" | "passwd" | "encrypt" | "decrypt" | "prune" | "gc" | "serve-store"
+        | "export" => 1,
+        _ => 2,
+    };
+    if positional.len() != arity {
+        return Err(usage());
+    }
+    let mut positional = positional.into_iter();
+    let vault = positional.next().ok_or_else(usage)?;
+    let mut path = || positional.next().ok_or_else(usage);
+
+    match command {
+        "init" => {
+            let mut vault_options = VaultOptions::default();
+            for (name, value) in options {
+                match name {
+                    // A pack size of 0 stores every chunk as an object of its own
+                    "--pack-size" => {
+                        vault_options.pack_size = Some(number(value)?).filter(|size| *size > 0)
+                    }
+                    _ => return Err(usage()),
+                }
+            }
+            Ok(Command::Init {
+                vault,
+                options: vault_options,
+            })
+        }
+        "backup" => {
+            let (mut device, mut policy) = (None, MetadataPolicy::default());
+            for (name, value) in options {
+                match name {
+                    "--device" => device = Some(string(value)?),
+                    _ => metadata_option(&mut policy, name, value)?,
+                }
+            }
+            Ok(Command::Backup {
+                vault,
+                folder: path()?,
+                device,
+                policy,
+            })
+        }
+        "restore" => {
+            let (mut snapshot, mut restored) = (None, PathBuf::new());
+            let mut policy = MetadataPolicy::default();
+            for (name, value) in options {
+                match name {
+                    "--snapshot" => {
+                        let id = Uuid::parse_str(&string(value)?).map_err(|_| usage())?;
+                        snapshot = Some(id);
+                    }
+                    "--path" => restored = PathBuf::from(value),
+                    _ => metadata_option(&mut policy, name, value)?,
+                }
+            }
+            Ok(Command::Restore {
+                vault,
+                target: path()?,
+                snapshot,
+                path: restored,
+                policy,
+            })
+        }
+        "sync" => {
+            let (mut state, mut device, mut resolution) = (None, None, Default::default());
+            let (mut policy, mut merge_text) = (MetadataPolicy::default(), false);
+            for (name, value) in options {
+                match name {
+                    "--state" => state = Some(PathBuf::from(value)),
+                    "--device" => device = Some(string(value)?),
+                    "--conflicts" => {
+                        resolution = match string(value)?.as_str() {
+                            "keep-both" => ConflictResolution::KeepBoth,
+                            "newest" => ConflictResolution::PreferNewest,
+                            other => ConflictResolution::PreferDevice(
+                                other.strip_prefix("device:").ok_or_else(usage)?.to_owned(),
+                            ),
+                        }
+                    }
+                    "--merge-text" => merge_text = true,
+                    _ => metadata_option(&mut policy, name, value)?,
+                }
+            }
+            Ok(Command::Sync {
+                vault,
+                folder: path()?,
+                state: state.ok_or_else(usage)?,
+                device,
+                policy,
+                resolution,
+                merge_text,
+            })
+        }
+        "prune" => {
+            let (mut policy, mut dry_run) = (RetentionPolicy::default(), false);
+            for (name, value) in options {
+                match name {
+                    "--keep-last" => policy.last = number(value)?,
+                    "--keep-hourly" => policy.hourly = number(value)?,
+                    "--keep-daily" => policy.daily = number(value)?,
+                    "--keep-weekly" => policy.weekly = number(value)?,
+                    "--keep-monthly" => policy.monthly = number(value)?,
+                    "--keep-within" => {
+                        let days: u64 = number(value)?;
+                        policy.within = Some(Duration::from_secs(days * 24 * 60 * 60));
+                    }
+                    "--keep-tag" => policy.pinned_tags.push(string(value)?),
+                    "--dry-run" => dry_run = true,
+                    _ => return Err(usage()),
+                }
+            }
+            Ok(Command::Prune {
+                vault,
+                policy,
+                dry_run,
+            })
+        }
+        "gc" => {
+            let mut dry_run = false;
+            for (name, _) in options {
+                match name {
+                    "--dry-run" => dry_run = true,
+                    _ => return Err(usage()),
+                }
+            }
+            Ok(Command::Gc { vault, dry_run })
+        }
+        "import" => {
+            let mut device = None;
+            for (name, value) in options {
+                match name {
+                    "--device" => device = Some(string(value)?),
+                    _ => return Err(usage()),
+                }
+            }
+            Ok(Command::Import {
+                vault,
+                archive: path()?,
+                device,
+            })
+        }
+        _ if !options.is_empty() => Err(usage()),
+        "export" => Ok(Command::Export { folder: vault }),
+        "extract" => Ok(Command::Extract {
+            archive: vault,
+            target: path()?,
+        }),
+        "info" => Ok(Command::Info { vault }),
+        "passwd" => Ok(Command::Passwd { vault }),
+        "encrypt" => Ok(Command::Encrypt { vault }),
+        "decrypt" => Ok(Command::Decrypt { vault }),
+        "pack" => Ok(Command::Pack {
+            vault,
+            file: path()?,
+        }),
+        "unpack" => Ok(Command::Unpack {
+            vault,
+            container: path()?,
+        }),
+        "serve-store" => Ok(Command::ServeStore {
+            location: string(vault.into_os_string())?,
+        }),
+        _ => Err(usage()),
+    }
+}
+
+/// One line describing what a sync did.
+fn describe(action: &SyncAction) -> String {
+    let verb = match action {
+        SyncAction::Upload(_) => "uploaded",
+        SyncAction::Download(_) => "downloaded",
+        SyncAction::DeleteLocal(_) => "deleted locally",
+        SyncAction::DeleteRemote(_) => "deleted remotely",
+        SyncAction::RenameLocal { .. } => "renamed locally",
+        SyncAction::RenameRemote { .. } => "renamed remotely",
+        SyncAction::Merge(_) => "merged",
+        SyncAction::ConflictCopy { .. } => "conflict, local version copied to",
+        SyncAction::Conflict(_) => "conflict, left alone",
+    };
+    match action {
+        SyncAction::RenameLocal { from, .. } | SyncAction::RenameRemote { from, .. } => {
+            format!("{verb} {} -> {}", from.display(), action.path().display())
+        }
+        _ => format!("{verb} {}", action.path().display()),
+    }
+}
+
+fn env(variable: &str) -> io::Result<String> {
+    std::env::var(variable).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{variable} is not set"),
+        )
+    })
+}
+
+/// Runs the command given by `args`, which do not include the program name.
+pub fn run_command(args: &[OsString]) -> io::Result<()> {
+    let command = parse(args)?;
+    // Only taken by the commands that open a vault
+    let password = env(PASSWORD_VARIABLE);
+
+    match command {
+        Command::Init { vault, options } => {
+            let vault = Vault::init(&vault, &password?, &options)?;
+            println!("created vault {}", vault.id());
+        }
+        Command::Info { vault } => {
+            let vault = Vault::open(&vault, &password?)?;
+            let config = vault.config();
+            println!("vault {} at {}", config.id, vault.root().display());
+            println!("format version {}", config.format_version);
+            match &config.store {
+                Some(location) => println!("chunks stored at {location}"),
+                None => println!("chunks stored in the vault"),
+            }
+            match config.pack_size {
+                Some(size) => println!("chunks bundled into packs of {size} bytes"),
+                None => println!("chunks stored as objects of their own"),
+            }
+        }
+        Command::Passwd { vault } => {
+            let new_password = env(NEW_PASSWORD_VARIABLE)?;
+            let vault = Vault::open(&vault, &password?)?;
+            let id = vault.id();
+            vault.change_password(&new_password)?;
+            vault.lock();
+            println!("changed the password of vault {id}");
+        }
+        Command::Backup {
+            vault,
+            folder,
+            device,
+            policy,
+        } => {
+            let vault = Vault::open(&vault, &password?)?;
+            let options = BackupOptions {
+                device: device.map_or_else(crate::lock::host, Ok)?,
+                policy,
+            };
+            let report = backup::backup(&vault, &folder, &options)?;
+            println!(
+                "backup {}: {} files stored, {} unchanged",
+                report.snapshot.id, report.stored, report.unchanged
+            );
+        }
+        Command::Restore {
+            vault,
+            target,
+            snapshot,
+            path,
+            policy,
+        } => {
+            let vault = Vault::open(&vault, &password?)?;
+            let snapshot = backup::restore(&vault, snapshot.as_ref(), &path, &target, &policy)?;
+            println!("restored from backup {}", snapshot.id);
+        }
+        Command::Sync {
+            vault,
+            folder,
+            state,
+            device,
+            policy,
+            resolution,
+            merge_text,
+        } => {
+            let vault = Vault::open(&vault, &password?)?;
+            let options = SyncOptions {
+                device: device.map_or_else(crate::lock::host, Ok)?,
+                policy,
+                resolution,
+                merge_text,
+            };
+            let report = sync::sync(&vault, &folder, &state, &options)?;
+            for action in &report.actions {
+                println!("{}", describe(action));
+            }
+            match report.snapshot {
+                Some(id) => println!("in sync with snapshot {id}"),
+                None => println!("nothing synced yet"),
+            }
+        }
+        Command::Prune {
+            vault,
+            policy,
+            dry_run,
+        } => {
+            let vault = Vault::open(&vault, &password?)?;
+            let retention = retention::prune(&vault, &policy, dry_run)?;
+            print!("{}", retention.report());
+        }
+        Command::Gc { vault, dry_run } => {
+            let vault = Vault::open(&vault, &password?)?;
+            let stats = gc::collect_garbage(&vault, dry_run)?;
+            let verb = if dry_run { "would delete" } else { "deleted" };
+            println!(
+                "{} snapshots reference {} of {} chunks, {verb} {} chunks of {} bytes, {} packs rewritten",
+                stats.snapshots,
+                stats.referenced,
+                stats.chunks,
+                stats.deleted,
+                stats.bytes_reclaimed,
+                stats.packs_rewritten
+            );
+        }
+        Command::Export { folder } => {
+            let password = env(ARCHIVE_PASSWORD_VARIABLE)?;
+            let mut writer = ArchiveWriter::new(io::stdout().lock(), &password)?;
+            writer.add_tree(&folder)?;
+            writer.finish()?.flush()?;
+        }
+        Command::Extract { archive, target } => {
+            let password = env(ARCHIVE_PASSWORD_VARIABLE)?;
+            let reader = io::BufReader::new(fs::File::open(archive)?);
+            ArchiveReader::new(reader, &password)?.extract(&target)?;
+        }
+        Command::Import {
+            vault,
+            archive,
+            device,
+        } => {
+            let vault = Vault::open(&vault, &password?)?;
+            let archive_password = env(ARCHIVE_PASSWORD_VARIABLE)?;
+            let reader = io::BufReader::new(fs::File::open(archive)?);
+            let device = device.map_or_else(crate::lock::host, Ok)?;
+            let snapshot = vault.import_archive(reader, &archive_password, &device)?;
+            println!("imported into snapshot {}", snapshot.id);
+        }
+        Command::Encrypt { vault } => {
+            let vault = Vault::open(&vault, &password?)?;
+            let mut writer = vault.encrypting_writer(io::stdout().lock())?;
+            io::copy(&mut io::stdin().lock(), &mut writer)?;
+            let _ = writer.finish()?;
+        }
+        Command::Decrypt { vault } => {
+            let vault = Vault::open(&vault, &password?)?;
+            let mut stdout = io::stdout().lock();
+            io::copy(
+                &mut vault.decrypting_reader(io::stdin().lock()),
+                &mut stdout,
+            )?;
+            stdout.flush()?;
+        }
+        Command::Pack { vault, file } => {
+            let vault = Vault::open(&vault, &password?)?;
+            let mut writer = vault.container_writer(io::stdout().lock())?;
+            io::copy(&mut fs::File::open(file)?, &mut writer)?;
+            let _ = writer.finish()?;
+        }
+        Command::Unpack { vault, container } => {
+            let vault = Vault::open(&vault, &password?)?;
+            let mut reader =
+                vault.container_reader(io::BufReader::new(fs::File::open(container)?))?;
+            let mut stdout = io::stdout().lock();
+            io::copy(&mut reader, &mut stdout)?;
+            stdout.flush()?;
+        }
+        Command::ServeStore { location } => {
+            let store = store::open(&location)?;
+            external::serve(&store, io::stdin().lock(), io::stdout().lock())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse(&args(&["backup", "vault", "folder", "--device", "laptop"])).unwrap(),
+            Command::Backup {
+                vault: "vault".into(),
+                folder: "folder".into(),
+                device: Some("laptop".to_owned()),
+                policy: MetadataPolicy::default(),
+            }
+        );
+        assert_eq!(
+            parse(&args(&["init", "vault"])).unwrap(),
+            Command::Init {
+                vault: "vault".into(),
+                options: VaultOptions::default(),
+            }
+        );
+        assert_eq!(
+            parse(&args(&["init", "vault", "--pack-size", "0"])).unwrap(),
+            Command::Init {
+                vault: "vault".into(),
+                options: VaultOptions {
+                    pack_size: None,
+                    ..VaultOptions::default()
+                },
+            }
+        );
+        let id = Uuid::now_v7();
+        assert_eq!(
+            parse(&args(&[
+                "restore",
+                "vault",
+                "--snapshot",
+                &id.to_string(),
+                "target",
+                "--path",
+                "docs/notes",
+            ]))
+            .unwrap(),
+            Command::Restore {
+                vault: "vault".into(),
+                target: "target".into(),
+                snapshot: Some(id),
+                path: "docs/notes".into(),
+                policy: MetadataPolicy::default(),
+            }
+        );
+        assert_eq!(
+            parse(&args(&["serve-store", "local:/srv/chunks"])).unwrap(),
+            Command::ServeStore {
+                location: "local:/srv/chunks".to_owned()
+            }
+        );
+        assert_eq!(
+            parse(&args(&["passwd", "vault"])).unwrap(),
+            Command::Passwd {
+                vault: "vault".into()
+            }
+        );
+        assert_eq!(
+            parse(&args(&["encrypt", "vault"])).unwrap(),
+            Command::Encrypt {
+                vault: "vault".into()
+            }
+        );
+        assert_eq!(
+            parse(&args(&["decrypt", "vault"])).unwrap(),
+            Command::Decrypt {
+                vault: "vault".into()
+            }
+        );
+        assert_eq!(
+            parse(&args(&["pack", "vault", "file"])).unwrap(),
+            Command::Pack {
+                vault: "vault".into(),
+                file: "file".into(),
+            }
+        );
+        assert_eq!(
+            parse(&args(&["unpack", "vault", "file.phc"])).unwrap(),
+            Command::Unpack {
+                vault: "vault".into(),
+                container: "file.phc".into(),
+            }
+        );
+        assert_eq!(
+            parse(&args(&["restore", "vault", "target"])).unwrap(),
+            Command::Restore {
+                vault: "vault".into(),
+                target: "target".into(),
+                snapshot: None,
+                path: PathBuf::new(),
+                policy: MetadataPolicy::default(),
+            }
+        );
+        assert_eq!(
+            parse(&args(&[
+                "sync",
+                "vault",
+                "folder",
+                "--state",
+                "folder.state",
+                "--conflicts",
+                "device:desktop",
+                "--merge-text",
+                "--metadata",
+                "none",
+            ]))
+            .unwrap(),
+            Command::Sync {
+                vault: "vault".into(),
+                folder: "folder".into(),
+                state: "folder.state".into(),
+                device: None,
+                policy: MetadataPolicy::none(),
+                resolution: ConflictResolution::PreferDevice("desktop".to_owned()),
+                merge_text: true,
+            }
+        );
+        assert_eq!(
+            parse(&args(&[
+                "prune",
+                "vault",
+                "--keep-daily",
+                "7",
+                "--keep-within",
+                "2",
+                "--keep-tag",
+                "sync",
+                "--keep-tag",
+                "release",
+                "--dry-run",
+            ]))
+            .unwrap(),
+            Command::Prune {
+                vault: "vault".into(),
+                policy: RetentionPolicy {
+                    daily: 7,
+                    within: Some(Duration::from_secs(2 * 24 * 60 * 60)),
+                    pinned_tags: vec!["sync".to_owned(), "release".to_owned()],
+                    ..RetentionPolicy::default()
+                },
+                dry_run: true,
+            }
+        );
+        assert_eq!(
+            parse(&args(&["gc", "vault"])).unwrap(),
+            Command::Gc {
+                vault: "vault".into(),
+                dry_run: false,
+            }
+        );
+        assert_eq!(
+            parse(&args(&["extract", "folder.pha", "target"])).unwrap(),
+            Command::Extract {
+                archive: "folder.pha".into(),
+                target: "target".into(),
+            }
+        );
+        assert_eq!(
+            parse(&args(&["import", "vault", "folder.pha"])).unwrap(),
+            Command::Import {
+                vault: "vault".into(),
+                archive: "folder.pha".into(),
+                device: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_metadata_options() {
+        let Command::Restore { policy, .. } = parse(&args(&[
+            "restore",
+            "vault",
+            "target",
+            "--map-uid",
+            "1000:1001",
+            "--metadata",
+            "all",
+            "--map-gid",
+            "100:50",
+        ]))
+        .unwrap() else {
+            panic!("expected a restore");
+        };
+        assert_eq!(
+            policy,
+            MetadataPolicy {
+                ownership: Ownership::Map {
+                    uids: HashMap::from([(1000, 1001)]),
+                    gids: HashMap::from([(100, 50)]),
+                },
+                ..MetadataPolicy::all()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        for invalid in [
+            &[][..],
+            &["mount", "vault", "folder"],
+            &["sync", "vault", "folder"],
+            &[
+                "sync",
+                "vault",
+                "folder",
+                "--state",
+                "s",
+                "--conflicts",
+                "oldest",
+            ],
+            &["prune", "vault", "--keep-last", "-1"],
+            &["prune", "vault", "--dry-run", "--device", "laptop"],
+            &["gc", "vault", "--dry-run", "yes"],
+            &["export", "folder", "--dry-run"],
+            &["extract", "folder.pha"],
+            &["import", "vault", "folder.pha", "--from", "other"],
+            &["init", "vault", "folder"],
+            &["init", "vault", "--pack-size", "large"],
+            &["encrypt", "vault", "--pack-size", "1024"],
+            &["encrypt"],
+            &["serve-store"],
+            &["info", "vault", "--pack-size", "1024"],
+            &["decrypt", "vault", "out"],
+            &["encrypt", "vault", "--device", "laptop"],
+            &["pack", "vault"],
+            &["unpack", "vault", "file.phc", "--path", "file"],
+            &["backup", "vault"],
+            &["backup", "vault", "folder", "extra"],
+            &["backup", "vault", "folder", "--device"],
+            &["backup", "vault", "folder", "--snapshot", "id"],
+            &["restore", "vault", "target", "--snapshot", "not an id"],
+            &["restore", "vault", "target", "--map-uid", "1000"],
+            &["backup", "vault", "folder", "--metadata", "some"],
+        ] {
+            let error = parse(&args(invalid)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{invalid:?}");
+        }
+    }
+}
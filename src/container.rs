@@ -0,0 +1,318 @@
+//! Single file encrypted container, for moving an encrypted file around as one blob.
+//!
+//! All integers are little endian.
+//!
+//! | Section | Field           | Size | Description                                              |
+//! |---------|-----------------|------|----------------------------------------------------------|
+//! | Header  | magic           | 4    | `PHC\0`                                                  |
+//! |         | version         | 1    | Container format version, currently 1                    |
+//! |         | vault id        | 16   | Identifies the vault, and so the KDF, the keys belong to |
+//! |         | encryption type | 1    | Encryption type of every chunk, currently always AES-GCM |
+//! |         | file id         | 16   | File id of every chunk                                   |
+//! | Chunks  | chunk records   | ...  | Chunk records as written by [`EncryptingWriter`]         |
+//! | Trailer | manifest hash   | 32   | SHA-256 of the [`FileManifest`] of the chunk records     |
+//! |         | signature len   | 2    | Length of the signature                                  |
+//! |         | signature       | ...  | Signature over the header and manifest hash              |
+//!
+//! Every chunk record is a `u32` length followed by an [`EncryptedChunk`], and an empty chunk ends
+//! the records. The signature is made with the signing key derived from the same prk as the chunk
+//! keys.
+//!
+//! [`EncryptedChunk`]: crate::crypto::aead::EncryptedChunk
+
+use std::io::{self, Read, Write};
+
+use sha2::Digest;
+use uuid::Uuid;
+
+use crate::{
+    crypto::{
+        self,
+        aead::{AesGcmKey, ChunkKey, EncryptionType},
+        asym::AsymmetricCryptoKey,
+    },
+    decrypting_reader::DecryptingReader,
+    encrypting_writer::EncryptingWriter,
+    error::{Error, Result},
+    file::FileManifest,
+    zeroize_allocator::Zeroing,
+};
+
+const MAGIC: &[u8; 4] = b"PHC\0";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 4 + 1 + 16 + 1 + 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ContainerHeader {
+    pub vault_id: Uuid,
+    pub encryption_type: EncryptionType,
+    pub file_id: Uuid,
+}
+
+impl ContainerHeader {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(self.vault_id.as_bytes());
+        bytes.push(self.encryption_type.into());
+        bytes.extend_from_slice(self.file_id.as_bytes());
+        bytes
+    }
+
+    pub fn parse(header: &[u8]) -> Result<Self> {
+        if header.len() != HEADER_SIZE || &header[..4] != MAGIC {
+            return Err(Error::InvalidContainer("not a pigeonhole container"));
+        }
+        if header[4] != VERSION {
+            return Err(Error::InvalidContainer("unsupported version"));
+        }
+
+        Ok(Self {
+            vault_id: Uuid::from_slice(&header[5..21])?,
+            encryption_type: EncryptionType::try_from(header[21])?,
+            file_id: Uuid::from_slice(&header[22..38])?,
+        })
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        Self::parse(&header)
+    }
+}
+
+fn signed_data(header: &ContainerHeader, manifest_hash: &[u8]) -> Vec<u8> {
+    let mut data = header.to_bytes();
+    data.extend_from_slice(manifest_hash);
+    data
+}
+
+/// Writes a container, encrypting everything written to it.
+pub(crate) struct ContainerWriter<W: Write> {
+    header: ContainerHeader,
+    writer: EncryptingWriter<W>,
+    prk: Zeroing<[u8; 32]>,
+}
+
+impl<W: Write> ContainerWriter<W> {
+    /// Starts a container for a new file, writing its header.
+    pub fn new(mut writer: W, prk: Zeroing<[u8; 32]>, vault_id: Uuid) -> Result<Self> {
+        let header = ContainerHeader {
+            vault_id,
+            encryption_type: EncryptionType::AesGcm,
+            file_id: Uuid::now_v7(),
+        };
+        writer.write_all(&header.to_bytes())?;
+        let key = AesGcmKey::generate(Box::pin(*prk), header.file_id)?;
+
+        Ok(Self {
+            header,
+            writer: EncryptingWriter::new(writer, key),
+            prk,
+        })
+    }
+
+    /// Writes the remaining chunk records and the signed trailer, returning the inner writer and
+    /// the manifest of the chunk records.
+    pub fn finish(self) -> Result<(W, FileManifest)> {
+        let (mut writer, manifest) = self.writer.finish()?;
+
+        let manifest_hash = sha2::Sha256::digest(manifest.to_bytes());
+        let signature =
+            crypto::signing_key(&self.prk)?.sign(&signed_data(&self.header, &manifest_hash))?;
+        writer.write_all(&manifest_hash)?;
+        writer.write_all(&(signature.len() as u16).to_le_bytes())?;
+        writer.write_all(&signature)?;
+        writer.flush()?;
+
+        Ok((writer, manifest))
+    }
+}
+
+impl<W: Write> Write for ContainerWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads and decrypts a container.
+///
+/// Data is returned as chunk records are decrypted, each of which is authenticated on its own. The
+/// trailer is verified once the last record has been read, so reading to the end fails for a
+/// container with a missing or invalid trailer.
+pub(crate) struct ContainerReader<R: Read> {
+    header: ContainerHeader,
+    reader: DecryptingReader<R>,
+    prk: Zeroing<[u8; 32]>,
+    verified: bool,
+}
+
+impl<R: Read> ContainerReader<R> {
+    /// Reads the container header, refusing encryption types containers are not written with.
+    /// Nothing is decrypted until the container is read, and records of another type than the
+    /// header's then fail to decrypt.
+    pub fn new(mut reader: R, prk: Zeroing<[u8; 32]>) -> Result<Self> {
+        let header = ContainerHeader::read_from(&mut reader)?;
+        if header.encryption_type != EncryptionType::AesGcm {
+            return Err(Error::InvalidContainer("unsupported encryption type"));
+        }
+        let reader = DecryptingReader::for_file(reader, Box::pin(*prk), header.file_id);
+        Ok(Self {
+            header,
+            reader,
+            prk,
+            verified: false,
+        })
+    }
+
+    pub fn header(&self) -> &ContainerHeader {
+        &self.header
+    }
+
+    /// Manifest of the chunk records, available once the container has been read to the end.
+    #[cfg(test)]
+    pub fn manifest(&self) -> Option<&FileManifest> {
+        Some(self.reader.manifest()).filter(|_| self.verified)
+    }
+
+    fn verify_trailer(&mut self) -> Result<()> {
+        let reader = self.reader.get_mut();
+        let mut manifest_hash = [0u8; 32];
+        reader.read_exact(&mut manifest_hash)?;
+        let mut signature_len = [0u8; 2];
+        reader.read_exact(&mut signature_len)?;
+        let mut signature = vec![0u8; u16::from_le_bytes(signature_len) as usize];
+        reader.read_exact(&mut signature)?;
+
+        if manifest_hash[..] != sha2::Sha256::digest(self.reader.manifest().to_bytes())[..] {
+            return Err(Error::InvalidContainer(
+                "manifest hash does not match chunks",
+            ));
+        }
+        if !crypto::signing_key(&self.prk)?
+            .verify(&signed_data(&self.header, &manifest_hash), &signature)?
+        {
+            return Err(Error::InvalidContainer("invalid signature"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ContainerReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        if read == 0 && !buf.is_empty() && !self.verified {
+            self.verify_trailer()?;
+            self.verified = true;
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::tests::PRK;
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    fn vault_id() -> Uuid {
+        Uuid::from_u128(42)
+    }
+
+    fn container(data: &[u8]) -> (Vec<u8>, FileManifest) {
+        let mut writer = ContainerWriter::new(Vec::new(), Box::pin(PRK), vault_id()).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn open(container: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = ContainerReader::new(container, Box::pin(PRK))?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn round_trips_file() {
+        let contents = std::fs::read(PATH).unwrap();
+        let (container, manifest) = container(&contents);
+
+        let mut reader = ContainerReader::new(&container[..], Box::pin(PRK)).unwrap();
+        assert_eq!(reader.header().vault_id, vault_id());
+        assert_eq!(reader.header().encryption_type, EncryptionType::AesGcm);
+        assert!(reader.manifest().is_none());
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, contents);
+        assert_eq!(reader.manifest(), Some(&manifest));
+    }
+
+    #[test]
+    fn round_trips_empty_file() {
+        let (container, _) = container(&[]);
+        assert!(open(&container).unwrap().is_empty());
+    }
+
+    #[test]
+    fn header_is_readable_without_decrypting() {
+        let (container, _) = container(b"some data");
+        let header = ContainerHeader::read_from(&mut &container[..]).unwrap();
+        assert_eq!(header.vault_id, vault_id());
+    }
+
+    #[test]
+    fn rejects_missing_trailer() {
+        let (container, _) = container(b"some data");
+        assert!(open(&container[..container.len() - 66]).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_header() {
+        let (mut container, _) = container(b"some data");
+        container[5] ^= 1;
+        assert!(open(&container).is_err());
+
+        let (mut container, _) = self::container(b"some data");
+        container[4] = 2;
+        assert!(matches!(
+            ContainerReader::new(&container[..], Box::pin(PRK)),
+            Err(Error::InvalidContainer(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unsupported_encryption_types() {
+        let (mut container, _) = container(b"some data");
+        container[4 + 1 + 16] = EncryptionType::XChaCha20Poly1305.into();
+        assert!(matches!(
+            ContainerReader::new(&container[..], Box::pin(PRK)),
+            Err(Error::InvalidContainer("unsupported encryption type"))
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_trailer() {
+        let (mut container, _) = container(b"some data");
+        let len = container.len();
+        container[len - 70] ^= 1;
+        assert!(open(&container).is_err());
+
+        let (mut container, _) = self::container(b"some data");
+        container[len - 1] ^= 1;
+        assert!(open(&container).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let (container, _) = container(b"some data");
+        let mut reader = ContainerReader::new(&container[..], Box::pin([0u8; 32])).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+}
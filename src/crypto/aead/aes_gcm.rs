@@ -8,7 +8,7 @@ use sha2::Sha512;
 use uuid::Uuid;
 use zeroize::Zeroize;
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::zeroize_allocator::Zeroing;
 
 use super::{ChunkKey, EncryptedChunk};
@@ -16,10 +16,13 @@ use super::{ChunkKey, EncryptedChunk};
 const AES_GCM_KEY_NAME: &str = "aesgcm seed";
 const AES_GCM_RATCHET_NAME: &str = "aesgcm ratchet";
 const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// Bytes added to the plaintext by encryption
+pub(super) const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
 type Nonce = GenericArray<u8, U12>;
 
 #[derive(Debug, PartialEq)]
-pub(super) struct AesGcmKey {
+pub struct AesGcmKey {
     full_key: Zeroing<[u8; 64]>,
     chunk_id: u64,
     file_id: Uuid,
@@ -39,12 +42,6 @@ impl zeroize::Zeroize for AesGcmKey {
 }
 
 impl AesGcmKey {
-    fn aad(file_id: Uuid) -> Vec<u8> {
-        let mut aad = Vec::with_capacity(16);
-        aad.extend_from_slice(file_id.as_bytes());
-        aad
-    }
-
     fn payload_for<'msg, 'aad>(&'aad self, data: &'msg [u8]) -> Payload<'msg, 'aad> {
         // No additional aad
         Payload::from(data)
@@ -69,6 +66,10 @@ impl AesGcmKey {
     fn chain_key(&self) -> &GenericArray<u8, U32> {
         Key::<Aes256Gcm>::from_slice(&self.full_key[32..])
     }
+
+    pub(super) fn file_id(&self) -> Uuid {
+        self.file_id
+    }
 }
 
 impl ChunkKey for AesGcmKey {
@@ -132,6 +133,9 @@ impl ChunkKey for AesGcmKey {
         {
             return Err(super::SymmetricKeyError::InvalidChunkDeriveError.into());
         }
+        if data.encrypted_data.len() < OVERHEAD {
+            return Err(Error::ChunkTooShort);
+        }
         let (nonce, cipher_text) = Self::split_encryption_result(&data.encrypted_data);
         let mut cipher = Aes256Gcm::new(self.encryption_key());
         let plain_text = cipher.decrypt(nonce, self.payload_for(cipher_text))?;
@@ -1,15 +1,21 @@
-use std::u64;
+use std::io::{self, Read, Write};
 
-use aes_gcm::AesGcmKey;
+use sha2::Digest;
 use uuid::Uuid;
 
+pub use aes_gcm::AesGcmKey;
+
 use crate::error::{Error, Result, SymmetricKeyError};
 use crate::zeroize_allocator::Zeroing;
 
 mod aes_gcm;
 
+/// XChaCha20-Poly1305 has no implementation yet, only its sizes for recognizing its chunks
+const XCHACHA_NONCE_SIZE: usize = 24;
+const XCHACHA_TAG_SIZE: usize = 16;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
-enum EncryptionType {
+pub enum EncryptionType {
     AesGcm,
     XChaCha20Poly1305,
 }
@@ -23,6 +29,16 @@ impl From<EncryptionType> for u8 {
     }
 }
 
+impl EncryptionType {
+    /// Bytes added to the plaintext of a chunk by encryption
+    pub fn overhead(&self) -> usize {
+        match self {
+            EncryptionType::AesGcm => aes_gcm::OVERHEAD,
+            EncryptionType::XChaCha20Poly1305 => XCHACHA_NONCE_SIZE + XCHACHA_TAG_SIZE,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum SymmetricEncryptionKey {
     AesGcm(Zeroing<AesGcmKey>),
@@ -59,7 +75,10 @@ pub trait ChunkKey {
     fn decrypt(&self, data: &EncryptedChunk) -> Result<Vec<u8>>;
 }
 
-struct EncryptedChunk {
+/// Encryption type, file id and chunk id
+const HEADER_SIZE: usize = 1 + 16 + 8;
+
+pub struct EncryptedChunk {
     encryption_type: EncryptionType,
     file_id: Uuid,
     chunk_id: u64,
@@ -81,8 +100,30 @@ impl EncryptedChunk {
         }
     }
 
+    pub fn file_id(&self) -> Uuid {
+        self.file_id
+    }
+
+    pub fn chunk_id(&self) -> u64 {
+        self.chunk_id
+    }
+
+    pub fn encryption_type(&self) -> EncryptionType {
+        self.encryption_type
+    }
+
+    /// Content id of the chunk as stored, which does not reveal anything about its plaintext.
+    pub fn content_id(&self) -> [u8; 32] {
+        sha2::Sha256::digest(self.to_bytes()).into()
+    }
+
+    /// Length of a chunk of `plaintext_len` bytes once encrypted and written by [`EncryptedChunk::write_framed`]
+    pub fn framed_len(encryption_type: EncryptionType, plaintext_len: u64) -> u64 {
+        (4 + HEADER_SIZE + encryption_type.overhead()) as u64 + plaintext_len
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes: Vec<u8> = Vec::with_capacity(1 + 16 + 8 + self.encrypted_data.len());
+        let mut bytes: Vec<u8> = Vec::with_capacity(HEADER_SIZE + self.encrypted_data.len());
         bytes.push(self.encryption_type.into());
         bytes.extend_from_slice(self.file_id.as_bytes());
         bytes.extend_from_slice(&self.chunk_id.to_le_bytes());
@@ -90,7 +131,37 @@ impl EncryptedChunk {
         bytes
     }
 
+    /// Writes the chunk prefixed with its length, so consecutive chunks can be read back from a stream.
+    pub fn write_framed(&self, writer: &mut impl Write) -> io::Result<()> {
+        let bytes = self.to_bytes();
+        let len = u32::try_from(bytes.len())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&bytes)
+    }
+
+    /// Reads a chunk written by [`EncryptedChunk::write_framed`].
+    ///
+    /// Returns `None` if the stream ends cleanly before a new frame.
+    pub fn read_framed(reader: &mut impl Read) -> Result<Option<Self>> {
+        let mut len = [0u8; 4];
+        match reader.read(&mut len[..1])? {
+            0 => return Ok(None),
+            _ => reader.read_exact(&mut len[1..])?,
+        }
+        let len = u32::from_le_bytes(len) as u64;
+
+        let mut bytes = Vec::new();
+        if reader.take(len).read_to_end(&mut bytes)? as u64 != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Self::parse(&bytes).map(Some)
+    }
+
     pub fn parse(encrypted_chunk: &[u8]) -> Result<Self> {
+        if encrypted_chunk.len() < HEADER_SIZE {
+            return Err(Error::ChunkTooShort);
+        }
         let encryption_type = EncryptionType::try_from(encrypted_chunk[0])?;
         let file_id = Uuid::from_slice(&encrypted_chunk[1..17]).map_err(Error::from)?;
         let chunk_id = u64::from_le_bytes(
@@ -98,7 +169,7 @@ impl EncryptedChunk {
                 .try_into()
                 .map_err(|_| Error::ParseChunkIdError)?,
         );
-        let encrypted_data = Vec::from(&encrypted_chunk[25..]);
+        let encrypted_data = Vec::from(&encrypted_chunk[HEADER_SIZE..]);
         Ok(Self {
             encryption_type,
             file_id,
@@ -116,33 +187,177 @@ impl TryFrom<&[u8]> for EncryptedChunk {
     }
 }
 
-fn key_for<Key: ChunkKey>(root_key: Zeroing<Key>, chunk_id: u64) -> Result<Zeroing<Key>> {
-    let chunk_id = chunk_id;
-    match root_key.chunk_id() {
-        current_chunk_id if current_chunk_id < chunk_id => key_for(root_key.next_key()?, chunk_id),
-        current_chunk_id if current_chunk_id == chunk_id => Ok(root_key),
-        _ => Err(SymmetricKeyError::InvalidChunkDeriveError.into()),
+fn key_for<Key: ChunkKey>(mut key: Zeroing<Key>, chunk_id: u64) -> Result<Zeroing<Key>> {
+    if key.chunk_id() > chunk_id {
+        return Err(SymmetricKeyError::InvalidChunkDeriveError.into());
     }
+    while key.chunk_id() < chunk_id {
+        key = key.next_key()?;
+    }
+    Ok(key)
 }
 
-struct FileDecryptor {
-    key: SymmetricEncryptionKey,
-    encrypted_chunk: EncryptedChunk,
+impl SymmetricEncryptionKey {
+    fn generate_for(prk: Zeroing<[u8; 32]>, encrypted_chunk: &EncryptedChunk) -> Result<Self> {
+        match encrypted_chunk.encryption_type {
+            EncryptionType::AesGcm => {
+                AesGcmKey::generate_for(prk, encrypted_chunk.file_id, encrypted_chunk.chunk_id)
+                    .map(SymmetricEncryptionKey::AesGcm)
+            }
+            unsupported => Err(SymmetricKeyError::InvalidEncryptionType(unsupported.into()).into()),
+        }
+    }
+
+    fn chunk_id(&self) -> u64 {
+        match self {
+            SymmetricEncryptionKey::AesGcm(key) => key.chunk_id(),
+        }
+    }
+
+    fn next_key(&self) -> Result<Self> {
+        match self {
+            SymmetricEncryptionKey::AesGcm(key) => {
+                key.next_key().map(SymmetricEncryptionKey::AesGcm)
+            }
+        }
+    }
+
+    /// Whether this key or a key ratcheted from it can decrypt `encrypted_chunk`
+    fn precedes(&self, encrypted_chunk: &EncryptedChunk) -> bool {
+        match self {
+            SymmetricEncryptionKey::AesGcm(key) => {
+                encrypted_chunk.encryption_type == EncryptionType::AesGcm
+                    && key.file_id() == encrypted_chunk.file_id
+                    && key.chunk_id() <= encrypted_chunk.chunk_id
+            }
+        }
+    }
+
+    fn key_for(self, chunk_id: u64) -> Result<Self> {
+        match self {
+            SymmetricEncryptionKey::AesGcm(key) => {
+                key_for(key, chunk_id).map(SymmetricEncryptionKey::AesGcm)
+            }
+        }
+    }
+
+    fn decrypt(&self, encrypted_chunk: &EncryptedChunk) -> Result<Vec<u8>> {
+        match self {
+            SymmetricEncryptionKey::AesGcm(key) => key.decrypt(encrypted_chunk),
+        }
+    }
+}
+
+/// Encrypts the chunks of a single file in order, ratcheting to the next chunk key after each chunk.
+pub(crate) struct FileEncryptor<Key: ChunkKey> {
+    key: Zeroing<Key>,
+}
+
+impl<Key: ChunkKey> FileEncryptor<Key> {
+    pub fn new(key: Zeroing<Key>) -> Self {
+        Self { key }
+    }
+
+    pub fn generate(prk: Zeroing<[u8; 32]>, file_id: Uuid) -> Result<Self> {
+        Ok(Self::new(Key::generate(prk, file_id)?))
+    }
+
+    pub fn encrypt(&mut self, data: &[u8]) -> Result<EncryptedChunk> {
+        let next_key = self.key.next_key()?;
+        let encrypted_chunk = self.key.encrypt(data)?;
+        self.key = next_key;
+        Ok(encrypted_chunk)
+    }
+}
+
+/// Decrypts the chunks of a single file in order, starting from chunk 0.
+///
+/// The file id and encryption type are taken from the first chunk unless the file id is given up
+/// front. Any chunk that is out of order or belongs to a different file is rejected, so chunks
+/// cannot be dropped or reordered undetected.
+pub(crate) struct FileDecryptor {
+    prk: Zeroing<[u8; 32]>,
+    file_id: Option<Uuid>,
+    key: Option<SymmetricEncryptionKey>,
 }
 
 impl FileDecryptor {
-    fn new(prk: Zeroing<[u8; 32]>, data: &[u8]) -> Result<Self> {
-        let encrypted_chunk = EncryptedChunk::parse(data)?;
-        let key = match encrypted_chunk.encryption_type {
-            EncryptionType::AesGcm => {
-                AesGcmKey::generate_for(prk, encrypted_chunk.file_id, encrypted_chunk.chunk_id)
-                    .map(SymmetricEncryptionKey::AesGcm)?
+    pub fn new(prk: Zeroing<[u8; 32]>) -> Self {
+        Self {
+            prk,
+            file_id: None,
+            key: None,
+        }
+    }
+
+    pub fn for_file(prk: Zeroing<[u8; 32]>, file_id: Uuid) -> Self {
+        Self {
+            prk,
+            file_id: Some(file_id),
+            key: None,
+        }
+    }
+
+    pub fn decrypt(&mut self, encrypted_chunk: &EncryptedChunk) -> Result<Vec<u8>> {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None if self.file_id.is_some_and(|id| id != encrypted_chunk.file_id) => {
+                return Err(SymmetricKeyError::InvalidFileId.into())
+            }
+            None if encrypted_chunk.chunk_id == 0 => {
+                SymmetricEncryptionKey::generate_for(Box::pin(*self.prk), encrypted_chunk)?
             }
-            EncryptionType::XChaCha20Poly1305 => todo!(),
+            None => return Err(SymmetricKeyError::InvalidChunkId.into()),
         };
-        Ok(Self {
-            key,
-            encrypted_chunk,
-        })
+        if key.chunk_id() != encrypted_chunk.chunk_id {
+            self.key = Some(key);
+            return Err(SymmetricKeyError::InvalidChunkId.into());
+        }
+
+        let data = key.decrypt(encrypted_chunk);
+        self.key = Some(match data {
+            Ok(_) => key.next_key()?,
+            Err(_) => key,
+        });
+        data
+    }
+}
+
+/// Encrypts a standalone blob, such as an index or manifest, as the only chunk of a new file id.
+pub(crate) fn encrypt_blob(prk: &Zeroing<[u8; 32]>, data: &[u8]) -> Result<EncryptedChunk> {
+    AesGcmKey::generate(Box::pin(**prk), Uuid::now_v7())?.encrypt(data)
+}
+
+/// Decrypts a blob encrypted by [`encrypt_blob`].
+pub(crate) fn decrypt_blob(
+    prk: &Zeroing<[u8; 32]>,
+    encrypted_chunk: &EncryptedChunk,
+) -> Result<Vec<u8>> {
+    FileDecryptor::new(Box::pin(**prk)).decrypt(encrypted_chunk)
+}
+
+/// Decrypts chunks of a single file in any order, as needed for random access.
+///
+/// Keys are ratcheted forward from the most recently used key where possible and otherwise derived
+/// again from the first chunk key. Unlike [`FileDecryptor`], nothing stops chunks from being
+/// skipped, so callers must check the chunks they decrypt against a [`crate::file::FileManifest`].
+pub(crate) struct ChunkDecryptor {
+    prk: Zeroing<[u8; 32]>,
+    key: Option<SymmetricEncryptionKey>,
+}
+
+impl ChunkDecryptor {
+    pub fn new(prk: Zeroing<[u8; 32]>) -> Self {
+        Self { prk, key: None }
+    }
+
+    pub fn decrypt(&mut self, encrypted_chunk: &EncryptedChunk) -> Result<Vec<u8>> {
+        let key = match self.key.take() {
+            Some(key) if key.precedes(encrypted_chunk) => key.key_for(encrypted_chunk.chunk_id)?,
+            _ => SymmetricEncryptionKey::generate_for(Box::pin(*self.prk), encrypted_chunk)?,
+        };
+        let data = key.decrypt(encrypted_chunk);
+        self.key = Some(key);
+        data
     }
 }
@@ -1,3 +1,4 @@
+use ed25519_dalek::{Signature, Signer};
 use ed25519_dalek_bip32::ExtendedSigningKey;
 
 use crate::{
@@ -5,6 +6,8 @@ use crate::{
     zeroize_allocator::Zeroing,
 };
 
+use super::AsymmetricCryptoKey;
+
 pub(crate) type ClassicalSigningKeyPair = ExtendedSigningKey;
 
 /// Generate a new `SigningKeys` instance from prk already prepared by hmac.
@@ -14,6 +17,22 @@ pub(crate) fn generate(prk: Zeroing<[u8; 32]>) -> Result<Zeroing<ClassicalSignin
     ))
 }
 
+impl AsymmetricCryptoKey for ClassicalSigningKeyPair {
+    fn generate(prk: Zeroing<[u8; 32]>) -> Result<Zeroing<Self>> {
+        generate(prk)
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.signing_key.sign(data).to_vec())
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8]) -> Result<bool> {
+        Ok(Signature::from_slice(signature)
+            .map(|signature| self.signing_key.verify(data, &signature).is_ok())
+            .unwrap_or(false))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -33,4 +52,14 @@ mod tests {
 
         assert_eq!(SIGNING_KEY, signing_key_pair.signing_key.to_bytes())
     }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signing_key_pair = generate(Box::pin(crate::crypto::tests::PRK)).unwrap();
+        let signature = signing_key_pair.sign(b"data").unwrap();
+
+        assert!(signing_key_pair.verify(b"data", &signature).unwrap());
+        assert!(!signing_key_pair.verify(b"other data", &signature).unwrap());
+        assert!(!signing_key_pair.verify(b"data", &signature[1..]).unwrap());
+    }
 }
@@ -1,9 +1,9 @@
 use crate::error::Result;
 use crate::zeroize_allocator::Zeroing;
 
-mod ed25519;
+pub(crate) mod ed25519;
 
-trait AsymmetricCryptoKey {
+pub(crate) trait AsymmetricCryptoKey {
     fn generate(prk: Zeroing<[u8; 32]>) -> Result<Zeroing<Self>>
     where
         Self: Sized;
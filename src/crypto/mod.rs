@@ -1,13 +1,25 @@
-mod aead;
-mod asym;
+pub(crate) mod aead;
+pub(crate) mod asym;
 
 use argon2::{Algorithm, Argon2, Params, Version};
+use hmac::{Hmac, Mac};
 use sha2::Digest;
 
 use crate::error::{Error, Result};
 use crate::zeroize_allocator::Zeroing;
 
-fn generate_prk(ikm: String) -> Result<Zeroing<[u8; 32]>> {
+const SIGNING_KEY_NAME: &str = "signing";
+
+pub(crate) fn generate_prk(ikm: String) -> Result<Zeroing<[u8; 32]>> {
+    let salt_hash = sha2::Sha256::new()
+        .chain_update("federated drive".as_bytes())
+        .finalize();
+    generate_salted_prk(&ikm, &salt_hash)
+}
+
+/// Like [`generate_prk`], but with a `salt` of at least 8 bytes, stored next to what the prk
+/// protects so equal passwords give unrelated prks.
+pub(crate) fn generate_salted_prk(ikm: &str, salt: &[u8]) -> Result<Zeroing<[u8; 32]>> {
     let params = if cfg!(test) {
         Params::new(
             1024, // 64 MiB
@@ -30,15 +42,26 @@ fn generate_prk(ikm: String) -> Result<Zeroing<[u8; 32]>> {
         params.map_err(Error::from)?,
     );
 
-    let salt_hash = sha2::Sha256::new()
-        .chain_update("federated drive".as_bytes())
-        .finalize();
-
     let mut prk = Box::pin([0u8; 32]);
-    argon.hash_password_into(ikm.as_bytes(), &salt_hash, &mut *prk)?;
+    argon.hash_password_into(ikm.as_bytes(), salt, &mut *prk)?;
     Ok(prk)
 }
 
+/// Derives an independent prk for the purpose given by `name`, so a single prk can seed several keys.
+pub(crate) fn derive_prk(prk: &Zeroing<[u8; 32]>, name: &str) -> Result<Zeroing<[u8; 32]>> {
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(&**prk)?;
+    mac.update(name.as_bytes());
+    Ok(Box::pin(mac.finalize().into_bytes().into()))
+}
+
+/// Signing key for data encrypted with keys derived from `prk`.
+pub(crate) fn signing_key(
+    prk: &Zeroing<[u8; 32]>,
+) -> Result<Zeroing<asym::ed25519::ClassicalSigningKeyPair>> {
+    use asym::AsymmetricCryptoKey;
+    asym::ed25519::ClassicalSigningKeyPair::generate(derive_prk(prk, SIGNING_KEY_NAME)?)
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -54,4 +77,28 @@ pub mod tests {
         let prk = generate_prk(ikm).unwrap();
         assert_eq!(PRK, *prk)
     }
+
+    #[test]
+    fn test_generate_salted_prk() {
+        let prk = generate_salted_prk("password", b"some salt").unwrap();
+        assert_ne!(*prk, PRK);
+        assert_eq!(
+            *prk,
+            *generate_salted_prk("password", b"some salt").unwrap()
+        );
+        assert_ne!(
+            *prk,
+            *generate_salted_prk("password", b"more salt").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_derive_prk() {
+        let signing = derive_prk(&Box::pin(PRK), "signing").unwrap();
+        let other = derive_prk(&Box::pin(PRK), "other").unwrap();
+
+        assert_ne!(*signing, PRK);
+        assert_ne!(*signing, *other);
+        assert_eq!(*signing, *derive_prk(&Box::pin(PRK), "signing").unwrap());
+    }
 }
@@ -0,0 +1,444 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use uuid::Uuid;
+
+use crate::{
+    crypto::aead::{ChunkDecryptor, EncryptedChunk, FileDecryptor},
+    error::{Error, Result},
+    file::FileManifest,
+    zeroize_allocator::Zeroing,
+};
+
+/// Decrypts a stream written by [`crate::encrypting_writer::EncryptingWriter`].
+///
+/// Chunks are decrypted as they are read. Reading fails if chunks are missing, reordered or
+/// tampered with, or if the stream ends before its end marker.
+pub(crate) struct DecryptingReader<R: Read> {
+    reader: R,
+    decryptor: FileDecryptor,
+    buf: Vec<u8>,
+    pos: usize,
+    manifest: FileManifest,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    pub fn new(reader: R, prk: Zeroing<[u8; 32]>) -> Self {
+        Self::with_decryptor(reader, FileDecryptor::new(prk))
+    }
+
+    /// Reads a stream that must belong to the file `file_id`.
+    pub fn for_file(reader: R, prk: Zeroing<[u8; 32]>, file_id: Uuid) -> Self {
+        Self::with_decryptor(reader, FileDecryptor::for_file(prk, file_id))
+    }
+
+    fn with_decryptor(reader: R, decryptor: FileDecryptor) -> Self {
+        Self {
+            reader,
+            decryptor,
+            buf: Vec::new(),
+            pos: 0,
+            manifest: FileManifest::new(),
+        }
+    }
+
+    /// Manifest of the chunks read so far. It is complete once the end marker has been read.
+    pub fn manifest(&self) -> &FileManifest {
+        &self.manifest
+    }
+
+    /// Returns the inner reader, positioned after the last chunk read.
+    #[cfg(test)]
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let encrypted_chunk = EncryptedChunk::read_framed(&mut self.reader)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "encrypted stream ended without an end marker",
+            )
+        })?;
+
+        self.buf = self.decryptor.decrypt(&encrypted_chunk)?;
+        self.pos = 0;
+        if self.buf.is_empty() {
+            self.manifest.mark_complete();
+        } else {
+            self.manifest.add(&encrypted_chunk, self.buf.len() as u64);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.manifest.is_complete() || buf.is_empty() {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+
+        let len = buf.len().min(self.buf.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Random access reader over a stream written by [`crate::encrypting_writer::EncryptingWriter`],
+/// starting at the position of the underlying reader when created.
+///
+/// The plaintext lengths in the file's manifest locate the chunk holding any position, so only
+/// the chunks actually read are decrypted. Every chunk read is checked against its content id in
+/// the manifest.
+pub(crate) struct SeekableDecryptingReader<R: Read + Seek> {
+    reader: R,
+    decryptor: ChunkDecryptor,
+    content_ids: Vec<[u8; 32]>,
+    /// Plaintext position of the start of each chunk, followed by the total plaintext length
+    starts: Vec<u64>,
+    /// Position of each chunk's frame in the encrypted stream
+    offsets: Vec<u64>,
+    /// Index and plaintext of the most recently decrypted chunk
+    chunk: Option<(usize, Vec<u8>)>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> SeekableDecryptingReader<R> {
+    pub fn new(mut reader: R, prk: Zeroing<[u8; 32]>, manifest: &FileManifest) -> Result<Self> {
+        if !manifest.is_complete() {
+            return Err(Error::IncompleteManifest);
+        }
+
+        let mut starts = Vec::with_capacity(manifest.lengths().len() + 1);
+        let mut offsets = Vec::with_capacity(manifest.lengths().len());
+        let mut start = 0;
+        let mut offset = reader.stream_position()?;
+        if let Some(first_chunk) = manifest.content_ids().first() {
+            let encryption_type = EncryptedChunk::read_framed(&mut reader)?
+                .filter(|chunk| chunk.content_id() == *first_chunk)
+                .ok_or(Error::ManifestMismatch)?
+                .encryption_type();
+
+            for len in manifest.lengths() {
+                starts.push(start);
+                offsets.push(offset);
+                start += len;
+                offset += EncryptedChunk::framed_len(encryption_type, *len);
+            }
+        }
+        starts.push(start);
+
+        Ok(Self {
+            reader,
+            decryptor: ChunkDecryptor::new(prk),
+            content_ids: manifest.content_ids().to_vec(),
+            starts,
+            offsets,
+            chunk: None,
+            pos: 0,
+        })
+    }
+
+    /// Total plaintext length of the file
+    pub fn len(&self) -> u64 {
+        self.starts[self.starts.len() - 1]
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn load_chunk(&mut self, index: usize) -> Result<()> {
+        if matches!(self.chunk, Some((current, _)) if current == index) {
+            return Ok(());
+        }
+
+        self.reader.seek(SeekFrom::Start(self.offsets[index]))?;
+        let encrypted_chunk = EncryptedChunk::read_framed(&mut self.reader)?
+            .filter(|chunk| chunk.content_id() == self.content_ids[index])
+            .ok_or(Error::ManifestMismatch)?;
+        let data = self.decryptor.decrypt(&encrypted_chunk)?;
+        if data.len() as u64 != self.starts[index + 1] - self.starts[index] {
+            return Err(Error::ManifestMismatch);
+        }
+
+        self.chunk = Some((index, data));
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableDecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len() || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = self.starts.partition_point(|start| *start <= self.pos) - 1;
+        self.load_chunk(index)?;
+        let (_, data) = self.chunk.as_ref().expect("chunk loaded above");
+
+        let offset = (self.pos - self.starts[index]) as usize;
+        let len = buf.len().min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableDecryptingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::{
+        crypto::{
+            aead::{AesGcmKey, ChunkKey},
+            tests::PRK,
+        },
+        encrypting_writer::EncryptingWriter,
+        file,
+    };
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    fn encrypt(data: &[u8]) -> Vec<u8> {
+        encrypt_with_manifest(data).0
+    }
+
+    fn encrypt_with_manifest(data: &[u8]) -> (Vec<u8>, FileManifest) {
+        let key = AesGcmKey::generate(Box::pin(PRK), uuid::Uuid::now_v7()).unwrap();
+        let mut writer = EncryptingWriter::new(Vec::new(), key);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn seekable(data: &[u8]) -> SeekableDecryptingReader<io::Cursor<Vec<u8>>> {
+        let (encrypted, manifest) = encrypt_with_manifest(data);
+        SeekableDecryptingReader::new(io::Cursor::new(encrypted), Box::pin(PRK), &manifest).unwrap()
+    }
+
+    fn decrypt(encrypted: &[u8]) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        DecryptingReader::new(encrypted, Box::pin(PRK)).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn frames(mut encrypted: &[u8]) -> Vec<EncryptedChunk> {
+        let mut frames = Vec::new();
+        while let Some(chunk) = EncryptedChunk::read_framed(&mut encrypted).unwrap() {
+            frames.push(chunk);
+        }
+        frames
+    }
+
+    fn join(frames: &[EncryptedChunk]) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        for frame in frames {
+            frame.write_framed(&mut encrypted).unwrap();
+        }
+        encrypted
+    }
+
+    #[test]
+    fn round_trips_file() {
+        let contents = std::fs::read(PATH).unwrap();
+        assert_eq!(decrypt(&encrypt(&contents)).unwrap(), contents);
+    }
+
+    #[test]
+    fn round_trips_binary_data() {
+        let binary: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        assert_eq!(decrypt(&encrypt(&binary)).unwrap(), binary);
+    }
+
+    #[test]
+    fn round_trips_exact_chunk_multiple() {
+        let data = vec![7u8; 4 * file::CHUNK_SIZE as usize];
+        assert_eq!(decrypt(&encrypt(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn round_trips_empty_input() {
+        assert!(decrypt(&encrypt(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rebuilds_manifest() {
+        let contents = std::fs::read(PATH).unwrap();
+        let (encrypted, manifest) = encrypt_with_manifest(&contents);
+        let mut reader = DecryptingReader::new(&encrypted[..], Box::pin(PRK));
+
+        reader.read_exact(&mut [0; 12]).unwrap();
+        assert!(!reader.manifest().is_complete());
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(reader.manifest(), &manifest);
+    }
+
+    #[test]
+    fn rejects_stream_of_other_file() {
+        let encrypted = encrypt(b"some data");
+        let mut reader =
+            DecryptingReader::for_file(&encrypted[..], Box::pin(PRK), uuid::Uuid::now_v7());
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn leaves_trailing_data_unread() {
+        let mut encrypted = encrypt(b"some data");
+        encrypted.extend_from_slice(b"trailer");
+
+        let mut reader = DecryptingReader::new(&encrypted[..], Box::pin(PRK));
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+
+        assert_eq!(data, b"some data");
+        assert_eq!(reader.into_inner(), b"trailer");
+    }
+
+    #[test]
+    fn rejects_truncated_stream() {
+        let encrypted = encrypt(&std::fs::read(PATH).unwrap());
+        let frames = frames(&encrypted);
+
+        let error = decrypt(&join(&frames[..frames.len() - 1])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(decrypt(&encrypted[..encrypted.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_first_chunk() {
+        let frames = frames(&encrypt(&std::fs::read(PATH).unwrap()));
+        assert!(decrypt(&join(&frames[1..])).is_err());
+    }
+
+    #[test]
+    fn rejects_reordered_chunks() {
+        let mut frames = frames(&encrypt(&std::fs::read(PATH).unwrap()));
+        frames.swap(1, 2);
+        assert!(decrypt(&join(&frames)).is_err());
+    }
+
+    #[test]
+    fn rejects_chunks_from_other_file() {
+        let mut frames = frames(&encrypt(&std::fs::read(PATH).unwrap()));
+        frames[1] = self::frames(&encrypt(&std::fs::read(PATH).unwrap())).remove(1);
+        assert!(decrypt(&join(&frames)).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let encrypted = encrypt(b"some data");
+        let mut reader = DecryptingReader::new(&encrypted[..], Box::pin([0u8; 32]));
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn seekable_reads_whole_file() {
+        let contents = std::fs::read(PATH).unwrap();
+        let mut reader = seekable(&contents);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+
+        assert_eq!(reader.len(), contents.len() as u64);
+        assert_eq!(data, contents);
+    }
+
+    #[test]
+    fn seekable_reads_ranges() {
+        let contents = std::fs::read(PATH).unwrap();
+        let mut reader = seekable(&contents);
+
+        for (start, end) in [(3, 21), (70, 77), (0, 1), (10, 10), (5, contents.len())] {
+            let mut data = vec![0; end - start];
+            reader.seek(SeekFrom::Start(start as u64)).unwrap();
+            reader.read_exact(&mut data).unwrap();
+            assert_eq!(data, contents[start..end]);
+        }
+    }
+
+    #[test]
+    fn seekable_seeks_from_end_and_current() {
+        let contents = std::fs::read(PATH).unwrap();
+        let mut reader = seekable(&contents);
+
+        let pos = reader.seek(SeekFrom::End(-12)).unwrap();
+        assert_eq!(pos, contents.len() as u64 - 12);
+        reader.seek(SeekFrom::Current(2)).unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, contents[contents.len() - 10..]);
+
+        assert!(reader.seek(SeekFrom::Current(-1000)).is_err());
+        reader.seek(SeekFrom::End(10)).unwrap();
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+    }
+
+    #[test]
+    fn seekable_handles_empty_file() {
+        let mut reader = seekable(&[]);
+        assert!(reader.is_empty());
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+    }
+
+    #[test]
+    fn seekable_only_decrypts_requested_chunks() {
+        let contents = std::fs::read(PATH).unwrap();
+        let (encrypted, manifest) = encrypt_with_manifest(&contents);
+        let mut frames = frames(&encrypted);
+        // Corrupt a chunk that is never read
+        frames[5] = self::frames(&encrypt(&contents)).remove(5);
+
+        let mut reader =
+            SeekableDecryptingReader::new(io::Cursor::new(join(&frames)), Box::pin(PRK), &manifest)
+                .unwrap();
+        let mut data = vec![0; 8];
+        reader.seek(SeekFrom::Start(64)).unwrap();
+        reader.read_exact(&mut data).unwrap();
+        assert_eq!(data, contents[64..72]);
+
+        reader.seek(SeekFrom::Start(40)).unwrap();
+        assert_eq!(
+            reader.read(&mut data).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn seekable_rejects_manifest_of_other_file() {
+        let contents = std::fs::read(PATH).unwrap();
+        let (encrypted, _) = encrypt_with_manifest(&contents);
+        let (_, manifest) = encrypt_with_manifest(&contents);
+
+        assert!(SeekableDecryptingReader::new(
+            io::Cursor::new(encrypted),
+            Box::pin(PRK),
+            &manifest
+        )
+        .is_err());
+    }
+}
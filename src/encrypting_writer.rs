@@ -0,0 +1,146 @@
+use std::io::{self, Write};
+
+use crate::{
+    crypto::aead::{AesGcmKey, ChunkKey, FileEncryptor},
+    error::Result,
+    file::{self, FileManifest},
+    zeroize_allocator::Zeroing,
+};
+
+/// Encrypts everything written to it into a stream of length prefixed [`EncryptedChunk`]s.
+///
+/// Plaintext is buffered into `CHUNK_SIZE` chunks, each encrypted with the next key of the
+/// [`ChunkKey`] ratchet. [`EncryptingWriter::finish`] must be called once all data is written: it
+/// encrypts the final partial chunk followed by an empty chunk marking the end of the stream.
+/// A stream without that marker is treated as truncated by [`crate::decrypting_reader::DecryptingReader`].
+///
+/// [`EncryptedChunk`]: crate::crypto::aead::EncryptedChunk
+pub(crate) struct EncryptingWriter<W: Write, K: ChunkKey = AesGcmKey> {
+    writer: W,
+    encryptor: FileEncryptor<K>,
+    buf: Vec<u8>,
+    manifest: FileManifest,
+}
+
+impl<W: Write, K: ChunkKey> EncryptingWriter<W, K> {
+    pub fn new(writer: W, key: Zeroing<K>) -> Self {
+        Self {
+            writer,
+            encryptor: FileEncryptor::new(key),
+            buf: Vec::with_capacity(file::CHUNK_SIZE as usize),
+            manifest: FileManifest::new(),
+        }
+    }
+
+    /// Writes any buffered data and the end of stream marker, returning the inner writer and the
+    /// manifest of the data chunks written.
+    pub fn finish(mut self) -> Result<(W, FileManifest)> {
+        if !self.buf.is_empty() {
+            self.write_chunk()?;
+        }
+        // Data chunks are never empty, so an empty chunk unambiguously ends the stream
+        self.encryptor
+            .encrypt(&[])?
+            .write_framed(&mut self.writer)?;
+        self.writer.flush()?;
+        self.manifest.mark_complete();
+        Ok((self.writer, self.manifest))
+    }
+
+    fn write_chunk(&mut self) -> Result<()> {
+        let encrypted_chunk = self.encryptor.encrypt(&self.buf)?;
+        encrypted_chunk.write_framed(&mut self.writer)?;
+        self.manifest.add(&encrypted_chunk, self.buf.len() as u64);
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write, K: ChunkKey> Write for EncryptingWriter<W, K> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(file::CHUNK_SIZE as usize - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+
+        if self.buf.len() == file::CHUNK_SIZE as usize {
+            self.write_chunk()?;
+        }
+        Ok(len)
+    }
+
+    /// Flushes the inner writer. Partially filled chunks stay buffered until they are full or the
+    /// writer is finished.
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{aead::EncryptedChunk, tests::PRK};
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    fn key() -> Zeroing<AesGcmKey> {
+        AesGcmKey::generate(Box::pin(PRK), uuid::Uuid::now_v7()).unwrap()
+    }
+
+    fn read_chunks(mut encrypted: &[u8]) -> Vec<EncryptedChunk> {
+        let mut chunks = Vec::new();
+        while let Some(chunk) = EncryptedChunk::read_framed(&mut encrypted).unwrap() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    #[test]
+    fn writes_full_chunks_and_end_marker() {
+        let contents = std::fs::read(PATH).unwrap();
+        let mut writer = EncryptingWriter::new(Vec::new(), key());
+        writer.write_all(&contents).unwrap();
+        let chunks = read_chunks(&writer.finish().unwrap().0);
+
+        assert_eq!(
+            chunks.len(),
+            contents.len().div_ceil(file::CHUNK_SIZE as usize) + 1
+        );
+        for (pos, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.chunk_id(), pos as u64);
+        }
+    }
+
+    #[test]
+    fn finish_returns_manifest_of_data_chunks() {
+        let contents = std::fs::read(PATH).unwrap();
+        let mut writer = EncryptingWriter::new(Vec::new(), key());
+        writer.write_all(&contents).unwrap();
+        let (encrypted, manifest) = writer.finish().unwrap();
+        let chunks = read_chunks(&encrypted);
+
+        assert!(manifest.is_complete());
+        assert_eq!(manifest.size(), contents.len() as u64);
+        assert_eq!(
+            manifest.content_ids(),
+            chunks[..chunks.len() - 1]
+                .iter()
+                .map(|c| c.content_id())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn flush_does_not_write_partial_chunks() {
+        let mut writer = EncryptingWriter::new(Vec::new(), key());
+        writer.write_all(b"abc").unwrap();
+        writer.flush().unwrap();
+
+        assert!(writer.writer.is_empty());
+        assert_eq!(read_chunks(&writer.finish().unwrap().0).len(), 2);
+    }
+
+    #[test]
+    fn empty_input_writes_end_marker() {
+        let writer = EncryptingWriter::new(Vec::new(), key());
+        assert_eq!(read_chunks(&writer.finish().unwrap().0).len(), 1);
+    }
+}
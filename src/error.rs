@@ -3,47 +3,132 @@ use std::{io, string::FromUtf8Error};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
-    #[error("transparent")]
+    #[error(transparent)]
     Io {
         #[from]
         source: io::Error,
     },
-    #[error("transparent")]
+    #[error(transparent)]
     String {
         #[from]
         source: FromUtf8Error,
     },
 
-    #[error("transparent")]
+    #[error(transparent)]
     Argon2 {
         #[from]
         source: argon2::Error,
     },
 
-    #[error("transparent")]
+    #[error(transparent)]
     Ed25519SignatureError {
         #[from]
         source: ed25519_dalek_bip32::Error,
     },
 
-    #[error("transparent")]
+    #[error(transparent)]
     DigestInvalidLength(#[from] sha2::digest::InvalidLength),
 
-    #[error("transparent")]
+    #[error(transparent)]
     HkdfInvalidLength(#[from] hkdf::InvalidLength),
 
-    #[error("transparent")]
+    #[error(transparent)]
     AesGcm(#[from] aes_gcm::Error),
 
-    #[error("transparent")]
+    #[error(transparent)]
     SymmetricCryptoKeyError(#[from] SymmetricKeyError),
 
-    #[error("transparent")]
+    #[error(transparent)]
     Uuid(#[from] uuid::Error),
 
+    #[error(transparent)]
+    Bincode(#[from] bincode::Error),
+
+    #[error(transparent)]
+    Ssh(#[from] ssh2::Error),
+
     #[error("failed to parse chunk id from file stream")]
     ParseChunkIdError,
+
+    #[error("encrypted chunk is too short")]
+    ChunkTooShort,
+
+    #[error("file manifest is incomplete")]
+    IncompleteManifest,
+
+    #[error("encrypted data does not match its manifest")]
+    ManifestMismatch,
+
+    #[error("failed to parse file manifest")]
+    ParseManifest,
+
+    #[error("invalid container: {0}")]
+    InvalidContainer(&'static str),
+
+    #[error("invalid archive: {0}")]
+    InvalidArchive(&'static str),
+
+    #[error("invalid pack: {0}")]
+    InvalidPack(&'static str),
+
+    #[error("invalid vault: {0}")]
+    InvalidVault(&'static str),
+
+    #[error("wrong password")]
+    WrongPassword,
+
+    #[error("snapshot {0} not found")]
+    SnapshotNotFound(uuid::Uuid),
+
+    #[error("invalid snapshot: {0}")]
+    InvalidSnapshot(&'static str),
+
+    #[error("{0:?} is not in the snapshot")]
+    NotInSnapshot(std::path::PathBuf),
+
+    #[error("retention policy keeps no snapshots")]
+    EmptyRetentionPolicy,
+
+    #[error("vault is locked: {0}")]
+    Locked(String),
+
+    #[error("another device synced at the same time, sync again to merge its changes")]
+    ConcurrentSync,
+
+    #[error("{0} needs a vault keeping its chunks in its own directory")]
+    NeedsSharedVault(&'static str),
+
+    #[error("no backup in the vault")]
+    NoBackup,
+
+    #[error("chunk {} not found", crate::store::hex(.0))]
+    ChunkNotFound(crate::store::ContentId),
+
+    #[error("chunk {} does not match its content id", crate::store::hex(.0))]
+    CorruptChunk(crate::store::ContentId),
+
+    #[error("remote storage error: {0}")]
+    Remote(String),
+
+    #[error("unsafe path {0:?}")]
+    UnsafePath(std::path::PathBuf),
+
+    #[error("invalid store location {0:?}")]
+    InvalidStoreLocation(String),
+
+    #[error("environment variable {0} is not set")]
+    MissingEnvironment(&'static str),
+}
+
+impl From<Error> for io::Error {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Io { source } => source,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -60,4 +145,4 @@ pub enum SymmetricKeyError {
     WrongEncryptionType,
 }
 
-pub(crate) type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = std::result::Result<T, Error>;
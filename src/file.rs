@@ -1,7 +1,9 @@
 #![allow(dead_code)]
 
 use crate::buf_reader::BufReader;
+use crate::crypto::aead::EncryptedChunk;
 use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
 use sha2::Digest;
 
 #[cfg(not(test))]
@@ -9,16 +11,18 @@ pub(crate) const CHUNK_SIZE: u64 = 1024;
 #[cfg(test)]
 pub(crate) const CHUNK_SIZE: u64 = 8;
 
+/// Plaintext chunker for a file on disk.
+///
+/// Chunks are not encrypted, so their ids never end up in a [`FileManifest`], which only ever holds
+/// the content ids of encrypted chunks.
 #[derive(Debug, Clone)]
 pub(crate) struct File {
-    manifest: FileManifest,
     path: String,
 }
 
 impl File {
     pub fn open(path: &str) -> Result<Self> {
         Ok(Self {
-            manifest: FileManifest::new(),
             path: path.to_owned(),
         })
     }
@@ -32,10 +36,9 @@ impl File {
             .div_ceil(CHUNK_SIZE))
     }
 
-    pub fn chunk(&mut self) -> Result<Vec<FileChunk>> {
+    pub fn chunk(&self) -> Result<Vec<FileChunk>> {
         let mut chunks = Vec::new();
 
-        // Iteration here also updates manifest
         for chunk in self.iter()? {
             chunks.push(chunk?);
         }
@@ -43,14 +46,9 @@ impl File {
         Ok(chunks)
     }
 
-    pub fn manifest(&self) -> &FileManifest {
-        &self.manifest
-    }
-
-    pub fn iter(&mut self) -> Result<FileIterator> {
+    pub fn iter(&self) -> Result<FileIterator> {
         Ok(FileIterator {
             buf_reader: BufReader::open(&self.path)?,
-            file: self,
         })
     }
 }
@@ -60,9 +58,9 @@ pub(crate) struct FileChunk {
 }
 
 impl FileChunk {
-    fn new(buf: &str) -> Self {
+    fn new(buf: &[u8]) -> Self {
         Self {
-            buffer: Vec::from(buf.as_bytes()),
+            buffer: Vec::from(buf),
         }
     }
     fn content_id(&self) -> [u8; 32] {
@@ -74,58 +72,117 @@ impl FileChunk {
     }
 }
 
-pub(crate) struct FileIterator<'a> {
-    file: &'a mut File,
+pub(crate) struct FileIterator {
     buf_reader: BufReader,
 }
 
-impl Iterator for FileIterator<'_> {
+impl Iterator for FileIterator {
     type Item = Result<FileChunk>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.buf_reader.next() {
-            Some(Ok(buf)) => {
-                // TODO Encrypt the buffer prior cid calculation and return
-                let chunk = FileChunk::new(&buf);
-                self.file.manifest.add(&chunk);
-                Some(Ok(chunk))
-            }
+            Some(Ok(buf)) => Some(Ok(FileChunk::new(&buf))),
             Some(Err(e)) => Some(Err(Error::from(e))),
-            None => {
-                self.file.manifest.mark_complete();
-                None
-            }
+            None => None,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct FileManifest {
+    /// Content id of each encrypted chunk
     content_ids: Vec<[u8; 32]>,
+    /// Plaintext length of each chunk
+    lengths: Vec<u64>,
     complete: bool,
 }
 
 impl FileManifest {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             content_ids: vec![],
+            lengths: vec![],
             complete: false,
         }
     }
 
-    fn add(&mut self, chunk: &FileChunk) {
+    pub fn add(&mut self, chunk: &EncryptedChunk, plaintext_len: u64) {
         self.content_ids.push(chunk.content_id());
+        self.lengths.push(plaintext_len);
     }
 
-    fn mark_complete(&mut self) {
+    pub fn mark_complete(&mut self) {
         self.complete = true;
     }
+
+    pub fn content_ids(&self) -> &[[u8; 32]] {
+        &self.content_ids
+    }
+
+    pub fn lengths(&self) -> &[u64] {
+        &self.lengths
+    }
+
+    /// Total plaintext length of the file
+    pub fn size(&self) -> u64 {
+        self.lengths.iter().sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Serializes the manifest as the chunk count, each chunk's content id and plaintext length,
+    /// and a completion flag. All integers are little endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.content_ids.len() * (32 + 8) + 1);
+        bytes.extend_from_slice(&(self.content_ids.len() as u64).to_le_bytes());
+        for (content_id, len) in self.content_ids.iter().zip(&self.lengths) {
+            bytes.extend_from_slice(content_id);
+            bytes.extend_from_slice(&len.to_le_bytes());
+        }
+        bytes.push(self.complete.into());
+        bytes
+    }
+
+    pub fn parse(manifest: &[u8]) -> Result<Self> {
+        let (count, mut rest) = manifest
+            .split_first_chunk::<8>()
+            .ok_or(Error::ParseManifest)?;
+        let count = u64::from_le_bytes(*count) as usize;
+        if rest.len() != count.checked_mul(32 + 8).ok_or(Error::ParseManifest)? + 1 {
+            return Err(Error::ParseManifest);
+        }
+
+        let mut result = Self::new();
+        for _ in 0..count {
+            let (content_id, remaining) = rest.split_first_chunk::<32>().unwrap();
+            let (len, remaining) = remaining.split_first_chunk::<8>().unwrap();
+            result.content_ids.push(*content_id);
+            result.lengths.push(u64::from_le_bytes(*len));
+            rest = remaining;
+        }
+        result.complete = match rest {
+            [0] => false,
+            [1] => true,
+            _ => return Err(Error::ParseManifest),
+        };
+        Ok(result)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io::Write;
 
     use super::*;
+    use crate::{
+        crypto::{
+            aead::{AesGcmKey, ChunkKey},
+            tests::PRK,
+        },
+        encrypting_writer::EncryptingWriter,
+    };
 
     const PATH: &str = "test/lorem_ipsum";
 
@@ -149,28 +206,79 @@ mod tests {
     }
 
     #[test]
-    fn chunk_updates_manifest() {
-        let mut file = File::open(PATH).unwrap();
-        let chunks = file.chunk().unwrap();
-        assert_eq!(file.manifest.content_ids.len(), 10);
+    fn chunks_are_content_addressed() {
+        let contents = std::fs::read(PATH).unwrap();
+        let chunks = File::open(PATH).unwrap().chunk().unwrap();
+        assert_eq!(chunks.len(), 10);
+
+        for (chunk, data) in chunks.iter().zip(contents.chunks(CHUNK_SIZE as usize)) {
+            assert_eq!(
+                chunk.content_id(),
+                <[u8; 32]>::from(sha2::Sha256::digest(data))
+            );
+        }
+    }
+
+    #[test]
+    fn chunk_lengths() {
+        let contents = std::fs::read(PATH).unwrap();
+        let chunks = File::open(PATH).unwrap().chunk().unwrap();
+
+        let lengths: Vec<_> = chunks.iter().map(|c| c.buffer.len() as u64).collect();
+        assert_eq!(lengths.iter().sum::<u64>(), contents.len() as u64);
+        assert!(lengths[..9].iter().all(|l| *l == CHUNK_SIZE));
+    }
+
+    /// Manifest of `test/lorem_ipsum` as encrypted by [`EncryptingWriter`], and the chunks written
+    fn encrypted_manifest() -> (FileManifest, Vec<EncryptedChunk>) {
+        let key = AesGcmKey::generate(Box::pin(PRK), uuid::Uuid::now_v7()).unwrap();
+        let mut writer = EncryptingWriter::new(Vec::new(), key);
+        writer.write_all(&std::fs::read(PATH).unwrap()).unwrap();
+        let (encrypted, manifest) = writer.finish().unwrap();
+
+        let mut reader = &encrypted[..];
+        let mut chunks = Vec::new();
+        while let Some(chunk) = EncryptedChunk::read_framed(&mut reader).unwrap() {
+            chunks.push(chunk);
+        }
+        (manifest, chunks)
+    }
+
+    #[test]
+    fn manifest_records_encrypted_chunks() {
+        let contents = std::fs::read(PATH).unwrap();
+        let (manifest, chunks) = encrypted_manifest();
 
-        for (pos, chunk) in chunks.iter().enumerate() {
-            assert_eq!(chunk.content_id(), file.manifest.content_ids[pos])
+        // The last chunk written is the empty end of stream marker
+        assert_eq!(manifest.content_ids().len(), chunks.len() - 1);
+        for (content_id, chunk) in manifest.content_ids().iter().zip(&chunks) {
+            assert_eq!(*content_id, chunk.content_id());
         }
+        assert_eq!(manifest.size(), contents.len() as u64);
+        assert!(manifest.lengths()[..9].iter().all(|l| *l == CHUNK_SIZE));
+        assert!(manifest.is_complete());
+    }
+
+    #[test]
+    fn manifest_round_trips_bytes() {
+        let (manifest, _) = encrypted_manifest();
 
-        assert!(file.manifest.complete);
+        let parsed = FileManifest::parse(&manifest.to_bytes()).unwrap();
+        assert_eq!(parsed, manifest);
+
+        let bytes = manifest.to_bytes();
+        assert!(FileManifest::parse(&bytes[..bytes.len() - 1]).is_err());
     }
 
     #[test]
     fn iter() {
-        let mut file = File::open(PATH).unwrap();
-        let mut cids: Vec<[u8; 32]> = Vec::new();
+        let file = File::open(PATH).unwrap();
+        let chunks: Vec<_> = file.iter().unwrap().map(Result::unwrap).collect();
 
-        for chunk in file.iter().unwrap() {
-            let chunk = chunk.unwrap();
-            cids.push(chunk.content_id());
-        }
-        assert_eq!(file.manifest().content_ids, cids);
-        assert!(file.manifest().complete);
+        // 77 bytes in chunks of 8
+        assert_eq!(chunks.len(), 10);
+        assert_eq!(chunks[0].buffer, b"Lorem ip");
+        assert_eq!(chunks[1].buffer, b"sum dolo");
+        assert_eq!(chunks[9].buffer.len(), 5);
     }
 }
@@ -0,0 +1,248 @@
+//! Mark and sweep garbage collection of chunks no snapshot references.
+//!
+//! Every chunk reachable from a snapshot is marked: the encrypted tree manifest of the snapshot
+//! and every chunk of every file in the tree. Everything else in the chunk store is then swept.
+//!
+//! In a vault bundling chunks into packs, sweeping a chunk only drops it from the pack index, so
+//! the packs left less than [`MIN_LIVE`] full are then rewritten without their swept chunks.
+//!
+//! Collection holds an exclusive [`VaultLock`], so it never runs while a writer has stored chunks
+//! that no snapshot references yet.
+
+use std::collections::HashSet;
+
+use crate::{
+    error::Result, lock::VaultLock, snapshot::Snapshot, store::ContentId, tree::TreeEntryKind,
+    vault::Vault,
+};
+
+/// Fraction of a pack that must still be in use for it to be kept as it is
+const MIN_LIVE: f64 = 0.5;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct GcStats {
+    pub snapshots: usize,
+    /// Chunks in the store before the sweep
+    pub chunks: usize,
+    /// Chunks referenced by a snapshot and kept
+    pub referenced: usize,
+    /// Chunks deleted, or that would be deleted on a dry run
+    pub deleted: usize,
+    /// Size of the deleted chunks
+    pub bytes_reclaimed: u64,
+    /// Sparse packs rewritten and removed, never counted on a dry run
+    pub packs_rewritten: usize,
+}
+
+/// Deletes every chunk in the vault's store that no snapshot references, or only counts them if
+/// `dry_run` is set.
+///
+/// Fails without deleting anything if any snapshot cannot be fully read, since the chunks it
+/// references would not be marked.
+pub(crate) fn collect_garbage(vault: &Vault, dry_run: bool) -> Result<GcStats> {
+    let _lock = VaultLock::exclusive(vault)?;
+    let mut stats = GcStats::default();
+
+    let mut marked = HashSet::new();
+    for snapshot in Snapshot::list(vault)? {
+        stats.snapshots += 1;
+        marked.insert(snapshot.tree);
+        for entry in snapshot.tree(vault)?.entries() {
+            if let TreeEntryKind::File(manifest) = &entry.kind {
+                marked.extend(manifest.content_ids());
+            }
+        }
+    }
+
+    let store = vault.store();
+    let chunks = store.list()?;
+    stats.chunks = chunks.len();
+    let unreferenced: Vec<ContentId> = chunks
+        .into_iter()
+        .filter(|id| !marked.contains(id))
+        .collect();
+    stats.referenced = stats.chunks - unreferenced.len();
+
+    for id in unreferenced {
+        stats.bytes_reclaimed += store.size(&id)?;
+        if !dry_run {
+            store.delete(&id)?;
+        }
+        stats.deleted += 1;
+    }
+    if !dry_run {
+        if let Some(repack) = vault.repack_store(MIN_LIVE)? {
+            stats.packs_rewritten = repack.obsolete.len();
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::{
+        error::Error,
+        metadata::MetadataPolicy,
+        snapshot::SnapshotOptions,
+        store::{get_file, local::LocalStore, put_file, ChunkStore},
+        vault::VaultOptions,
+    };
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    fn setup(dir: &tempfile::TempDir) -> (Vault, std::path::PathBuf) {
+        let vault = Vault::init(
+            &dir.path().join("vault"),
+            "password",
+            &VaultOptions::default(),
+        )
+        .unwrap();
+        let root = dir.path().join("tree");
+        fs::create_dir(&root).unwrap();
+        fs::copy(PATH, root.join("lorem_ipsum")).unwrap();
+        (vault, root)
+    }
+
+    fn take(vault: &Vault, root: &Path) -> Snapshot {
+        Snapshot::take(
+            vault,
+            root,
+            &MetadataPolicy::default(),
+            SnapshotOptions::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn keeps_everything_snapshots_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, root) = setup(&dir);
+        let snapshot = take(&vault, &root);
+        let chunks = vault.store().list().unwrap().len();
+
+        let stats = collect_garbage(&vault, false).unwrap();
+        assert_eq!(
+            stats,
+            GcStats {
+                snapshots: 1,
+                chunks,
+                referenced: chunks,
+                ..GcStats::default()
+            }
+        );
+
+        let target = dir.path().join("restored");
+        snapshot
+            .restore(&vault, Path::new(""), &target, &MetadataPolicy::default())
+            .unwrap();
+        assert_eq!(
+            fs::read(target.join("lorem_ipsum")).unwrap(),
+            fs::read(PATH).unwrap()
+        );
+    }
+
+    #[test]
+    fn reclaims_chunks_of_forgotten_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, root) = setup(&dir);
+        let old = take(&vault, &root);
+        let old_chunks = vault.store().list().unwrap().len();
+        fs::write(root.join("lorem_ipsum"), b"rewritten").unwrap();
+        let new = take(&vault, &root);
+        let total = vault.store().list().unwrap().len();
+        Snapshot::forget(&vault, &old.id).unwrap();
+
+        let dry_run = collect_garbage(&vault, true).unwrap();
+        assert_eq!(dry_run.deleted, old_chunks);
+        assert!(dry_run.bytes_reclaimed > fs::metadata(PATH).unwrap().len());
+        assert_eq!(vault.store().list().unwrap().len(), total);
+
+        let stats = collect_garbage(&vault, false).unwrap();
+        assert_eq!(
+            GcStats {
+                packs_rewritten: 0,
+                ..stats
+            },
+            dry_run
+        );
+        assert_eq!(vault.store().list().unwrap().len(), total - old_chunks);
+        let tree = new.tree(&vault).unwrap();
+        let TreeEntryKind::File(manifest) = &tree.entries()[0].kind else {
+            panic!("expected a file");
+        };
+        let mut data = Vec::new();
+        get_file(vault.store(), vault.data_prk(), manifest, &mut data).unwrap();
+        assert_eq!(data, b"rewritten");
+    }
+
+    #[test]
+    fn rewrites_sparse_packs() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultOptions {
+            pack_size: Some(256),
+            ..VaultOptions::default()
+        };
+        let vault = Vault::init(&dir.path().join("vault"), "password", &options).unwrap();
+        let root = dir.path().join("tree");
+        fs::create_dir(&root).unwrap();
+        fs::copy(PATH, root.join("lorem_ipsum")).unwrap();
+        let old = take(&vault, &root);
+        fs::write(root.join("lorem_ipsum"), b"rewritten").unwrap();
+        let new = take(&vault, &root);
+        Snapshot::forget(&vault, &old.id).unwrap();
+
+        let chunks = LocalStore::new(dir.path().join("vault/chunks")).unwrap();
+        let objects = || chunks.list().unwrap().len();
+        let before = objects();
+        let stats = collect_garbage(&vault, false).unwrap();
+        assert!(stats.packs_rewritten > 0);
+        assert!(objects() < before);
+
+        // Opened again from the index recorded in the vault
+        vault.lock();
+        let vault = Vault::open(&dir.path().join("vault"), "password").unwrap();
+        let target = dir.path().join("restored");
+        new.restore(&vault, Path::new(""), &target, &MetadataPolicy::default())
+            .unwrap();
+        assert_eq!(fs::read(target.join("lorem_ipsum")).unwrap(), b"rewritten");
+        assert!(old.tree(&vault).is_err());
+    }
+
+    #[test]
+    fn waits_for_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, _) = setup(&dir);
+        let writer = VaultLock::shared(&vault).unwrap();
+        put_file(
+            vault.store(),
+            vault.data_prk(),
+            &b"not in a snapshot yet"[..],
+        )
+        .unwrap();
+
+        assert!(matches!(
+            collect_garbage(&vault, false),
+            Err(Error::Locked(_))
+        ));
+        let chunks = vault.store().list().unwrap().len();
+        assert!(chunks > 0);
+        drop(writer);
+        assert_eq!(collect_garbage(&vault, false).unwrap().deleted, chunks);
+    }
+
+    #[test]
+    fn sweeps_nothing_if_a_snapshot_is_unreadable() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, root) = setup(&dir);
+        let snapshot = take(&vault, &root);
+        take(&vault, &root);
+        let chunks = vault.store().list().unwrap().len();
+        vault.store().delete(&snapshot.tree).unwrap();
+
+        assert!(collect_garbage(&vault, false).is_err());
+        assert_eq!(vault.store().list().unwrap().len(), chunks - 1);
+    }
+}
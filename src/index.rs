@@ -0,0 +1,318 @@
+//! Local cache of what the files of a folder held when last read, so unchanged files are neither
+//! read nor stored again.
+//!
+//! The index records the size, modification time, change time and inode of every file next to
+//! the hash of its contents and the manifest it was last stored under. A file whose metadata still
+//! matches is taken to be unchanged. Files modified less than [`RACY_WINDOW`] before they were read
+//! are never trusted: a file written again within the granularity of its file system's timestamps
+//! keeps its modification time, so they are read again until their modification time is safely in
+//! the past.
+//!
+//! Each folder has its own index in the vault's `index` directory, encrypted like everything else
+//! in the vault since it lists paths and hashes of their contents. An index that is missing or
+//! cannot be read is simply rebuilt.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    crypto::aead::{self, EncryptedChunk},
+    error::Result,
+    file::FileManifest,
+    metadata::Timestamp,
+    vault::Vault,
+    zeroize_allocator::Zeroing,
+};
+
+const INDEX_KEY_NAME: &str = "index";
+
+/// How long before it was read a file must have been modified last for its entry to be trusted,
+/// covering file systems with timestamps as coarse as two seconds
+const RACY_WINDOW: i64 = 2;
+
+/// Metadata telling whether a file changed since it was read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Stat {
+    size: u64,
+    modified: Timestamp,
+    changed: Timestamp,
+    inode: u64,
+}
+
+impl Stat {
+    fn of(metadata: &fs::Metadata) -> Self {
+        Self {
+            size: metadata.len(),
+            modified: FileTime::from_last_modification_time(metadata).into(),
+            changed: Timestamp {
+                seconds: metadata.ctime(),
+                nanos: metadata.ctime_nsec() as u32,
+            },
+            inode: metadata.ino(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct IndexEntry {
+    stat: Stat,
+    /// When the file was read, which must be well after its modification time
+    read: Timestamp,
+    /// SHA-256 of the contents
+    pub hash: [u8; 32],
+    /// Manifest the contents were last stored under, if they were
+    pub manifest: Option<FileManifest>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredEntry {
+    #[serde(with = "crate::path")]
+    path: PathBuf,
+    entry: IndexEntry,
+}
+
+/// Index of one folder, with paths relative to the folder.
+pub(crate) struct FileIndex {
+    path: PathBuf,
+    prk: Zeroing<[u8; 32]>,
+    entries: HashMap<PathBuf, IndexEntry>,
+}
+
+impl FileIndex {
+    /// Loads the index of the folder `root` on this host, or starts an empty one.
+    pub fn open(vault: &Vault, root: &Path) -> Result<Self> {
+        let root = fs::canonicalize(root)?;
+        let mut id = Sha256::new();
+        id.update(crate::lock::host()?);
+        id.update([0]);
+        id.update(root.as_os_str().as_encoded_bytes());
+        let mut index = Self {
+            path: vault.index_dir().join(crate::store::hex(&id.finalize())),
+            prk: vault.derive_prk(INDEX_KEY_NAME)?,
+            entries: HashMap::new(),
+        };
+
+        let data = match fs::read(&index.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(index),
+            Err(e) => return Err(e.into()),
+        };
+        let stored = EncryptedChunk::parse(&data)
+            .and_then(|encrypted| aead::decrypt_blob(&index.prk, &encrypted))
+            .and_then(|plaintext| Ok(bincode::deserialize::<Vec<StoredEntry>>(&plaintext)?));
+        // A damaged index only costs reading every file again
+        if let Ok(stored) = stored {
+            index.entries = stored
+                .into_iter()
+                .map(|stored| (stored.path, stored.entry))
+                .collect();
+        }
+        Ok(index)
+    }
+
+    /// The entry of the file at `path`, if `metadata` shows it unchanged since it was recorded.
+    pub fn get(&self, path: &Path, metadata: &fs::Metadata) -> Option<&IndexEntry> {
+        self.entries.get(path).filter(|entry| {
+            entry.stat == Stat::of(metadata)
+                && entry.stat.modified.seconds + RACY_WINDOW <= entry.read.seconds
+        })
+    }
+
+    /// Records the contents of the file at `path` as read at `read`, when its metadata was
+    /// `metadata`. A manifest recorded for the same contents before is kept if `manifest` is `None`.
+    pub fn insert(
+        &mut self,
+        path: &Path,
+        metadata: &fs::Metadata,
+        read: Timestamp,
+        hash: [u8; 32],
+        manifest: Option<FileManifest>,
+    ) {
+        let manifest = manifest.or_else(|| {
+            let previous = self.entries.get(path)?;
+            previous.manifest.clone().filter(|_| previous.hash == hash)
+        });
+        let entry = IndexEntry {
+            stat: Stat::of(metadata),
+            read,
+            hash,
+            manifest,
+        };
+        self.entries.insert(path.to_owned(), entry);
+    }
+
+    /// Hash of the contents of the file at `path` below `root`, from the index if the file is
+    /// unchanged and read and recorded otherwise.
+    pub fn hash(&mut self, root: &Path, path: &Path) -> Result<[u8; 32]> {
+        let full_path = root.join(path);
+        let metadata = fs::symlink_metadata(&full_path)?;
+        if let Some(entry) = self.get(path, &metadata) {
+            return Ok(entry.hash);
+        }
+        let read = Timestamp::now();
+        let mut hasher = Sha256::new();
+        io::copy(&mut fs::File::open(&full_path)?, &mut hasher)?;
+        let hash = hasher.finalize().into();
+        self.insert(path, &metadata, read, hash, None);
+        Ok(hash)
+    }
+
+    /// Forgets every file `keep` returns false for, such as files deleted since.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Path) -> bool) {
+        self.entries.retain(|path, _| keep(path));
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut stored: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(path, entry)| StoredEntry {
+                path: path.clone(),
+                entry: entry.clone(),
+            })
+            .collect();
+        stored.sort_by(|a, b| a.path.cmp(&b.path));
+        let encrypted = aead::encrypt_blob(&self.prk, &bincode::serialize(&stored)?)?;
+        fs::create_dir_all(self.path.parent().unwrap_or(Path::new("")))?;
+        crate::vault::write_atomic(&self.path, &encrypted.to_bytes())
+    }
+}
+
+/// Reader hashing everything read through it, for recording a file while storing it.
+pub(crate) struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: io::Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: io::Read> io::Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::VaultOptions;
+
+    fn setup(dir: &tempfile::TempDir) -> (Vault, PathBuf) {
+        let vault = Vault::init(
+            &dir.path().join("vault"),
+            "password",
+            &VaultOptions::default(),
+        )
+        .unwrap();
+        let root = dir.path().join("folder");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("file"), b"contents").unwrap();
+        (vault, root)
+    }
+
+    /// Moves the modification time of `path` out of the racy window
+    fn age(path: &Path) {
+        let past = FileTime::from_unix_time(FileTime::now().unix_seconds() - 60, 0);
+        filetime::set_file_mtime(path, past).unwrap();
+    }
+
+    #[test]
+    fn trusts_unchanged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, root) = setup(&dir);
+        age(&root.join("file"));
+        let mut index = FileIndex::open(&vault, &root).unwrap();
+        let hash = index.hash(&root, Path::new("file")).unwrap();
+        assert_eq!(hash, <[u8; 32]>::from(Sha256::digest(b"contents")));
+        index.save().unwrap();
+
+        // Contents changed behind the index's back are not noticed while the metadata matches,
+        // which shows the file is not read again
+        let metadata = fs::metadata(root.join("file")).unwrap();
+        let mut index = FileIndex::open(&vault, &root).unwrap();
+        assert!(index.get(Path::new("file"), &metadata).is_some());
+        index.entries.get_mut(Path::new("file")).unwrap().hash = [0; 32];
+        assert_eq!(index.hash(&root, Path::new("file")).unwrap(), [0; 32]);
+
+        // Any change to the metadata has it read again
+        fs::write(root.join("file"), b"new contents").unwrap();
+        age(&root.join("file"));
+        assert_eq!(
+            index.hash(&root, Path::new("file")).unwrap(),
+            <[u8; 32]>::from(Sha256::digest(b"new contents"))
+        );
+    }
+
+    #[test]
+    fn rereads_racily_modified_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, root) = setup(&dir);
+        let mut index = FileIndex::open(&vault, &root).unwrap();
+        index.hash(&root, Path::new("file")).unwrap();
+
+        // Modified so recently that a rewrite might not have changed the modification time
+        let metadata = fs::metadata(root.join("file")).unwrap();
+        assert_eq!(index.get(Path::new("file"), &metadata), None);
+    }
+
+    #[test]
+    fn keeps_manifests_of_unchanged_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, root) = setup(&dir);
+        let path = Path::new("file");
+        let metadata = fs::metadata(root.join(path)).unwrap();
+        let manifest =
+            crate::store::put_file(vault.store(), vault.data_prk(), &b"contents"[..]).unwrap();
+        let mut index = FileIndex::open(&vault, &root).unwrap();
+        index.insert(
+            path,
+            &metadata,
+            Timestamp::now(),
+            [1; 32],
+            Some(manifest.clone()),
+        );
+
+        index.insert(path, &metadata, Timestamp::now(), [1; 32], None);
+        assert_eq!(index.entries[path].manifest, Some(manifest));
+        index.insert(path, &metadata, Timestamp::now(), [2; 32], None);
+        assert_eq!(index.entries[path].manifest, None);
+
+        index.retain(|path| path != Path::new("file"));
+        index.save().unwrap();
+        assert!(FileIndex::open(&vault, &root).unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn starts_over_from_damaged_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, root) = setup(&dir);
+        let mut index = FileIndex::open(&vault, &root).unwrap();
+        index.hash(&root, Path::new("file")).unwrap();
+        index.save().unwrap();
+        assert_eq!(FileIndex::open(&vault, &root).unwrap().entries.len(), 1);
+
+        fs::write(&index.path, b"damaged").unwrap();
+        assert!(FileIndex::open(&vault, &root).unwrap().entries.is_empty());
+    }
+}
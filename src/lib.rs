@@ -1,9 +1,38 @@
+mod annex;
+mod archive;
+#[cfg(feature = "async")]
+mod async_buf_reader;
+mod backup;
 mod buf_reader;
+mod cli;
+mod container;
 mod crypto;
+mod decrypting_reader;
+mod encrypting_writer;
 mod error;
 mod file;
+mod gc;
+mod index;
+mod lock;
+mod merge;
+mod metadata;
+mod path;
+mod retention;
+mod snapshot;
+mod store;
+mod sync;
+mod tree;
+mod vault;
+mod version;
 mod zeroize_allocator;
 
+pub use annex::run_special_remote;
+#[cfg(feature = "async")]
+pub use async_buf_reader::{AsyncBufReader, EncryptingStream};
+pub use cli::run_command;
+pub use crypto::aead::{AesGcmKey, ChunkKey, EncryptedChunk, EncryptionType};
+pub use error::{Error, Result, SymmetricKeyError};
+
 #[global_allocator]
 static ALLOCATOR: zeroize_allocator::ZeroizeAllocator<std::alloc::System> =
     zeroize_allocator::ZeroizeAllocator::new(std::alloc::System);
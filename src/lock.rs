@@ -0,0 +1,226 @@
+//! Locks keeping garbage collection from deleting chunks that writers are about to reference.
+//!
+//! Every lock is a file in the vault's `locks` directory, so locks work across processes and
+//! devices sharing the vault. Writers take shared locks and garbage collection takes an exclusive
+//! lock. Both create their lock file before looking for conflicting locks, and back off if they
+//! find one, so at least one of two racing lockers always sees the other.
+//!
+//! A lock left behind by a process that died is stale once nothing on its host runs under its
+//! pid anymore. Stale locks are ignored and removed by whoever comes across them. Locks from other
+//! hosts, and locks whose process cannot be told to be gone, are never considered stale.
+
+use std::{fs, io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    error::{Error, Result},
+    metadata::Timestamp,
+    vault::Vault,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct LockInfo {
+    exclusive: bool,
+    host: String,
+    pid: u32,
+    time: Timestamp,
+}
+
+impl LockInfo {
+    fn is_stale(&self) -> bool {
+        host().is_ok_and(|host| host == self.host) && !is_running(self.pid)
+    }
+
+    fn describe(&self) -> String {
+        let kind = match self.exclusive {
+            true => "exclusive",
+            false => "shared",
+        };
+        format!("{kind} lock held by pid {} on {}", self.pid, self.host)
+    }
+}
+
+/// Whether a process runs under `pid` on this host, which is assumed when it cannot be told.
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return true;
+    };
+    // Signal 0 only checks whether the process exists, and pid 0 would address a process group
+    if pid == 0 || unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// Name of this host, which is also the default name of the device.
+pub(crate) fn host() -> io::Result<String> {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = name
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(name.len());
+    match String::from_utf8_lossy(&name[..len]).into_owned() {
+        host if host.is_empty() => Err(io::Error::other("the host has no name")),
+        host => Ok(host),
+    }
+}
+
+/// A lock on a vault, released when dropped.
+#[derive(Debug)]
+pub(crate) struct VaultLock {
+    path: PathBuf,
+}
+
+impl VaultLock {
+    /// Locks `vault` for writing, which any number of writers may do at once.
+    pub fn shared(vault: &Vault) -> Result<Self> {
+        Self::acquire(vault, false)
+    }
+
+    /// Locks `vault` for garbage collection, which excludes every other lock.
+    pub fn exclusive(vault: &Vault) -> Result<Self> {
+        Self::acquire(vault, true)
+    }
+
+    fn acquire(vault: &Vault, exclusive: bool) -> Result<Self> {
+        let dir = vault.locks_dir();
+        fs::create_dir_all(&dir)?;
+        let info = LockInfo {
+            exclusive,
+            host: host()?,
+            pid: std::process::id(),
+            time: Timestamp::now(),
+        };
+        let id = Uuid::now_v7().to_string();
+        let lock = Self {
+            path: dir.join(&id),
+        };
+        crate::vault::write_atomic(&lock.path, &bincode::serialize(&info)?)?;
+
+        // Dropping the lock removes it again if there is a conflict
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name.to_str() == Some(&id) || Uuid::parse_str(&name.to_string_lossy()).is_err() {
+                continue;
+            }
+            let other: LockInfo = match fs::read(entry.path()) {
+                Ok(data) => bincode::deserialize(&data)?,
+                // Released in the meantime
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if other.is_stale() {
+                let _ = fs::remove_file(entry.path());
+            } else if exclusive || other.exclusive {
+                return Err(Error::Locked(other.describe()));
+            }
+        }
+        Ok(lock)
+    }
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::VaultOptions;
+
+    fn vault(dir: &tempfile::TempDir) -> Vault {
+        Vault::init(
+            &dir.path().join("vault"),
+            "password",
+            &VaultOptions::default(),
+        )
+        .unwrap()
+    }
+
+    fn lock_count(vault: &Vault) -> usize {
+        fs::read_dir(vault.locks_dir()).unwrap().count()
+    }
+
+    #[test]
+    fn shares_shared_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = vault(&dir);
+        let first = VaultLock::shared(&vault).unwrap();
+        let second = VaultLock::shared(&vault).unwrap();
+        assert_eq!(lock_count(&vault), 2);
+
+        assert!(matches!(
+            VaultLock::exclusive(&vault),
+            Err(Error::Locked(_))
+        ));
+        assert_eq!(lock_count(&vault), 2);
+        drop((first, second));
+        assert_eq!(lock_count(&vault), 0);
+        VaultLock::exclusive(&vault).unwrap();
+    }
+
+    #[test]
+    fn excludes_other_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = vault(&dir);
+        let exclusive = VaultLock::exclusive(&vault).unwrap();
+        assert!(matches!(VaultLock::shared(&vault), Err(Error::Locked(_))));
+        assert!(matches!(
+            VaultLock::exclusive(&vault),
+            Err(Error::Locked(_))
+        ));
+        drop(exclusive);
+        VaultLock::shared(&vault).unwrap();
+    }
+
+    #[test]
+    fn ignores_stale_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = vault(&dir);
+        let write_lock = |pid, host: &str| {
+            let info = LockInfo {
+                exclusive: true,
+                host: host.to_owned(),
+                pid,
+                time: Timestamp::now(),
+            };
+            let path = vault.locks_dir().join(Uuid::now_v7().to_string());
+            fs::write(&path, bincode::serialize(&info).unwrap()).unwrap();
+            path
+        };
+
+        // No process runs under the largest pid Linux allows
+        let stale = write_lock(4_194_304, &host().unwrap());
+        VaultLock::exclusive(&vault).unwrap();
+        assert!(!stale.exists());
+
+        let remote = write_lock(4_194_304, "elsewhere");
+        assert!(matches!(
+            VaultLock::shared(&vault),
+            Err(Error::Locked(message)) if message.contains("elsewhere")
+        ));
+        fs::remove_file(remote).unwrap();
+
+        // Processes of other users cannot be signalled but still count as running
+        for pid in [std::process::id(), 1] {
+            let running = write_lock(pid, &host().unwrap());
+            assert!(matches!(VaultLock::shared(&vault), Err(Error::Locked(_))));
+            fs::remove_file(running).unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn names_this_host() {
+        let name = fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+        assert_eq!(host().unwrap(), name.trim());
+    }
+}
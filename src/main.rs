@@ -1,3 +1,24 @@
-fn main() {
-    println!("Hello, world!");
+use std::{path::Path, process::ExitCode};
+
+/// git-annex runs special remotes as `git-annex-remote-<type>`, so this binary serves as one when
+/// linked under that name.
+const ANNEX_PROGRAM: &str = "git-annex-remote-pigeonhole";
+
+fn main() -> ExitCode {
+    let args: Vec<_> = std::env::args_os().collect();
+    let program = args.first().map(Path::new).and_then(Path::file_name);
+    let annex = program.is_some_and(|program| program == ANNEX_PROGRAM)
+        || args.get(1).is_some_and(|arg| arg == "annex-remote");
+
+    let result = match annex {
+        true => pigeonhole::run_special_remote(),
+        false => pigeonhole::run_command(args.get(1..).unwrap_or_default()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("pigeonhole: {e}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,150 @@
+//! Three-way merging of text files, for resolving sync conflicts without conflict copies.
+//!
+//! Both versions are compared line by line with their common ancestor. Lines unchanged in all
+//! three split the files into regions, and each region is taken from whichever side changed it.
+//! A region both sides changed differently is an overlapping change, and the files do not merge.
+
+/// Largest file merged, as comparing files takes time and memory quadratic in their lengths
+pub(crate) const MAX_MERGE_SIZE: u64 = 1 << 20;
+
+/// Most pairs of differing lines compared between two versions
+const MAX_COMPARISONS: usize = 1 << 22;
+
+/// Whether `data` looks like text that can be merged line by line.
+pub(crate) fn is_text(data: &[u8]) -> bool {
+    data.len() as u64 <= MAX_MERGE_SIZE && !data.contains(&0)
+}
+
+/// Merges the changes `ours` and `theirs` made to `base`, or returns `None` if they overlap or any
+/// of them is not text.
+pub(crate) fn merge(base: &[u8], ours: &[u8], theirs: &[u8]) -> Option<Vec<u8>> {
+    if ![base, ours, theirs].into_iter().all(is_text) {
+        return None;
+    }
+    let (base, ours, theirs) = (lines(base), lines(ours), lines(theirs));
+    let to_ours = matching_lines(&base, &ours)?;
+    let to_theirs = matching_lines(&base, &theirs)?;
+
+    let mut merged = Vec::new();
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    loop {
+        // The next line unchanged on both sides, or the ends of the files
+        let stable = (i..base.len()).find_map(|x| Some((x, to_ours[x]?, to_theirs[x]?)));
+        let (x, y, z) = stable.unwrap_or((base.len(), ours.len(), theirs.len()));
+        let region = (&base[i..x], &ours[j..y], &theirs[k..z]);
+        let taken = match region {
+            (base, ours, theirs) if ours == base => theirs,
+            (base, ours, theirs) if theirs == base => ours,
+            (_, ours, theirs) if ours == theirs => ours,
+            _ => return None,
+        };
+        merged.extend(taken.iter().copied().flatten());
+        let Some((x, y, z)) = stable else {
+            return Some(merged);
+        };
+        merged.extend_from_slice(base[x]);
+        (i, j, k) = (x + 1, y + 1, z + 1);
+    }
+}
+
+/// Lines of `data` including their line feeds, so joining them gives `data` back.
+fn lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|&byte| byte == b'\n').collect()
+}
+
+/// For every line of `a`, the line of `b` it matches in a longest common subsequence, or `None` if
+/// comparing them would take too long.
+fn matching_lines(a: &[&[u8]], b: &[&[u8]]) -> Option<Vec<Option<usize>>> {
+    let prefix = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (middle_a, middle_b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let (n, m) = (middle_a.len(), middle_b.len());
+    if n.saturating_mul(m) > MAX_COMPARISONS {
+        return None;
+    }
+
+    // lengths[x * (m + 1) + y] is the length of the longest common subsequence of the lines
+    // from x and from y on
+    let mut lengths = vec![0u32; (n + 1) * (m + 1)];
+    for x in (0..n).rev() {
+        for y in (0..m).rev() {
+            lengths[x * (m + 1) + y] = match middle_a[x] == middle_b[y] {
+                true => lengths[(x + 1) * (m + 1) + y + 1] + 1,
+                false => lengths[(x + 1) * (m + 1) + y].max(lengths[x * (m + 1) + y + 1]),
+            };
+        }
+    }
+
+    let mut matches: Vec<_> = (0..prefix).map(Some).collect();
+    matches.resize(a.len(), None);
+    let (mut x, mut y) = (0, 0);
+    while x < n && y < m {
+        if middle_a[x] == middle_b[y] {
+            matches[prefix + x] = Some(prefix + y);
+            (x, y) = (x + 1, y + 1);
+        } else if lengths[(x + 1) * (m + 1) + y] >= lengths[x * (m + 1) + y + 1] {
+            x += 1;
+        } else {
+            y += 1;
+        }
+    }
+    for offset in 1..=suffix {
+        matches[a.len() - offset] = Some(b.len() - offset);
+    }
+    Some(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &[u8] = b"[server]\nhost = example.com\nport = 80\n\n[client]\nretries = 3\n";
+
+    #[test]
+    fn merges_separate_changes() {
+        let ours = b"[server]\nhost = example.org\nport = 80\n\n[client]\nretries = 3\n";
+        let theirs = b"# settings\n[server]\nhost = example.com\nport = 80\n\n[client]\nretries = 5\ntimeout = 10\n";
+        let merged = b"# settings\n[server]\nhost = example.org\nport = 80\n\n[client]\nretries = 5\ntimeout = 10\n";
+        assert_eq!(merge(BASE, ours, theirs).unwrap(), merged);
+        assert_eq!(merge(BASE, theirs, ours).unwrap(), merged);
+    }
+
+    #[test]
+    fn merges_deletions_and_identical_changes() {
+        let ours = b"[server]\nhost = example.com\n\n[client]\nretries = 3\n";
+        let theirs = b"[server]\nhost = example.com\nport = 80\n\n[client]\nretries = 3";
+        assert_eq!(
+            merge(BASE, ours, theirs).unwrap(),
+            b"[server]\nhost = example.com\n\n[client]\nretries = 3"
+        );
+
+        let both = b"[server]\nhost = example.org\nport = 80\n\n[client]\nretries = 3\n";
+        assert_eq!(merge(BASE, both, both).unwrap(), both);
+        assert_eq!(merge(BASE, BASE, BASE).unwrap(), BASE);
+        assert_eq!(merge(b"", b"ours\n", b"").unwrap(), b"ours\n");
+    }
+
+    #[test]
+    fn rejects_overlapping_changes() {
+        let ours = b"[server]\nhost = example.org\nport = 80\n\n[client]\nretries = 3\n";
+        let theirs = b"[server]\nhost = example.net\nport = 80\n\n[client]\nretries = 3\n";
+        assert_eq!(merge(BASE, ours, theirs), None);
+
+        // Adjacent changes overlap too, as nothing unchanged separates them
+        let theirs = b"[server]\nhost = example.com\nport = 8080\n\n[client]\nretries = 3\n";
+        assert_eq!(merge(BASE, ours, theirs), None);
+        assert_eq!(merge(b"", b"ours\n", b"theirs\n"), None);
+    }
+
+    #[test]
+    fn rejects_binary_files() {
+        let ours = b"[server]\0";
+        assert!(!is_text(ours));
+        assert_eq!(merge(BASE, ours, BASE), None);
+    }
+}
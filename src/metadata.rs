@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+/// Which file metadata to capture and re-apply.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MetadataPolicy {
+    /// Permission bits, including setuid, setgid and sticky bits
+    pub permissions: bool,
+    pub modified: bool,
+    pub accessed: bool,
+    pub ownership: Ownership,
+    pub xattrs: XattrPolicy,
+}
+
+impl Default for MetadataPolicy {
+    fn default() -> Self {
+        Self {
+            permissions: true,
+            modified: true,
+            accessed: false,
+            ownership: Ownership::Ignore,
+            xattrs: XattrPolicy::User,
+        }
+    }
+}
+
+impl MetadataPolicy {
+    /// Captures and applies nothing
+    pub fn none() -> Self {
+        Self {
+            permissions: false,
+            modified: false,
+            accessed: false,
+            ownership: Ownership::Ignore,
+            xattrs: XattrPolicy::Ignore,
+        }
+    }
+
+    /// Captures and applies everything, which takes root to restore
+    pub fn all() -> Self {
+        Self {
+            permissions: true,
+            modified: true,
+            accessed: true,
+            ownership: Ownership::Preserve,
+            xattrs: XattrPolicy::All,
+        }
+    }
+}
+
+/// How file owners are synced. Changing owners usually requires root.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Ownership {
+    Ignore,
+    /// Restores the recorded uid and gid
+    Preserve,
+    /// Restores the recorded uid and gid translated through these maps, keeping ids without a
+    /// mapping as recorded
+    Map {
+        uids: HashMap<u32, u32>,
+        gids: HashMap<u32, u32>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum XattrPolicy {
+    Ignore,
+    /// Only attributes in the `user.` namespace, which are the only ones unprivileged users can set
+    User,
+    All,
+}
+
+impl XattrPolicy {
+    fn includes(&self, name: &OsString) -> bool {
+        match self {
+            XattrPolicy::Ignore => false,
+            XattrPolicy::User => name.as_encoded_bytes().starts_with(b"user."),
+            XattrPolicy::All => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub(crate) struct Timestamp {
+    pub seconds: i64,
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        FileTime::now().into()
+    }
+}
+
+impl From<Timestamp> for FileTime {
+    fn from(value: Timestamp) -> Self {
+        FileTime::from_unix_time(value.seconds, value.nanos)
+    }
+}
+
+impl From<FileTime> for Timestamp {
+    fn from(value: FileTime) -> Self {
+        Self {
+            seconds: value.unix_seconds(),
+            nanos: value.nanoseconds(),
+        }
+    }
+}
+
+/// Metadata of a file, directory or symlink. Anything excluded by the [`MetadataPolicy`] it was
+/// captured with is `None`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Metadata {
+    pub permissions: Option<u32>,
+    pub modified: Option<Timestamp>,
+    pub accessed: Option<Timestamp>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub xattrs: Option<Vec<(OsString, Vec<u8>)>>,
+}
+
+impl Metadata {
+    /// Captures the metadata of `path` without following symlinks.
+    pub fn capture(path: &Path, policy: &MetadataPolicy) -> Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        let preserve_owner = policy.ownership != Ownership::Ignore;
+
+        Ok(Self {
+            permissions: policy
+                .permissions
+                .then(|| metadata.permissions().mode() & 0o7777),
+            modified: policy
+                .modified
+                .then(|| FileTime::from_last_modification_time(&metadata).into()),
+            accessed: policy
+                .accessed
+                .then(|| FileTime::from_last_access_time(&metadata).into()),
+            uid: preserve_owner.then(|| metadata.uid()),
+            gid: preserve_owner.then(|| metadata.gid()),
+            xattrs: match policy.xattrs {
+                XattrPolicy::Ignore => None,
+                xattr_policy => Some(read_xattrs(path, xattr_policy)?),
+            },
+        })
+    }
+
+    /// Applies the metadata allowed by `policy` to `path` without following symlinks.
+    ///
+    /// Permissions cannot be set on symlinks and are skipped for them. They are applied after the
+    /// xattrs, which could not be written to a file made read-only first. Modifying a directory's
+    /// contents updates its modification time, so directories should have their metadata applied
+    /// after their contents.
+    pub fn apply(&self, path: &Path, policy: &MetadataPolicy) -> Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+
+        if policy.ownership != Ownership::Ignore && (self.uid.is_some() || self.gid.is_some()) {
+            let (uid, gid) = match &policy.ownership {
+                Ownership::Map { uids, gids } => (
+                    self.uid.map(|uid| *uids.get(&uid).unwrap_or(&uid)),
+                    self.gid.map(|gid| *gids.get(&gid).unwrap_or(&gid)),
+                ),
+                _ => (self.uid, self.gid),
+            };
+            if uid.is_some_and(|uid| uid != metadata.uid())
+                || gid.is_some_and(|gid| gid != metadata.gid())
+            {
+                std::os::unix::fs::lchown(path, uid, gid)?;
+            }
+        }
+
+        if let Some(xattrs) = self
+            .xattrs
+            .as_ref()
+            .filter(|_| policy.xattrs != XattrPolicy::Ignore)
+        {
+            for (name, _) in read_xattrs(path, policy.xattrs)? {
+                if !xattrs.iter().any(|(recorded, _)| *recorded == name) {
+                    xattr::remove(path, &name)?;
+                }
+            }
+            for (name, value) in xattrs
+                .iter()
+                .filter(|(name, _)| policy.xattrs.includes(name))
+            {
+                xattr::set(path, name, value)?;
+            }
+        }
+
+        if let Some(mode) = self.permissions.filter(|_| policy.permissions) {
+            if !metadata.is_symlink() {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+            }
+        }
+
+        let modified = self.modified.filter(|_| policy.modified);
+        let accessed = self.accessed.filter(|_| policy.accessed);
+        if modified.is_some() || accessed.is_some() {
+            filetime::set_symlink_file_times(
+                path,
+                accessed.map_or_else(|| FileTime::from_last_access_time(&metadata), Into::into),
+                modified.map_or_else(
+                    || FileTime::from_last_modification_time(&metadata),
+                    Into::into,
+                ),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn read_xattrs(path: &Path, policy: XattrPolicy) -> Result<Vec<(OsString, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    for name in xattr::list(path)?.filter(|name| policy.includes(name)) {
+        if let Some(value) = xattr::get(path, &name)? {
+            xattrs.push((name, value));
+        }
+    }
+    xattrs.sort();
+    Ok(xattrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all() -> MetadataPolicy {
+        MetadataPolicy {
+            accessed: true,
+            ownership: Ownership::Preserve,
+            ..MetadataPolicy::default()
+        }
+    }
+
+    fn file(dir: &Path, name: &str) -> std::path::PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, name).unwrap();
+        path
+    }
+
+    #[test]
+    fn captures_only_what_policy_allows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = file(dir.path(), "file");
+
+        let metadata = Metadata::capture(&path, &MetadataPolicy::none()).unwrap();
+        assert_eq!(metadata, Metadata::default());
+
+        let metadata = Metadata::capture(&path, &all()).unwrap();
+        assert!(metadata.permissions.is_some());
+        assert!(metadata.modified.is_some());
+        assert!(metadata.accessed.is_some());
+        assert_eq!(metadata.uid, Some(fs::metadata(&path).unwrap().uid()));
+    }
+
+    #[test]
+    fn round_trips_permissions_and_times() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = file(dir.path(), "source");
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o751)).unwrap();
+        filetime::set_file_times(
+            &source,
+            FileTime::from_unix_time(1_000_000_000, 5),
+            FileTime::from_unix_time(1_200_000_000, 123_456_789),
+        )
+        .unwrap();
+        let metadata = Metadata::capture(&source, &all()).unwrap();
+
+        let target = file(dir.path(), "target");
+        metadata.apply(&target, &all()).unwrap();
+        assert_eq!(Metadata::capture(&target, &all()).unwrap(), metadata);
+        assert_eq!(
+            metadata.modified,
+            Some(Timestamp {
+                seconds: 1_200_000_000,
+                nanos: 123_456_789
+            })
+        );
+    }
+
+    #[test]
+    fn apply_respects_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = file(dir.path(), "source");
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o700)).unwrap();
+        let metadata = Metadata::capture(&source, &all()).unwrap();
+
+        let target = file(dir.path(), "target");
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o644)).unwrap();
+        let policy = MetadataPolicy {
+            permissions: false,
+            ..all()
+        };
+        metadata.apply(&target, &policy).unwrap();
+        assert_eq!(
+            fs::metadata(&target).unwrap().permissions().mode() & 0o7777,
+            0o644
+        );
+    }
+
+    #[test]
+    fn round_trips_user_xattrs() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = file(dir.path(), "source");
+        if xattr::set(&source, "user.pigeonhole", b"value").is_err() {
+            // Filesystem without user xattr support
+            return;
+        }
+        xattr::set(&source, "user.empty", b"").unwrap();
+        let metadata = Metadata::capture(&source, &all()).unwrap();
+
+        let target = file(dir.path(), "target");
+        xattr::set(&target, "user.stale", b"removed on apply").unwrap();
+        metadata.apply(&target, &all()).unwrap();
+
+        assert_eq!(
+            read_xattrs(&target, XattrPolicy::User).unwrap(),
+            [
+                (OsString::from("user.empty"), vec![]),
+                (OsString::from("user.pigeonhole"), b"value".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_xattrs_to_read_only_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = file(dir.path(), "source");
+        if xattr::set(&source, "user.pigeonhole", b"value").is_err() {
+            return;
+        }
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o444)).unwrap();
+        let metadata = Metadata::capture(&source, &all()).unwrap();
+
+        let target = file(dir.path(), "target");
+        metadata.apply(&target, &all()).unwrap();
+        assert_eq!(Metadata::capture(&target, &all()).unwrap(), metadata);
+    }
+
+    #[test]
+    fn applies_times_to_symlinks_without_following() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = file(dir.path(), "target");
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink("target", &link).unwrap();
+        let target_metadata = Metadata::capture(&target, &all()).unwrap();
+
+        let metadata = Metadata {
+            modified: Some(Timestamp {
+                seconds: 1_000_000_000,
+                nanos: 0,
+            }),
+            permissions: Some(0o600),
+            ..Metadata::default()
+        };
+        metadata.apply(&link, &all()).unwrap();
+
+        assert_eq!(
+            Metadata::capture(&link, &all()).unwrap().modified,
+            metadata.modified
+        );
+        assert_eq!(Metadata::capture(&target, &all()).unwrap(), target_metadata);
+    }
+
+    #[test]
+    fn maps_ownership() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = file(dir.path(), "file");
+        let current = fs::metadata(&path).unwrap();
+
+        let metadata = Metadata {
+            uid: Some(current.uid() + 1000),
+            gid: Some(current.gid() + 1000),
+            ..Metadata::default()
+        };
+        let policy = MetadataPolicy {
+            ownership: Ownership::Map {
+                uids: HashMap::from([(current.uid() + 1000, current.uid())]),
+                gids: HashMap::from([(current.gid() + 1000, current.gid())]),
+            },
+            ..MetadataPolicy::none()
+        };
+        metadata.apply(&path, &policy).unwrap();
+
+        let applied = fs::metadata(&path).unwrap();
+        assert_eq!(
+            (applied.uid(), applied.gid()),
+            (current.uid(), current.gid())
+        );
+    }
+}
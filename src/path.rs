@@ -0,0 +1,96 @@
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::{Error, Result};
+
+/// Serializes a path as an `OsString` for use with `#[serde(with = "crate::path")]`, so paths that
+/// are not valid UTF-8 survive a round trip.
+pub(crate) fn serialize<S: Serializer>(
+    path: &Path,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    path.as_os_str().serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<PathBuf, D::Error> {
+    OsString::deserialize(deserializer).map(PathBuf::from)
+}
+
+/// Checks that `path` is relative and cannot escape the directory it is joined to.
+pub(crate) fn check_relative(path: &Path) -> Result<()> {
+    let mut components = path.components().peekable();
+    if components.peek().is_none() || !components.all(|c| matches!(c, Component::Normal(_))) {
+        return Err(Error::UnsafePath(path.to_owned()));
+    }
+    Ok(())
+}
+
+/// Checks that no directory `path` is inside below `root` is a symlink, so what is written at
+/// `root.join(path)` stays below `root`. `path` must have passed [`check_relative`].
+pub(crate) fn check_no_symlinks(root: &Path, path: &Path) -> Result<()> {
+    let mut dir = root.to_owned();
+    for component in path.parent().into_iter().flat_map(Path::components) {
+        dir.push(component);
+        match fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_symlink() => {
+                return Err(Error::UnsafePath(path.to_owned()))
+            }
+            Ok(_) => {}
+            // Created as a directory once needed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Entries of a directory sorted by name, so walking the same tree always visits it in the same order.
+pub(crate) fn read_dir_sorted(dir: &Path) -> Result<Vec<fs::DirEntry>> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_relative_accepts_nested_paths() {
+        assert!(check_relative(Path::new("a")).is_ok());
+        assert!(check_relative(Path::new("a/b/c.txt")).is_ok());
+    }
+
+    #[test]
+    fn check_no_symlinks_rejects_paths_through_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("dir")).unwrap();
+        std::os::unix::fs::symlink("/etc", dir.path().join("dir/link")).unwrap();
+        for path in ["file", "dir/file", "dir/link", "missing/deeper/file"] {
+            assert!(
+                check_no_symlinks(dir.path(), Path::new(path)).is_ok(),
+                "{path}"
+            );
+        }
+        for path in ["dir/link/passwd", "dir/link/missing/file"] {
+            assert!(
+                check_no_symlinks(dir.path(), Path::new(path)).is_err(),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn check_relative_rejects_escaping_paths() {
+        for path in ["", "/etc/passwd", "../a", "a/../../b", "./a", "a/./b/.."] {
+            assert!(check_relative(Path::new(path)).is_err(), "{path}");
+        }
+    }
+}
@@ -0,0 +1,471 @@
+//! Retention policies choosing which snapshots to keep and which to forget.
+//!
+//! Policies are applied to the snapshots of every device separately, so a device that syncs often
+//! cannot push the snapshots of another out of the buckets. Calendar buckets are in UTC.
+//!
+//! The heads of the sync snapshots are never forgotten whatever the policy, since sync would take
+//! the files added since the snapshots before them for deleted.
+//!
+//! Forgetting a snapshot only removes the snapshot itself. The chunks only it referenced stay in
+//! the store until they are garbage collected.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    time::Duration,
+};
+
+use uuid::Uuid;
+
+use crate::{
+    error::{Error, Result},
+    metadata::Timestamp,
+    snapshot::Snapshot,
+    sync,
+    vault::Vault,
+};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct RetentionPolicy {
+    /// Keeps the newest snapshots
+    pub last: usize,
+    /// Keeps the newest snapshot of each of the most recent hours with snapshots
+    pub hourly: usize,
+    pub daily: usize,
+    /// Weeks start on Monday
+    pub weekly: usize,
+    pub monthly: usize,
+    /// Keeps every snapshot taken within this long before the newest snapshot
+    pub within: Option<Duration>,
+    /// Keeps every snapshot with any of these tags
+    pub pinned_tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum KeepReason {
+    Last,
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Within,
+    Pinned,
+    /// A head of the sync snapshots, kept regardless of the policy
+    SyncHead,
+}
+
+/// Snapshots a policy keeps, with every reason to keep them, and snapshots it forgets. Both are
+/// ordered oldest first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Retention {
+    pub keep: Vec<(Snapshot, Vec<KeepReason>)>,
+    pub forget: Vec<Snapshot>,
+}
+
+impl RetentionPolicy {
+    fn is_empty(&self) -> bool {
+        self.last == 0
+            && self.hourly == 0
+            && self.daily == 0
+            && self.weekly == 0
+            && self.monthly == 0
+            && self.within.is_none()
+            && self.pinned_tags.is_empty()
+    }
+
+    /// Sorts `snapshots` into the ones to keep and the ones to forget.
+    ///
+    /// Fails for a policy that keeps nothing, which is far more likely a mistake than a wish to
+    /// forget every snapshot.
+    pub fn apply(&self, snapshots: Vec<Snapshot>) -> Result<Retention> {
+        if self.is_empty() {
+            return Err(Error::EmptyRetentionPolicy);
+        }
+        let sync_heads: HashSet<Uuid> = sync::sync_heads(snapshots.clone())
+            .iter()
+            .map(|snapshot| snapshot.id)
+            .collect();
+        let mut by_device: BTreeMap<String, Vec<Snapshot>> = BTreeMap::new();
+        for snapshot in snapshots {
+            by_device
+                .entry(snapshot.device.clone())
+                .or_default()
+                .push(snapshot);
+        }
+
+        let mut retention = Retention::default();
+        for (_, mut snapshots) in by_device {
+            // Newest first, as buckets are filled from the newest snapshot back
+            snapshots.sort_by_key(|snapshot| std::cmp::Reverse((snapshot.time, snapshot.id)));
+            let mut reasons = vec![Vec::new(); snapshots.len()];
+
+            for reason in reasons.iter_mut().take(self.last) {
+                reason.push(KeepReason::Last);
+            }
+            let buckets = [
+                (self.hourly, KeepReason::Hourly),
+                (self.daily, KeepReason::Daily),
+                (self.weekly, KeepReason::Weekly),
+                (self.monthly, KeepReason::Monthly),
+            ];
+            for (count, reason) in buckets {
+                let mut last_bucket = None;
+                let mut kept = 0;
+                for (snapshot, reasons) in snapshots.iter().zip(&mut reasons) {
+                    if kept == count {
+                        break;
+                    }
+                    let bucket = bucket(reason, snapshot.time);
+                    if last_bucket != Some(bucket) {
+                        last_bucket = Some(bucket);
+                        reasons.push(reason);
+                        kept += 1;
+                    }
+                }
+            }
+            if let (Some(within), Some(newest)) = (self.within, snapshots.first()) {
+                let since = newest.time.seconds.saturating_sub(within.as_secs() as i64);
+                for (snapshot, reasons) in snapshots.iter().zip(&mut reasons) {
+                    if snapshot.time.seconds >= since {
+                        reasons.push(KeepReason::Within);
+                    }
+                }
+            }
+            for (snapshot, reasons) in snapshots.iter().zip(&mut reasons) {
+                if snapshot
+                    .tags
+                    .iter()
+                    .any(|tag| self.pinned_tags.contains(tag))
+                {
+                    reasons.push(KeepReason::Pinned);
+                }
+                if sync_heads.contains(&snapshot.id) {
+                    reasons.push(KeepReason::SyncHead);
+                }
+            }
+
+            for (snapshot, reasons) in snapshots.into_iter().zip(reasons) {
+                match reasons.is_empty() {
+                    true => retention.forget.push(snapshot),
+                    false => retention.keep.push((snapshot, reasons)),
+                }
+            }
+        }
+
+        retention
+            .keep
+            .sort_by_key(|(snapshot, _)| (snapshot.time, snapshot.id));
+        retention
+            .forget
+            .sort_by_key(|snapshot| (snapshot.time, snapshot.id));
+        Ok(retention)
+    }
+}
+
+impl KeepReason {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Last => "last",
+            Self::Hourly => "hourly",
+            Self::Daily => "daily",
+            Self::Weekly => "weekly",
+            Self::Monthly => "monthly",
+            Self::Within => "within",
+            Self::Pinned => "pinned",
+            Self::SyncHead => "sync head",
+        }
+    }
+}
+
+impl Retention {
+    /// One line per snapshot saying whether it is kept and why, oldest first.
+    pub fn report(&self) -> String {
+        let mut lines: Vec<_> = self
+            .keep
+            .iter()
+            .map(|(snapshot, reasons)| (snapshot, Some(reasons)))
+            .chain(self.forget.iter().map(|snapshot| (snapshot, None)))
+            .collect();
+        lines.sort_by_key(|(snapshot, _)| (snapshot.time, snapshot.id));
+
+        let mut report = String::new();
+        for (snapshot, reasons) in lines {
+            let action = match reasons {
+                Some(_) => "keep  ",
+                None => "forget",
+            };
+            let _ = write!(
+                report,
+                "{action} {} {} {}",
+                snapshot.id,
+                format_time(snapshot.time),
+                snapshot.device
+            );
+            if let Some(reasons) = reasons {
+                let reasons: Vec<_> = reasons.iter().map(|reason| reason.name()).collect();
+                let _ = write!(report, " ({})", reasons.join(", "));
+            }
+            report.push('\n');
+        }
+        report
+    }
+}
+
+/// Applies `policy` to the snapshots in `vault`, forgetting the snapshots it does not keep unless
+/// `dry_run` is set.
+pub(crate) fn prune(vault: &Vault, policy: &RetentionPolicy, dry_run: bool) -> Result<Retention> {
+    let retention = policy.apply(Snapshot::list(vault)?)?;
+    if !dry_run {
+        for snapshot in &retention.forget {
+            Snapshot::forget(vault, &snapshot.id)?;
+        }
+    }
+    Ok(retention)
+}
+
+pub(crate) fn days(time: Timestamp) -> i64 {
+    time.seconds.div_euclid(86400)
+}
+
+/// Calendar bucket of `time` for a bucket based reason, numbered in time order.
+fn bucket(reason: KeepReason, time: Timestamp) -> i64 {
+    match reason {
+        KeepReason::Hourly => time.seconds.div_euclid(3600),
+        KeepReason::Daily => days(time),
+        // 1970-01-01 was a Thursday
+        KeepReason::Weekly => (days(time) + 3).div_euclid(7),
+        KeepReason::Monthly => {
+            let (year, month, _) = civil_date(days(time));
+            year * 12 + month as i64
+        }
+        _ => unreachable!("{reason:?} is not based on buckets"),
+    }
+}
+
+/// Year, month and day of the days since 1970-01-01 in the proleptic Gregorian calendar.
+pub(crate) fn civil_date(days: i64) -> (i64, u32, u32) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn format_time(time: Timestamp) -> String {
+    let (year, month, day) = civil_date(days(time));
+    let seconds = time.seconds.rem_euclid(86400);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        metadata::MetadataPolicy, snapshot::SnapshotOptions, sync::SYNC_TAG, vault::VaultOptions,
+    };
+
+    const HOUR: i64 = 3600;
+    const DAY: i64 = 24 * HOUR;
+    /// 2024-01-01 00:00:00 UTC, a Monday
+    const START: i64 = 1_704_067_200;
+
+    fn snapshot(seconds: i64, device: &str, tags: &[&str]) -> Snapshot {
+        Snapshot {
+            id: Uuid::now_v7(),
+            time: Timestamp { seconds, nanos: 0 },
+            device: device.to_owned(),
+            parents: vec![],
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            tree: [0; 32],
+        }
+    }
+
+    fn kept_times(retention: &Retention) -> Vec<i64> {
+        retention
+            .keep
+            .iter()
+            .map(|(snapshot, _)| (snapshot.time.seconds - START) / HOUR)
+            .collect()
+    }
+
+    /// Hourly snapshots over `days` days.
+    fn hourly(days: i64) -> Vec<Snapshot> {
+        (0..days * 24)
+            .map(|hour| snapshot(START + hour * HOUR, "laptop", &[]))
+            .collect()
+    }
+
+    #[test]
+    fn computes_calendar_dates() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(START / DAY), (2024, 1, 1));
+        assert_eq!(civil_date(START / DAY + 59), (2024, 2, 29));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+        assert_eq!(
+            format_time(Timestamp {
+                seconds: START + 13 * HOUR + 61,
+                nanos: 0
+            }),
+            "2024-01-01 13:01:01 UTC"
+        );
+    }
+
+    #[test]
+    fn keeps_last_and_hourly() {
+        let policy = RetentionPolicy {
+            last: 2,
+            hourly: 3,
+            ..RetentionPolicy::default()
+        };
+        let retention = policy.apply(hourly(1)).unwrap();
+        assert_eq!(kept_times(&retention), [21, 22, 23]);
+        assert_eq!(retention.forget.len(), 21);
+        assert_eq!(retention.keep[2].1, [KeepReason::Last, KeepReason::Hourly]);
+    }
+
+    #[test]
+    fn keeps_newest_snapshot_of_each_bucket() {
+        let policy = RetentionPolicy {
+            daily: 3,
+            weekly: 2,
+            monthly: 2,
+            ..RetentionPolicy::default()
+        };
+        let retention = policy.apply(hourly(40)).unwrap();
+        // The last snapshot of the last three days, of the last two weeks and of the last two
+        // months, the 40th day being Friday 2024-02-09
+        let day = |day: i64| day * 24 + 23;
+        assert_eq!(
+            kept_times(&retention),
+            [day(30), day(34), day(37), day(38), day(39)]
+        );
+        let reasons: Vec<_> = retention.keep.iter().map(|(_, reasons)| reasons).collect();
+        assert_eq!(reasons[0], &[KeepReason::Monthly]);
+        assert_eq!(reasons[1], &[KeepReason::Weekly]);
+        assert_eq!(
+            reasons[4],
+            &[KeepReason::Daily, KeepReason::Weekly, KeepReason::Monthly]
+        );
+    }
+
+    #[test]
+    fn keeps_recent_and_pinned_snapshots() {
+        let mut snapshots = hourly(2);
+        snapshots[3].tags.push("release".to_owned());
+        let policy = RetentionPolicy {
+            within: Some(Duration::from_secs(5 * 3600)),
+            pinned_tags: vec!["release".to_owned()],
+            ..RetentionPolicy::default()
+        };
+        let retention = policy.apply(snapshots).unwrap();
+        assert_eq!(kept_times(&retention), [3, 42, 43, 44, 45, 46, 47]);
+        assert_eq!(retention.keep[0].1, [KeepReason::Pinned]);
+    }
+
+    #[test]
+    fn applies_policy_per_device() {
+        let snapshots = vec![
+            snapshot(START, "desktop", &[]),
+            snapshot(START + HOUR, "laptop", &[]),
+            snapshot(START + 2 * HOUR, "laptop", &[]),
+        ];
+        let policy = RetentionPolicy {
+            last: 1,
+            ..RetentionPolicy::default()
+        };
+        let retention = policy.apply(snapshots).unwrap();
+        assert_eq!(kept_times(&retention), [0, 2]);
+        assert_eq!(retention.forget[0].device, "laptop");
+    }
+
+    #[test]
+    fn keeps_sync_heads() {
+        let mut snapshots = vec![
+            snapshot(START, "laptop", &[SYNC_TAG]),
+            snapshot(START + HOUR, "laptop", &["backup"]),
+            snapshot(START + 2 * HOUR, "desktop", &[SYNC_TAG]),
+            snapshot(START + 3 * HOUR, "laptop", &[SYNC_TAG]),
+        ];
+        // The desktop's snapshot follows the laptop's first, while the laptop's last was
+        // committed concurrently and is a head too
+        let first = snapshots[0].id;
+        snapshots[2].parents.push(first);
+        snapshots[3].parents.push(first);
+        let policy = RetentionPolicy {
+            pinned_tags: vec!["backup".to_owned()],
+            ..RetentionPolicy::default()
+        };
+        let retention = policy.apply(snapshots).unwrap();
+        assert_eq!(kept_times(&retention), [1, 2, 3]);
+        assert_eq!(retention.keep[1].1, [KeepReason::SyncHead]);
+        assert_eq!(retention.forget[0].time.seconds, START);
+        assert!(retention
+            .report()
+            .lines()
+            .last()
+            .unwrap()
+            .ends_with("(sync head)"));
+    }
+
+    #[test]
+    fn refuses_empty_policy() {
+        assert!(matches!(
+            RetentionPolicy::default().apply(hourly(1)),
+            Err(Error::EmptyRetentionPolicy)
+        ));
+    }
+
+    #[test]
+    fn prunes_only_outside_dry_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::init(
+            &dir.path().join("vault"),
+            "password",
+            &VaultOptions::default(),
+        )
+        .unwrap();
+        let root = dir.path().join("tree");
+        std::fs::create_dir(&root).unwrap();
+        for _ in 0..3 {
+            let options = SnapshotOptions {
+                device: "laptop".to_owned(),
+                ..SnapshotOptions::default()
+            };
+            Snapshot::take(&vault, &root, &MetadataPolicy::default(), options).unwrap();
+        }
+        let policy = RetentionPolicy {
+            last: 1,
+            ..RetentionPolicy::default()
+        };
+
+        let dry_run = prune(&vault, &policy, true).unwrap();
+        assert_eq!(dry_run.forget.len(), 2);
+        assert_eq!(Snapshot::list(&vault).unwrap().len(), 3);
+        let report = dry_run.report();
+        assert_eq!(report.lines().count(), 3);
+        assert!(report.lines().next().unwrap().starts_with("forget "));
+        assert!(report.lines().last().unwrap().ends_with("laptop (last)"));
+
+        let pruned = prune(&vault, &policy, false).unwrap();
+        assert_eq!(pruned, dry_run);
+        let remaining = Snapshot::list(&vault).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0], pruned.keep[0].0);
+    }
+}
@@ -0,0 +1,360 @@
+//! Immutable, signed snapshots of a directory tree, for browsing and restoring past states.
+//!
+//! A snapshot records when and where it was taken and the content id of its [`TreeManifest`],
+//! which is stored as an encrypted blob in the vault's chunk store and in turn references the
+//! [`FileManifest`] of every file. Snapshots are kept in the vault's `snapshots` directory, one
+//! file per snapshot named by its id, encrypted and then signed with keys derived from the
+//! vault's master key.
+//!
+//! [`FileManifest`]: crate::file::FileManifest
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    crypto::{
+        self,
+        aead::{self, EncryptedChunk},
+        asym::AsymmetricCryptoKey,
+    },
+    error::{Error, Result},
+    lock::VaultLock,
+    metadata::{MetadataPolicy, Timestamp},
+    store::{self, ContentId},
+    tree::TreeManifest,
+    vault::Vault,
+    zeroize_allocator::Zeroing,
+};
+
+const SNAPSHOT_KEY_NAME: &str = "snapshots";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    /// Time ordered, so sorting by id sorts by creation
+    pub id: Uuid,
+    pub time: Timestamp,
+    /// Name of the device that took the snapshot
+    pub device: String,
+    /// Snapshots this one was derived from, if any
+    pub parents: Vec<Uuid>,
+    pub tags: Vec<String>,
+    /// Content id of the encrypted [`TreeManifest`]
+    pub tree: ContentId,
+}
+
+/// Details of a snapshot chosen by whoever takes it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct SnapshotOptions {
+    pub device: String,
+    pub parents: Vec<Uuid>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedSnapshot {
+    /// [`EncryptedChunk`] holding the serialized [`Snapshot`]
+    encrypted: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+fn snapshot_prk(vault: &Vault) -> Result<Zeroing<[u8; 32]>> {
+    vault.derive_prk(SNAPSHOT_KEY_NAME)
+}
+
+fn snapshot_path(vault: &Vault, id: &Uuid) -> PathBuf {
+    vault.snapshots_dir().join(id.to_string())
+}
+
+impl Snapshot {
+    /// Stores every file below `root` and records the tree in a new snapshot.
+    #[cfg(test)]
+    pub fn take(
+        vault: &Vault,
+        root: &Path,
+        policy: &MetadataPolicy,
+        options: SnapshotOptions,
+    ) -> Result<Self> {
+        let _lock = VaultLock::shared(vault)?;
+        let tree = TreeManifest::build(root, policy, |path| {
+            store::put_file(vault.store(), vault.data_prk(), fs::File::open(path)?)
+        })?;
+        Self::commit(vault, &tree, options)
+    }
+
+    /// Records `tree`, whose files must already be stored, in a new snapshot.
+    ///
+    /// Callers storing the files themselves should hold a shared [`VaultLock`] from before
+    /// storing the first file until the snapshot is committed, so garbage collection does not
+    /// sweep the files in between.
+    pub fn commit(vault: &Vault, tree: &TreeManifest, options: SnapshotOptions) -> Result<Self> {
+        let _lock = VaultLock::shared(vault)?;
+        let prk = snapshot_prk(vault)?;
+        let tree = vault.store().put_chunk(&tree.encrypt(&prk)?)?;
+        vault.flush_store()?;
+        let snapshot = Self {
+            id: Uuid::now_v7(),
+            time: Timestamp::now(),
+            device: options.device,
+            parents: options.parents,
+            tags: options.tags,
+            tree,
+        };
+
+        let encrypted = aead::encrypt_blob(&prk, &bincode::serialize(&snapshot)?)?.to_bytes();
+        let signature = crypto::signing_key(&prk)?.sign(&encrypted)?;
+        let signed = bincode::serialize(&SignedSnapshot {
+            encrypted,
+            signature,
+        })?;
+
+        fs::create_dir_all(vault.snapshots_dir())?;
+        // Snapshots are never replaced, so a file in the way is left alone
+        let path = snapshot_path(vault, &snapshot.id);
+        let tmp = vault.snapshots_dir().join(format!(".tmp-{}", snapshot.id));
+        crate::vault::write_atomic(&tmp, &signed)?;
+        let result = fs::hard_link(&tmp, &path);
+        fs::remove_file(&tmp)?;
+        result?;
+        Ok(snapshot)
+    }
+
+    /// Loads and verifies the snapshot `id`.
+    pub fn load(vault: &Vault, id: &Uuid) -> Result<Self> {
+        let data = match fs::read(snapshot_path(vault, id)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::SnapshotNotFound(*id))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let signed: SignedSnapshot = bincode::deserialize(&data)?;
+        let prk = snapshot_prk(vault)?;
+        if !crypto::signing_key(&prk)?.verify(&signed.encrypted, &signed.signature)? {
+            return Err(Error::InvalidSnapshot("invalid signature"));
+        }
+
+        let encrypted = EncryptedChunk::parse(&signed.encrypted)?;
+        let snapshot: Self = bincode::deserialize(&aead::decrypt_blob(&prk, &encrypted)?)?;
+        if snapshot.id != *id {
+            return Err(Error::InvalidSnapshot("stored under another id"));
+        }
+        Ok(snapshot)
+    }
+
+    /// Deletes the snapshot `id`, leaving the chunks it references in the store.
+    pub fn forget(vault: &Vault, id: &Uuid) -> Result<()> {
+        match fs::remove_file(snapshot_path(vault, id)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::SnapshotNotFound(*id)),
+            result => Ok(result?),
+        }
+    }
+
+    /// All snapshots in the vault, oldest first.
+    pub fn list(vault: &Vault) -> Result<Vec<Self>> {
+        let mut snapshots = Vec::new();
+        let entries = match fs::read_dir(vault.snapshots_dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(snapshots),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let name = entry?.file_name();
+            if let Some(id) = name.to_str().and_then(|name| Uuid::parse_str(name).ok()) {
+                snapshots.push(Self::load(vault, &id)?);
+            }
+        }
+        snapshots.sort_by_key(|snapshot| (snapshot.time, snapshot.id));
+        Ok(snapshots)
+    }
+
+    /// The snapshots among `snapshots` that none of the others names as a parent, in the same
+    /// order. Unlike the newest snapshot, these do not depend on the clocks of the devices taking
+    /// the snapshots.
+    pub fn heads(snapshots: Vec<Self>) -> Vec<Self> {
+        let parents: HashSet<Uuid> = snapshots
+            .iter()
+            .flat_map(|snapshot| snapshot.parents.iter().copied())
+            .collect();
+        snapshots
+            .into_iter()
+            .filter(|snapshot| !parents.contains(&snapshot.id))
+            .collect()
+    }
+
+    /// The newest snapshot, if there is any.
+    #[cfg(test)]
+    pub fn latest(vault: &Vault) -> Result<Option<Self>> {
+        Ok(Self::list(vault)?.pop())
+    }
+
+    /// The tree recorded by the snapshot, for browsing it.
+    pub fn tree(&self, vault: &Vault) -> Result<TreeManifest> {
+        TreeManifest::decrypt(&snapshot_prk(vault)?, &vault.store().get_chunk(&self.tree)?)
+    }
+
+    /// Restores `path` from the snapshot into the directory `target`, keeping its name. An empty
+    /// `path` restores the whole tree into `target`.
+    pub fn restore(
+        &self,
+        vault: &Vault,
+        path: &Path,
+        target: &Path,
+        policy: &MetadataPolicy,
+    ) -> Result<()> {
+        let subtree = self
+            .tree(vault)?
+            .subtree(path)
+            .ok_or_else(|| Error::NotInSnapshot(path.to_owned()))?;
+        subtree.restore(target, policy, |manifest, file| {
+            let mut writer = io::BufWriter::new(file);
+            store::get_file(vault.store(), vault.data_prk(), manifest, &mut writer)?;
+            Ok(writer.flush()?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::VaultOptions;
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    fn vault(dir: &tempfile::TempDir) -> Vault {
+        Vault::init(
+            &dir.path().join("vault"),
+            "password",
+            &VaultOptions::default(),
+        )
+        .unwrap()
+    }
+
+    fn tree(dir: &tempfile::TempDir) -> PathBuf {
+        let root = dir.path().join("tree");
+        fs::create_dir_all(root.join("docs/notes")).unwrap();
+        fs::copy(PATH, root.join("docs/lorem_ipsum")).unwrap();
+        fs::write(root.join("docs/notes/todo"), b"first").unwrap();
+        fs::write(root.join("top"), b"top").unwrap();
+        root
+    }
+
+    fn options(device: &str) -> SnapshotOptions {
+        SnapshotOptions {
+            device: device.to_owned(),
+            ..SnapshotOptions::default()
+        }
+    }
+
+    #[test]
+    fn restores_files_and_folders_from_any_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = vault(&dir);
+        let root = tree(&dir);
+        let policy = MetadataPolicy::default();
+
+        let first = Snapshot::take(&vault, &root, &policy, options("laptop")).unwrap();
+        fs::write(root.join("docs/notes/todo"), b"second").unwrap();
+        fs::remove_file(root.join("top")).unwrap();
+        let second = Snapshot::take(&vault, &root, &policy, options("laptop")).unwrap();
+
+        let target = dir.path().join("restored");
+        first
+            .restore(&vault, Path::new("docs/notes"), &target, &policy)
+            .unwrap();
+        assert_eq!(fs::read(target.join("notes/todo")).unwrap(), b"first");
+        first
+            .restore(&vault, Path::new("top"), &target, &policy)
+            .unwrap();
+        assert_eq!(fs::read(target.join("top")).unwrap(), b"top");
+
+        let target = dir.path().join("latest");
+        second
+            .restore(&vault, Path::new(""), &target, &policy)
+            .unwrap();
+        assert_eq!(fs::read(target.join("docs/notes/todo")).unwrap(), b"second");
+        assert_eq!(
+            fs::read(target.join("docs/lorem_ipsum")).unwrap(),
+            fs::read(PATH).unwrap()
+        );
+        assert!(!target.join("top").exists());
+
+        assert!(matches!(
+            second.restore(&vault, Path::new("top"), &target, &policy),
+            Err(Error::NotInSnapshot(_))
+        ));
+    }
+
+    #[test]
+    fn lists_and_browses_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = vault(&dir);
+        let root = tree(&dir);
+        assert!(Snapshot::list(&vault).unwrap().is_empty());
+        assert_eq!(Snapshot::latest(&vault).unwrap(), None);
+
+        let policy = MetadataPolicy::default();
+        let first = Snapshot::take(&vault, &root, &policy, options("laptop")).unwrap();
+        let second = Snapshot::take(
+            &vault,
+            &root,
+            &policy,
+            SnapshotOptions {
+                device: "desktop".to_owned(),
+                parents: vec![first.id],
+                tags: vec!["release".to_owned()],
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            Snapshot::list(&vault).unwrap(),
+            [first.clone(), second.clone()]
+        );
+        assert_eq!(Snapshot::latest(&vault).unwrap(), Some(second.clone()));
+        assert_eq!(Snapshot::load(&vault, &second.id).unwrap(), second);
+
+        let tree = second.tree(&vault).unwrap();
+        assert!(tree.get(Path::new("docs/notes/todo")).is_some());
+        assert_eq!(tree.entries().len(), 5);
+        assert_eq!(Snapshot::heads(Snapshot::list(&vault).unwrap()), [second]);
+    }
+
+    #[test]
+    fn rejects_tampered_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = vault(&dir);
+        let root = tree(&dir);
+        let policy = MetadataPolicy::default();
+        let first = Snapshot::take(&vault, &root, &policy, options("laptop")).unwrap();
+        let second = Snapshot::take(&vault, &root, &policy, options("laptop")).unwrap();
+
+        // A snapshot copied over another is detected by its id
+        let first_path = snapshot_path(&vault, &first.id);
+        let second_path = snapshot_path(&vault, &second.id);
+        fs::copy(&first_path, &second_path).unwrap();
+        assert!(matches!(
+            Snapshot::load(&vault, &second.id),
+            Err(Error::InvalidSnapshot(_))
+        ));
+
+        let mut data = fs::read(&first_path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&first_path, data).unwrap();
+        assert!(matches!(
+            Snapshot::load(&vault, &first.id),
+            Err(Error::InvalidSnapshot("invalid signature"))
+        ));
+        assert!(matches!(
+            Snapshot::load(&vault, &Uuid::now_v7()),
+            Err(Error::SnapshotNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,411 @@
+//! Chunk stores implemented by helper programs, in the spirit of git remote helpers.
+//!
+//! A store named `<name>` is served by a program called `pigeonhole-remote-<name>` found on the
+//! `PATH`. It is spawned once and then receives one request at a time on its standard input,
+//! answering each on its standard output before the next request is sent. Its standard error is
+//! passed through to the user.
+//!
+//! Requests and responses are lines terminated by `\n`, content ids are in lower case hex and
+//! chunk data follows the line announcing its length:
+//!
+//! ```text
+//! helper:  pigeonhole-remote 1            greeting sent once after starting
+//! request: put <id> <length>\n<data>      response: ok
+//! request: get <id>                       response: data <length>\n<data> | missing
+//! request: has <id>                       response: yes | no
+//! request: delete <id>                    response: ok
+//! request: list                           response: <id> lines, then an empty line
+//! ```
+//!
+//! Any request may instead be answered with `error <message>`. The helper should exit once its
+//! standard input is closed. [`serve`] implements the helper side on top of any [`ChunkStore`],
+//! and `pigeonhole serve-store <location>` serves any store [`super::open`] understands.
+
+use std::{
+    ffi::OsStr,
+    io::{BufRead, BufReader, Write},
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+};
+
+use super::{hex, parse_hex, ChunkStore, ContentId};
+use crate::error::{Error, Result};
+
+pub(crate) const PROGRAM_PREFIX: &str = "pigeonhole-remote-";
+const GREETING: &str = "pigeonhole-remote 1";
+/// Largest chunk data accepted from the other side, to avoid allocating for garbage lengths
+const MAX_LENGTH: usize = 1 << 30;
+
+struct Connection {
+    reader: Box<dyn BufRead + Send>,
+    writer: Box<dyn Write + Send>,
+    /// Set once a request failed halfway, after which responses can no longer be matched up
+    broken: bool,
+}
+
+impl Connection {
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            self.broken = true;
+            return Err(Error::Remote("helper closed its output".to_owned()));
+        }
+        if !line.ends_with('\n') {
+            self.broken = true;
+            return Err(Error::Remote("helper output ends mid line".to_owned()));
+        }
+        line.pop();
+        Ok(line)
+    }
+
+    /// Sends a request and reads the first line of the response, turning error responses into
+    /// [`Error::Remote`].
+    fn request(&mut self, line: &str, data: &[u8]) -> Result<String> {
+        self.writer.write_all(format!("{line}\n").as_bytes())?;
+        self.writer.write_all(data)?;
+        self.writer.flush()?;
+        let response = self.read_line()?;
+        match response.strip_prefix("error ") {
+            Some(message) => Err(Error::Remote(format!("helper failed to {line}: {message}"))),
+            None => Ok(response),
+        }
+    }
+}
+
+/// Chunk store served by a helper program speaking the protocol described in this module.
+pub(crate) struct ExternalStore {
+    connection: Mutex<Connection>,
+    child: Option<Child>,
+}
+
+impl ExternalStore {
+    /// Spawns `pigeonhole-remote-<name>` with `args`.
+    pub fn spawn(name: &str, args: &[&OsStr]) -> Result<Self> {
+        Self::spawn_program(format!("{PROGRAM_PREFIX}{name}").as_ref(), args)
+    }
+
+    pub fn spawn_program(program: &OsStr, args: &[&OsStr]) -> Result<Self> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                Error::Remote(format!("cannot start {}: {e}", program.to_string_lossy()))
+            })?;
+        let reader = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let writer = child.stdin.take().expect("stdin is piped");
+        let mut store = Self::connect(reader, writer)?;
+        store.child = Some(child);
+        Ok(store)
+    }
+
+    /// Talks to a helper over an existing pair of streams, waiting for its greeting.
+    pub fn connect(
+        reader: impl BufRead + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> Result<Self> {
+        let mut connection = Connection {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            broken: false,
+        };
+        let greeting = connection.read_line()?;
+        if greeting != GREETING {
+            return Err(Error::Remote(format!(
+                "helper does not speak {GREETING}, it sent {greeting:?}"
+            )));
+        }
+        Ok(Self {
+            connection: Mutex::new(connection),
+            child: None,
+        })
+    }
+
+    /// Runs `exchange` on the connection, refusing to continue after a failed exchange left
+    /// unread or unsent data behind.
+    fn exchange<T>(&self, exchange: impl FnOnce(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        if connection.broken {
+            return Err(Error::Remote("helper connection is broken".to_owned()));
+        }
+        let result = exchange(&mut connection);
+        if matches!(result, Err(Error::Io { .. })) {
+            connection.broken = true;
+        }
+        result
+    }
+}
+
+impl Drop for ExternalStore {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            // Closing standard input asks the helper to exit
+            let connection = self.connection.get_mut().unwrap_or_else(|e| e.into_inner());
+            connection.writer = Box::new(std::io::sink());
+            let _ = child.wait();
+        }
+    }
+}
+
+fn unexpected(request: &str, response: &str) -> Error {
+    Error::Remote(format!(
+        "unexpected helper response to {request}: {response:?}"
+    ))
+}
+
+/// Reads the data announced by a `<keyword> <length>` line.
+fn read_data(reader: &mut (impl BufRead + ?Sized), length: &str) -> Result<Option<Vec<u8>>> {
+    let Some(length) = length.parse().ok().filter(|length| *length <= MAX_LENGTH) else {
+        return Ok(None);
+    };
+    let mut data = vec![0; length];
+    reader.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+impl ChunkStore for ExternalStore {
+    fn put(&self, id: &ContentId, data: &[u8]) -> Result<()> {
+        let request = format!("put {} {}", hex(id), data.len());
+        self.exchange(
+            |connection| match connection.request(&request, data)?.as_str() {
+                "ok" => Ok(()),
+                response => Err(unexpected(&request, response)),
+            },
+        )
+    }
+
+    fn get(&self, id: &ContentId) -> Result<Vec<u8>> {
+        let request = format!("get {}", hex(id));
+        self.exchange(|connection| {
+            let response = connection.request(&request, &[])?;
+            if response == "missing" {
+                return Err(Error::ChunkNotFound(*id));
+            }
+            let length = response.strip_prefix("data ").unwrap_or_default();
+            match read_data(&mut connection.reader, length) {
+                Ok(Some(data)) => Ok(data),
+                Ok(None) => {
+                    connection.broken = true;
+                    Err(unexpected(&request, &response))
+                }
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    fn has(&self, id: &ContentId) -> Result<bool> {
+        let request = format!("has {}", hex(id));
+        self.exchange(
+            |connection| match connection.request(&request, &[])?.as_str() {
+                "yes" => Ok(true),
+                "no" => Ok(false),
+                response => Err(unexpected(&request, response)),
+            },
+        )
+    }
+
+    fn delete(&self, id: &ContentId) -> Result<()> {
+        let request = format!("delete {}", hex(id));
+        self.exchange(
+            |connection| match connection.request(&request, &[])?.as_str() {
+                "ok" => Ok(()),
+                response => Err(unexpected(&request, response)),
+            },
+        )
+    }
+
+    fn list(&self) -> Result<Vec<ContentId>> {
+        self.exchange(|connection| {
+            let mut ids = Vec::new();
+            let mut line = connection.request("list", &[])?;
+            while !line.is_empty() {
+                match parse_hex(&line) {
+                    Some(id) => ids.push(id),
+                    None => {
+                        connection.broken = true;
+                        return Err(unexpected("list", &line));
+                    }
+                }
+                line = connection.read_line()?;
+            }
+            Ok(ids)
+        })
+    }
+}
+
+/// Serves `store` to pigeonhole as a helper program would, until `reader` reaches its end.
+///
+/// Helpers written in Rust can call this with their standard input and output.
+pub(crate) fn serve(
+    store: &impl ChunkStore,
+    mut reader: impl BufRead,
+    mut writer: impl Write,
+) -> Result<()> {
+    writeln!(writer, "{GREETING}")?;
+    writer.flush()?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let mut words = line.trim_end_matches('\n').split(' ');
+        let command = words.next().unwrap_or_default();
+        let id = words.next().and_then(parse_hex);
+
+        let response = match (command, id) {
+            ("put", Some(id)) => match read_data(&mut reader, words.next().unwrap_or_default())? {
+                Some(data) => store.put(&id, &data).map(|()| b"ok\n".to_vec()),
+                // The data cannot be skipped without knowing its length
+                None => return Err(Error::Remote(format!("invalid request {line:?}"))),
+            },
+            ("get", Some(id)) => match store.get(&id) {
+                Ok(data) => Ok([format!("data {}\n", data.len()).into_bytes(), data].concat()),
+                Err(Error::ChunkNotFound(_)) => Ok(b"missing\n".to_vec()),
+                Err(e) => Err(e),
+            },
+            ("has", Some(id)) => store.has(&id).map(|has| match has {
+                true => b"yes\n".to_vec(),
+                false => b"no\n".to_vec(),
+            }),
+            ("delete", Some(id)) => store.delete(&id).map(|()| b"ok\n".to_vec()),
+            ("list", None) => store.list().map(|ids| {
+                let mut response = String::new();
+                for id in ids {
+                    response.push_str(&hex(&id));
+                    response.push('\n');
+                }
+                response.push('\n');
+                response.into_bytes()
+            }),
+            _ => Err(Error::Remote(format!(
+                "invalid request {:?}",
+                line.trim_end()
+            ))),
+        };
+
+        match response {
+            Ok(response) => writer.write_all(&response)?,
+            Err(e) => {
+                let message = match e {
+                    Error::Remote(message) => message,
+                    e => e.to_string(),
+                };
+                let message = message.replace('\n', " ");
+                writeln!(writer, "error {message}")?;
+            }
+        }
+        writer.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        os::unix::{fs::PermissionsExt, net::UnixStream},
+        sync::Arc,
+        thread,
+    };
+
+    use super::*;
+    use crate::store::{
+        memory::{Fault, MemoryStore, Operation},
+        tests::check_store,
+    };
+
+    /// Serves `store` on a helper thread, as a helper process would over its pipes.
+    fn connect(store: Arc<MemoryStore>) -> ExternalStore {
+        let (client, helper) = UnixStream::pair().unwrap();
+        let helper_writer = helper.try_clone().unwrap();
+        thread::spawn(move || serve(&*store, BufReader::new(helper), helper_writer));
+        ExternalStore::connect(BufReader::new(client.try_clone().unwrap()), client).unwrap()
+    }
+
+    #[test]
+    fn satisfies_store_contract() {
+        check_store(&connect(Arc::new(MemoryStore::new())));
+    }
+
+    #[test]
+    fn passes_on_helper_errors() {
+        let memory = Arc::new(MemoryStore::new());
+        let store = connect(memory.clone());
+        memory.inject(Operation::Put, Fault::Fail, Some(1));
+
+        let error = store.put(&[1; 32], b"chunk").unwrap_err();
+        assert!(matches!(error, Error::Remote(message) if message.starts_with("helper failed")));
+        // The connection is still usable afterwards
+        store.put(&[1; 32], b"chunk").unwrap();
+        assert_eq!(store.get(&[1; 32]).unwrap(), b"chunk");
+    }
+
+    #[test]
+    fn rejects_unknown_helpers() {
+        let reader = Cursor::new(b"git-remote 1\n".to_vec());
+        assert!(matches!(
+            ExternalStore::connect(reader, Vec::new()),
+            Err(Error::Remote(_))
+        ));
+    }
+
+    #[test]
+    fn gives_up_on_malformed_responses() {
+        let reader = Cursor::new(format!("{GREETING}\nmaybe\n").into_bytes());
+        let store = ExternalStore::connect(reader, Vec::new()).unwrap();
+        assert!(matches!(store.has(&[0; 32]), Err(Error::Remote(_))));
+        // The helper closed its output, which breaks the connection for good
+        assert!(matches!(store.has(&[0; 32]), Err(Error::Remote(m)) if m.contains("closed")));
+        assert!(matches!(store.has(&[0; 32]), Err(Error::Remote(m)) if m.contains("broken")));
+    }
+
+    #[test]
+    fn serve_rejects_invalid_requests() {
+        let mut output = Vec::new();
+        serve(
+            &MemoryStore::new(),
+            Cursor::new(b"has 12\nfrobnicate\n".to_vec()),
+            &mut output,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines[0], GREETING);
+        assert!(lines[1].starts_with("error invalid request"));
+        assert!(lines[2].starts_with("error invalid request"));
+    }
+
+    #[test]
+    fn spawns_helper_programs() {
+        let dir = tempfile::tempdir().unwrap();
+        let program = dir.path().join(format!("{PROGRAM_PREFIX}empty"));
+        std::fs::write(
+            &program,
+            "#!/bin/sh\n\
+             echo 'pigeonhole-remote 1'\n\
+             while read -r command id; do\n\
+                 case $command in\n\
+                     has) echo no ;;\n\
+                     list) echo ;;\n\
+                     *) echo \"error $command is not supported\" ;;\n\
+                 esac\n\
+             done\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let store = ExternalStore::spawn_program(program.as_os_str(), &[]).unwrap();
+        assert!(!store.has(&[0; 32]).unwrap());
+        assert!(store.list().unwrap().is_empty());
+        assert!(matches!(store.delete(&[0; 32]), Err(Error::Remote(_))));
+    }
+
+    #[test]
+    fn reports_missing_helpers() {
+        assert!(matches!(
+            ExternalStore::spawn("does-not-exist", &[]),
+            Err(Error::Remote(message)) if message.contains("pigeonhole-remote-does-not-exist")
+        ));
+    }
+}
@@ -0,0 +1,367 @@
+//! HTTP plumbing shared by the stores that talk to a web server.
+
+use std::{io::Read, thread, time::Duration};
+
+use ureq::OrAnyStatus;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RetryPolicy {
+    /// Attempts made before giving up, including the first
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for every retry after it
+    pub backoff: Duration,
+    /// Longest an attempt waits for the server to send or accept data before it fails, so a
+    /// server stalling mid-response cannot block forever
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            backoff: Duration::from_millis(250),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+pub(crate) struct Request<'a> {
+    pub method: &'a str,
+    /// Fully encoded URL, including any query
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: &'a [u8],
+}
+
+pub(crate) struct Response {
+    pub status: u16,
+    /// Header names are lower case
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Error for a response the store did not expect.
+    pub fn error(&self, request: &Request) -> Error {
+        let url = request.url.split('?').next().unwrap_or_default();
+        Error::Remote(format!(
+            "{} {url} returned {}: {}",
+            request.method,
+            self.status,
+            String::from_utf8_lossy(&self.body).trim()
+        ))
+    }
+}
+
+/// Client reusing connections across requests, retrying connection failures, server errors and
+/// throttling responses with exponential backoff.
+pub(crate) struct HttpClient {
+    agent: ureq::Agent,
+    retry: RetryPolicy,
+}
+
+impl HttpClient {
+    pub fn new(retry: RetryPolicy) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(30))
+                .timeout_read(retry.timeout)
+                .timeout_write(retry.timeout)
+                .build(),
+            retry,
+        }
+    }
+
+    /// Sends `request`, returning any response with a status that is not worth retrying, even if
+    /// it is an error status.
+    pub fn send(&self, request: &Request) -> Result<Response> {
+        let mut backoff = self.retry.backoff;
+        for attempt in 1..=self.retry.attempts.max(1) {
+            let last = attempt == self.retry.attempts.max(1);
+            let mut builder = self.agent.request(request.method, &request.url);
+            for (name, value) in &request.headers {
+                builder = builder.set(name, value);
+            }
+
+            let failure = match builder.send_bytes(request.body).or_any_status() {
+                Ok(response) if last || !is_retryable(response.status()) => {
+                    match read_response(response) {
+                        Ok(response) => return Ok(response),
+                        // Cut off or timed out in the middle of the body
+                        Err(e) => e.to_string(),
+                    }
+                }
+                Ok(response) => format!("returned {}", response.status()),
+                Err(e) => e.to_string(),
+            };
+            if last {
+                return Err(Error::Remote(format!(
+                    "{} {}: {failure}",
+                    request.method, request.url
+                )));
+            }
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+        unreachable!("the last attempt always returns")
+    }
+}
+
+fn is_retryable(status: u16) -> bool {
+    status == 429 || status >= 500
+}
+
+fn read_response(response: ureq::Response) -> Result<Response> {
+    let status = response.status();
+    let headers = response
+        .headers_names()
+        .into_iter()
+        .filter_map(|name| {
+            let value = response.header(&name)?.to_owned();
+            Some((name.to_ascii_lowercase(), value))
+        })
+        .collect();
+    let mut body = Vec::new();
+    response.into_reader().read_to_end(&mut body)?;
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// Percent encodes everything but unreserved characters, and `/` unless `encode_slash` is set.
+pub(crate) fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+pub(crate) fn uri_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Text content of every element named `tag` in `xml`, ignoring namespace prefixes.
+///
+/// This is only meant for the flat, well known responses of storage servers: elements with
+/// nested elements yield the text before the first child.
+pub(crate) fn xml_text(xml: &str, tag: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = rest.find('>').unwrap_or(rest.len());
+        let element = &rest[..end];
+        rest = &rest[(end + 1).min(rest.len())..];
+
+        let name = element
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        let local_name = name.rsplit(':').next().unwrap_or_default();
+        if local_name != tag || element.ends_with('/') {
+            continue;
+        }
+        let text = &rest[..rest.find('<').unwrap_or(rest.len())];
+        values.push(xml_unescape(text));
+    }
+    values
+}
+
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::store::test_server::{self, TestServer};
+
+    fn request(url: String) -> Request<'static> {
+        Request {
+            method: "GET",
+            url,
+            headers: vec![],
+            body: &[],
+        }
+    }
+
+    fn fast_retries() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn uri_encoding_round_trips() {
+        assert_eq!(uri_encode("a b/c~d+é", false), "a%20b/c~d%2B%C3%A9");
+        assert_eq!(uri_encode("a/b", true), "a%2Fb");
+        assert_eq!(uri_decode("a%20b/c~d%2B%C3%A9"), "a b/c~d+é");
+        assert_eq!(uri_decode("100%"), "100%");
+    }
+
+    #[test]
+    fn extracts_xml_text() {
+        let xml = r#"<?xml version="1.0"?>
+            <d:multistatus xmlns:d="DAV:">
+                <d:response><d:href>/a%20b</d:href><d:resourcetype/></d:response>
+                <D:response><D:href>/c&amp;d</D:href></D:response>
+                <Key attr="1">e</Key><Empty></Empty>
+            </d:multistatus>"#;
+        assert_eq!(xml_text(xml, "href"), ["/a%20b", "/c&d"]);
+        assert_eq!(xml_text(xml, "Key"), ["e"]);
+        assert_eq!(xml_text(xml, "Empty"), [""]);
+        assert!(xml_text(xml, "resourcetype").is_empty());
+        assert_eq!(xml_unescape(&xml_escape(r#"<"&">"#)), r#"<"&">"#);
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server_calls = calls.clone();
+        let server =
+            TestServer::start(move |_| match server_calls.fetch_add(1, Ordering::SeqCst) {
+                0 => test_server::Response::status(503),
+                1 => test_server::Response::status(429),
+                _ => test_server::Response::ok(b"done".to_vec()),
+            });
+
+        let response = HttpClient::new(fast_retries())
+            .send(&request(server.url("/")))
+            .unwrap();
+        assert_eq!(response.body, b"done");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn gives_up_after_last_attempt() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server_calls = calls.clone();
+        let server = TestServer::start(move |_| {
+            server_calls.fetch_add(1, Ordering::SeqCst);
+            test_server::Response::status(500)
+        });
+
+        let response = HttpClient::new(fast_retries())
+            .send(&request(server.url("/")))
+            .unwrap();
+        assert_eq!(response.status, 500);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn returns_client_errors_without_retrying() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server_calls = calls.clone();
+        let server = TestServer::start(move |_| {
+            server_calls.fetch_add(1, Ordering::SeqCst);
+            test_server::Response::status(404)
+        });
+
+        let response = HttpClient::new(fast_retries())
+            .send(&request(server.url("/")))
+            .unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn times_out_stalled_responses() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server_calls = calls.clone();
+        std::thread::spawn(move || {
+            // Connections are kept open, but never get more than the start of a body
+            let mut stalled = Vec::new();
+            for mut stream in listener.incoming().map_while(std::result::Result::ok) {
+                server_calls.fetch_add(1, Ordering::SeqCst);
+                let _ = std::io::Write::write_all(
+                    &mut stream,
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc",
+                );
+                stalled.push(stream);
+            }
+        });
+
+        let retry = RetryPolicy {
+            timeout: Duration::from_millis(100),
+            ..fast_retries()
+        };
+        assert!(matches!(
+            HttpClient::new(retry).send(&request(url)),
+            Err(Error::Remote(_))
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn retries_connection_failures() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+
+        assert!(matches!(
+            HttpClient::new(fast_retries()).send(&request(url)),
+            Err(Error::Remote(_))
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+use super::{hex, parse_hex, ChunkStore, ContentId};
+use crate::error::{Error, Result};
+
+/// Directory for partially written chunks, which never holds a valid fan-out name
+const TMP_DIR: &str = "tmp";
+
+/// Chunk store in a local directory, such as a USB drive or NAS mount.
+///
+/// Chunks are stored as `<root>/<first byte in hex>/<content id in hex>`, so no directory grows
+/// beyond 1/256th of the chunks. Chunks are written to a temporary file and renamed into place,
+/// so a chunk is either fully stored or absent even if writing is interrupted.
+pub(crate) struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    /// Opens the store at `root`, creating it if needed.
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(root.join(TMP_DIR))?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, id: &ContentId) -> PathBuf {
+        let hex = hex(id);
+        self.root.join(&hex[..2]).join(hex)
+    }
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+impl ChunkStore for LocalStore {
+    fn put(&self, id: &ContentId, data: &[u8]) -> Result<()> {
+        let path = self.path(id);
+        if path.exists() {
+            return Ok(());
+        }
+
+        let tmp = self.root.join(TMP_DIR).join(Uuid::now_v7().to_string());
+        let result = (|| {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+
+            let dir = path.parent().expect("chunk paths have a fan-out directory");
+            fs::create_dir_all(dir)?;
+            fs::rename(&tmp, &path)?;
+            sync_dir(dir)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        Ok(result?)
+    }
+
+    fn get(&self, id: &ContentId) -> Result<Vec<u8>> {
+        fs::read(self.path(id)).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => Error::ChunkNotFound(*id),
+            _ => e.into(),
+        })
+    }
+
+    fn has(&self, id: &ContentId) -> Result<bool> {
+        Ok(self.path(id).try_exists()?)
+    }
+
+    fn delete(&self, id: &ContentId) -> Result<()> {
+        match fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn size(&self, id: &ContentId) -> Result<u64> {
+        match fs::metadata(self.path(id)) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::ChunkNotFound(*id)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<ContentId>> {
+        let mut ids = Vec::new();
+        for dir in fs::read_dir(&self.root)? {
+            let dir = dir?;
+            let name = dir.file_name();
+            if name.len() != 2 || !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for chunk in fs::read_dir(dir.path())? {
+                if let Some(id) = chunk?.file_name().to_str().and_then(parse_hex) {
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::check_store;
+
+    #[test]
+    fn satisfies_store_contract() {
+        let dir = tempfile::tempdir().unwrap();
+        check_store(&LocalStore::new(dir.path().join("store")).unwrap());
+    }
+
+    #[test]
+    fn stores_chunks_in_fan_out_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).unwrap();
+        let id = [0xab; 32];
+        store.put(&id, b"data").unwrap();
+
+        assert_eq!(
+            fs::read(dir.path().join("ab").join(hex(&id))).unwrap(),
+            b"data"
+        );
+        assert_eq!(fs::read_dir(dir.path().join(TMP_DIR)).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn ignores_leftover_temporary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).unwrap();
+        fs::write(dir.path().join(TMP_DIR).join(hex(&[1; 32])), b"partial").unwrap();
+
+        assert!(store.list().unwrap().is_empty());
+        assert!(!store.has(&[1; 32]).unwrap());
+    }
+
+    #[test]
+    fn reopens_existing_store() {
+        let dir = tempfile::tempdir().unwrap();
+        LocalStore::new(dir.path())
+            .unwrap()
+            .put(&[2; 32], b"data")
+            .unwrap();
+        assert_eq!(
+            LocalStore::new(dir.path()).unwrap().get(&[2; 32]).unwrap(),
+            b"data"
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::Mutex,
+    time::Duration,
+};
+
+use super::{ChunkStore, ContentId};
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Operation {
+    Put,
+    Get,
+    Has,
+    Delete,
+    List,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Fault {
+    /// Fails the operation without touching the stored chunks
+    Fail,
+    /// Sleeps before carrying out the operation
+    Delay(Duration),
+    /// Flips a bit of the data written by a put or returned by a get
+    Corrupt,
+    /// Reports success without storing or deleting anything, or reads as if the chunk were missing
+    Drop,
+}
+
+struct Rule {
+    operation: Operation,
+    id: Option<ContentId>,
+    fault: Fault,
+    /// Times left to apply the fault, or `None` to apply it indefinitely
+    remaining: Option<usize>,
+}
+
+#[derive(Default)]
+struct State {
+    chunks: BTreeMap<ContentId, Vec<u8>>,
+    rules: Vec<Rule>,
+    counts: HashMap<Operation, usize>,
+}
+
+/// Chunk store held in memory, with faults that can be injected into any operation.
+///
+/// Faults are matched in the order they were injected, and at most one fault applies to each
+/// operation.
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    state: Mutex<State>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies `fault` to the next `times` matching operations. `times` of `None` applies the
+    /// fault until [`MemoryStore::clear_faults`] is called.
+    pub fn inject(&self, operation: Operation, fault: Fault, times: Option<usize>) {
+        self.add_rule(operation, None, fault, times);
+    }
+
+    /// Like [`MemoryStore::inject`], but only for operations on the chunk `id`.
+    pub fn inject_for(
+        &self,
+        operation: Operation,
+        id: ContentId,
+        fault: Fault,
+        times: Option<usize>,
+    ) {
+        self.add_rule(operation, Some(id), fault, times);
+    }
+
+    fn add_rule(
+        &self,
+        operation: Operation,
+        id: Option<ContentId>,
+        fault: Fault,
+        times: Option<usize>,
+    ) {
+        self.lock().rules.push(Rule {
+            operation,
+            id,
+            fault,
+            remaining: times,
+        });
+    }
+
+    pub fn clear_faults(&self) {
+        self.lock().rules.clear();
+    }
+
+    /// Number of times `operation` has been attempted, including attempts that faulted.
+    pub fn count(&self, operation: Operation) -> usize {
+        self.lock().counts.get(&operation).copied().unwrap_or(0)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Counts the operation and takes the fault to apply to it, if any. Delays are applied here,
+    /// without holding the lock, so the returned fault is never a delay.
+    fn fault(&self, operation: Operation, id: Option<&ContentId>) -> Result<Option<Fault>> {
+        let fault = {
+            let mut state = self.lock();
+            *state.counts.entry(operation).or_default() += 1;
+
+            let index = state.rules.iter().position(|rule| {
+                rule.operation == operation
+                    && rule.remaining != Some(0)
+                    && (rule.id.is_none() || rule.id.as_ref() == id)
+            });
+            index.map(|index| {
+                let rule = &mut state.rules[index];
+                if let Some(remaining) = &mut rule.remaining {
+                    *remaining -= 1;
+                }
+                rule.fault
+            })
+        };
+
+        match fault {
+            Some(Fault::Fail) => {
+                Err(io::Error::other(format!("injected {operation:?} failure")).into())
+            }
+            Some(Fault::Delay(duration)) => {
+                std::thread::sleep(duration);
+                Ok(None)
+            }
+            fault => Ok(fault),
+        }
+    }
+}
+
+fn corrupt(data: &mut [u8]) {
+    if let Some(byte) = data.last_mut() {
+        *byte ^= 1;
+    }
+}
+
+impl ChunkStore for MemoryStore {
+    fn put(&self, id: &ContentId, data: &[u8]) -> Result<()> {
+        let mut data = data.to_vec();
+        match self.fault(Operation::Put, Some(id))? {
+            Some(Fault::Drop) => return Ok(()),
+            Some(Fault::Corrupt) => corrupt(&mut data),
+            _ => {}
+        }
+        self.lock().chunks.entry(*id).or_insert(data);
+        Ok(())
+    }
+
+    fn get(&self, id: &ContentId) -> Result<Vec<u8>> {
+        let fault = self.fault(Operation::Get, Some(id))?;
+        let mut data = match fault {
+            Some(Fault::Drop) => None,
+            _ => self.lock().chunks.get(id).cloned(),
+        }
+        .ok_or(Error::ChunkNotFound(*id))?;
+
+        if fault == Some(Fault::Corrupt) {
+            corrupt(&mut data);
+        }
+        Ok(data)
+    }
+
+    fn has(&self, id: &ContentId) -> Result<bool> {
+        match self.fault(Operation::Has, Some(id))? {
+            Some(Fault::Drop) => Ok(false),
+            _ => Ok(self.lock().chunks.contains_key(id)),
+        }
+    }
+
+    fn delete(&self, id: &ContentId) -> Result<()> {
+        if self.fault(Operation::Delete, Some(id))?.is_none() {
+            self.lock().chunks.remove(id);
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<ContentId>> {
+        match self.fault(Operation::List, None)? {
+            Some(Fault::Drop) => Ok(Vec::new()),
+            _ => Ok(self.lock().chunks.keys().copied().collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{
+        crypto::tests::PRK,
+        store::{get_file, put_file, tests::check_store},
+    };
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    fn stored_file(store: &MemoryStore) -> crate::file::FileManifest {
+        put_file(store, &Box::pin(PRK), std::fs::File::open(PATH).unwrap()).unwrap()
+    }
+
+    fn read_file(store: &MemoryStore, manifest: &crate::file::FileManifest) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        get_file(store, &Box::pin(PRK), manifest, &mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn satisfies_store_contract() {
+        check_store(&MemoryStore::new());
+    }
+
+    #[test]
+    fn failed_write_fails_file_upload() {
+        let store = MemoryStore::new();
+        store.inject(Operation::Put, Fault::Fail, Some(1));
+        assert!(put_file(&store, &Box::pin(PRK), std::fs::File::open(PATH).unwrap()).is_err());
+        assert!(store.list().unwrap().is_empty());
+
+        let manifest = stored_file(&store);
+        assert_eq!(
+            read_file(&store, &manifest).unwrap(),
+            std::fs::read(PATH).unwrap()
+        );
+    }
+
+    #[test]
+    fn dropped_write_is_detected_on_read() {
+        let store = MemoryStore::new();
+        store.inject(Operation::Put, Fault::Drop, Some(1));
+        let manifest = stored_file(&store);
+
+        assert!(matches!(
+            read_file(&store, &manifest),
+            Err(Error::ChunkNotFound(id)) if id == manifest.content_ids()[0]
+        ));
+    }
+
+    #[test]
+    fn corrupted_chunks_are_detected() {
+        let store = MemoryStore::new();
+        let manifest = stored_file(&store);
+
+        store.inject_for(
+            Operation::Get,
+            manifest.content_ids()[2],
+            Fault::Corrupt,
+            Some(1),
+        );
+        assert!(matches!(
+            read_file(&store, &manifest),
+            Err(Error::CorruptChunk(_))
+        ));
+        assert!(read_file(&store, &manifest).is_ok());
+
+        let store = MemoryStore::new();
+        store.inject(Operation::Put, Fault::Corrupt, None);
+        let manifest = stored_file(&store);
+        assert!(matches!(
+            read_file(&store, &manifest),
+            Err(Error::CorruptChunk(_))
+        ));
+    }
+
+    #[test]
+    fn faults_apply_the_given_number_of_times() {
+        let store = MemoryStore::new();
+        store.inject(Operation::Has, Fault::Fail, Some(2));
+
+        assert!(store.has(&[0; 32]).is_err());
+        assert!(store.has(&[0; 32]).is_err());
+        assert!(store.has(&[0; 32]).is_ok());
+        assert_eq!(store.count(Operation::Has), 3);
+        assert_eq!(store.count(Operation::Get), 0);
+
+        store.inject(Operation::List, Fault::Fail, None);
+        for _ in 0..5 {
+            assert!(store.list().is_err());
+        }
+        store.clear_faults();
+        assert!(store.list().is_ok());
+    }
+
+    #[test]
+    fn dropped_delete_keeps_chunk() {
+        let store = MemoryStore::new();
+        store.put(&[1; 32], b"data").unwrap();
+        store.inject(Operation::Delete, Fault::Drop, Some(1));
+
+        store.delete(&[1; 32]).unwrap();
+        assert!(store.has(&[1; 32]).unwrap());
+        store.delete(&[1; 32]).unwrap();
+        assert!(!store.has(&[1; 32]).unwrap());
+    }
+
+    #[test]
+    fn delays_operations() {
+        let store = MemoryStore::new();
+        store.put(&[1; 32], b"data").unwrap();
+        store.inject(
+            Operation::Get,
+            Fault::Delay(Duration::from_millis(50)),
+            Some(1),
+        );
+
+        let start = Instant::now();
+        assert_eq!(store.get(&[1; 32]).unwrap(), b"data");
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,344 @@
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use sha2::Digest;
+use uuid::Uuid;
+
+use crate::{
+    crypto::aead::{self, AesGcmKey, EncryptedChunk, FileDecryptor, FileEncryptor},
+    error::{Error, Result},
+    file::{self, FileManifest},
+    zeroize_allocator::Zeroing,
+};
+
+pub(crate) mod external;
+pub(crate) mod http;
+pub(crate) mod local;
+#[cfg(test)]
+pub(crate) mod memory;
+pub(crate) mod pack;
+pub(crate) mod s3;
+pub(crate) mod sftp;
+#[cfg(test)]
+mod test_server;
+pub(crate) mod webdav;
+
+/// SHA-256 of an [`EncryptedChunk`] as stored
+pub(crate) type ContentId = [u8; 32];
+
+/// Storage for encrypted chunks, addressed by their content id.
+///
+/// Stores only ever see encrypted chunks. Putting a chunk that is already stored is a no-op, and
+/// deleting a chunk that is not stored succeeds, so interrupted operations can simply be retried.
+pub(crate) trait ChunkStore {
+    fn put(&self, id: &ContentId, data: &[u8]) -> Result<()>;
+    /// Fails with [`Error::ChunkNotFound`] if the chunk is not stored
+    fn get(&self, id: &ContentId) -> Result<Vec<u8>>;
+    fn has(&self, id: &ContentId) -> Result<bool>;
+    fn delete(&self, id: &ContentId) -> Result<()>;
+    fn list(&self) -> Result<Vec<ContentId>>;
+
+    /// Size of a stored chunk in bytes, failing with [`Error::ChunkNotFound`] if it is not
+    /// stored. Stores that can tell without reading the chunk override this.
+    fn size(&self, id: &ContentId) -> Result<u64> {
+        Ok(self.get(id)?.len() as u64)
+    }
+
+    fn put_chunk(&self, chunk: &EncryptedChunk) -> Result<ContentId> {
+        let id = chunk.content_id();
+        self.put(&id, &chunk.to_bytes())?;
+        Ok(id)
+    }
+
+    /// Gets a chunk, checking that it matches its content id.
+    fn get_chunk(&self, id: &ContentId) -> Result<EncryptedChunk> {
+        let data = self.get(id)?;
+        if sha2::Sha256::digest(&data)[..] != id[..] {
+            return Err(Error::CorruptChunk(*id));
+        }
+        EncryptedChunk::parse(&data)
+    }
+}
+
+impl<S: ChunkStore + ?Sized> ChunkStore for Box<S> {
+    fn put(&self, id: &ContentId, data: &[u8]) -> Result<()> {
+        (**self).put(id, data)
+    }
+
+    fn get(&self, id: &ContentId) -> Result<Vec<u8>> {
+        (**self).get(id)
+    }
+
+    fn has(&self, id: &ContentId) -> Result<bool> {
+        (**self).has(id)
+    }
+
+    fn delete(&self, id: &ContentId) -> Result<()> {
+        (**self).delete(id)
+    }
+
+    fn list(&self) -> Result<Vec<ContentId>> {
+        (**self).list()
+    }
+
+    fn size(&self, id: &ContentId) -> Result<u64> {
+        (**self).size(id)
+    }
+}
+
+/// Opens the store at `location`, which names the kind of store followed by where it is:
+///
+/// - `local:<directory>`
+/// - `s3:<endpoint>/<bucket>`, with credentials and region from `AWS_ACCESS_KEY_ID`,
+///   `AWS_SECRET_ACCESS_KEY` and optionally `AWS_REGION`
+/// - `webdav:<url>`, with credentials from `WEBDAV_USERNAME` and `WEBDAV_PASSWORD`
+/// - `sftp:<user>@<host>:<directory>`, authenticating with the key in `PIGEONHOLE_SSH_KEY` or
+///   `~/.ssh/id_ed25519`
+/// - `external:<name>`, served by the helper program `pigeonhole-remote-<name>`
+pub(crate) fn open(location: &str) -> Result<Box<dyn ChunkStore>> {
+    let invalid = || Error::InvalidStoreLocation(location.to_owned());
+    let (kind, rest) = location.split_once(':').ok_or_else(invalid)?;
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    Ok(match kind {
+        "local" => Box::new(local::LocalStore::new(rest)?),
+        "s3" => {
+            let (endpoint, bucket) = rest
+                .trim_end_matches('/')
+                .rsplit_once('/')
+                .ok_or_else(invalid)?;
+            if !endpoint.contains("://") || bucket.is_empty() {
+                return Err(invalid());
+            }
+            let mut config = s3::S3Config::new(
+                endpoint,
+                bucket,
+                &env("AWS_ACCESS_KEY_ID")?,
+                &env("AWS_SECRET_ACCESS_KEY")?,
+            );
+            if let Ok(region) = std::env::var("AWS_REGION") {
+                config.region = region;
+            }
+            Box::new(s3::S3Store::new(config))
+        }
+        "webdav" => Box::new(webdav::WebDavStore::new(webdav::WebDavConfig::new(
+            rest,
+            &env("WEBDAV_USERNAME")?,
+            &env("WEBDAV_PASSWORD")?,
+        ))),
+        "sftp" => {
+            let (username, rest) = rest.split_once('@').ok_or_else(invalid)?;
+            let (host, root) = rest.split_once(':').ok_or_else(invalid)?;
+            let key = match std::env::var_os("PIGEONHOLE_SSH_KEY") {
+                Some(key) => PathBuf::from(key),
+                None => {
+                    Path::new(&std::env::var_os("HOME").unwrap_or_default()).join(".ssh/id_ed25519")
+                }
+            };
+            let config = sftp::SftpConfig::new(host, username, &key, Path::new(root));
+            Box::new(sftp::SftpStore::new(config)?)
+        }
+        "external" => Box::new(external::ExternalStore::spawn(rest, &[])?),
+        _ => return Err(invalid()),
+    })
+}
+
+fn env(name: &'static str) -> Result<String> {
+    std::env::var(name).map_err(|_| Error::MissingEnvironment(name))
+}
+
+/// Lower case hex encoding of a content id or other bytes, as used in storage keys and messages.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub(crate) fn parse_hex(hex: &str) -> Option<ContentId> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut id = [0u8; 32];
+    for (byte, pair) in id.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(id)
+}
+
+/// Encrypts everything read from `reader` as a new file, storing one chunk per `CHUNK_SIZE` bytes.
+pub(crate) fn put_file(
+    store: &(impl ChunkStore + ?Sized),
+    prk: &Zeroing<[u8; 32]>,
+    mut reader: impl Read,
+) -> Result<FileManifest> {
+    let mut encryptor = FileEncryptor::<AesGcmKey>::generate(Box::pin(**prk), Uuid::now_v7())?;
+    let mut manifest = FileManifest::new();
+    let mut buf = Vec::with_capacity(file::CHUNK_SIZE as usize);
+
+    loop {
+        buf.clear();
+        (&mut reader).take(file::CHUNK_SIZE).read_to_end(&mut buf)?;
+        if buf.is_empty() {
+            break;
+        }
+        let chunk = encryptor.encrypt(&buf)?;
+        store.put_chunk(&chunk)?;
+        manifest.add(&chunk, buf.len() as u64);
+    }
+    manifest.mark_complete();
+    Ok(manifest)
+}
+
+/// Decrypts the file described by `manifest` into `writer`, returning the number of bytes written.
+///
+/// Every chunk is checked against the manifest, which must itself come from a trusted source such
+/// as an encrypted tree manifest.
+pub(crate) fn get_file(
+    store: &(impl ChunkStore + ?Sized),
+    prk: &Zeroing<[u8; 32]>,
+    manifest: &FileManifest,
+    writer: &mut impl Write,
+) -> Result<u64> {
+    if !manifest.is_complete() {
+        return Err(Error::IncompleteManifest);
+    }
+    let mut decryptor = FileDecryptor::new(Box::pin(**prk));
+    for (id, len) in manifest.content_ids().iter().zip(manifest.lengths()) {
+        let data = decryptor.decrypt(&store.get_chunk(id)?)?;
+        if data.len() as u64 != *len {
+            return Err(Error::ManifestMismatch);
+        }
+        writer.write_all(&data)?;
+    }
+    Ok(manifest.size())
+}
+
+/// Encrypts and stores a standalone blob, such as a tree manifest, returning its content id.
+pub(crate) fn put_blob(
+    store: &(impl ChunkStore + ?Sized),
+    prk: &Zeroing<[u8; 32]>,
+    data: &[u8],
+) -> Result<ContentId> {
+    store.put_chunk(&aead::encrypt_blob(prk, data)?)
+}
+
+pub(crate) fn get_blob(
+    store: &(impl ChunkStore + ?Sized),
+    prk: &Zeroing<[u8; 32]>,
+    id: &ContentId,
+) -> Result<Vec<u8>> {
+    aead::decrypt_blob(prk, &store.get_chunk(id)?)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{local::LocalStore, *};
+    use crate::crypto::tests::PRK;
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    /// Exercises the [`ChunkStore`] contract, shared by the tests of every store.
+    pub fn check_store(store: &impl ChunkStore) {
+        let chunk = aead::encrypt_blob(&Box::pin(PRK), b"chunk").unwrap();
+        let id = chunk.content_id();
+
+        assert!(!store.has(&id).unwrap());
+        assert!(matches!(store.get(&id), Err(Error::ChunkNotFound(missing)) if missing == id));
+        assert!(matches!(store.size(&id), Err(Error::ChunkNotFound(_))));
+        assert!(store.list().unwrap().is_empty());
+
+        assert_eq!(store.put_chunk(&chunk).unwrap(), id);
+        store.put_chunk(&chunk).unwrap();
+        assert!(store.has(&id).unwrap());
+        assert_eq!(store.size(&id).unwrap(), chunk.to_bytes().len() as u64);
+        assert_eq!(store.get(&id).unwrap(), chunk.to_bytes());
+        assert_eq!(store.get_chunk(&id).unwrap().to_bytes(), chunk.to_bytes());
+
+        let other = aead::encrypt_blob(&Box::pin(PRK), b"other").unwrap();
+        store.put_chunk(&other).unwrap();
+        let mut ids = vec![id, other.content_id()];
+        ids.sort();
+        let mut listed = store.list().unwrap();
+        listed.sort();
+        assert_eq!(listed, ids);
+
+        store.delete(&id).unwrap();
+        store.delete(&id).unwrap();
+        assert!(!store.has(&id).unwrap());
+        assert_eq!(store.list().unwrap(), [other.content_id()]);
+
+        store.put(&id, b"not the chunk").unwrap();
+        assert!(matches!(store.get_chunk(&id), Err(Error::CorruptChunk(_))));
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let id: ContentId = std::array::from_fn(|i| i as u8 * 7);
+        let hex = hex(&id);
+        assert_eq!(&hex[..6], "00070e");
+        assert_eq!(parse_hex(&hex), Some(id));
+        assert_eq!(parse_hex(&hex[1..]), None);
+        assert_eq!(parse_hex(&"g".repeat(64)), None);
+    }
+
+    #[test]
+    fn round_trips_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).unwrap();
+        let contents = std::fs::read(PATH).unwrap();
+
+        let manifest = put_file(&store, &Box::pin(PRK), &contents[..]).unwrap();
+        assert_eq!(manifest.size(), contents.len() as u64);
+        assert_eq!(store.list().unwrap().len(), manifest.content_ids().len());
+
+        let mut data = Vec::new();
+        get_file(&store, &Box::pin(PRK), &manifest, &mut data).unwrap();
+        assert_eq!(data, contents);
+
+        let empty = put_file(&store, &Box::pin(PRK), &[][..]).unwrap();
+        let mut data = Vec::new();
+        assert_eq!(
+            get_file(&store, &Box::pin(PRK), &empty, &mut data).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn get_file_rejects_missing_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).unwrap();
+        let manifest =
+            put_file(&store, &Box::pin(PRK), std::fs::File::open(PATH).unwrap()).unwrap();
+
+        store.delete(&manifest.content_ids()[3]).unwrap();
+        assert!(matches!(
+            get_file(&store, &Box::pin(PRK), &manifest, &mut Vec::new()),
+            Err(Error::ChunkNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn round_trips_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path()).unwrap();
+
+        let id = put_blob(&store, &Box::pin(PRK), b"blob").unwrap();
+        assert_eq!(get_blob(&store, &Box::pin(PRK), &id).unwrap(), b"blob");
+        assert!(get_blob(&store, &Box::pin([0u8; 32]), &id).is_err());
+    }
+
+    #[test]
+    fn opens_stores_by_location() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(&format!("local:{}", dir.path().display())).unwrap();
+        check_store(&store);
+
+        for location in ["", "local:", "tape:/dev/st0", "s3:bucket", "sftp:host:/srv"] {
+            assert!(
+                matches!(open(location), Err(Error::InvalidStoreLocation(_))),
+                "{location}"
+            );
+        }
+    }
+}
@@ -0,0 +1,618 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+use super::{get_blob, hex, parse_hex, put_blob, ChunkStore, ContentId};
+use crate::{
+    error::{Error, Result},
+    zeroize_allocator::Zeroing,
+};
+
+const MAGIC: &[u8; 4] = b"PHPK";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1;
+pub(crate) const DEFAULT_PACK_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Location {
+    pack: ContentId,
+    offset: u64,
+    len: u64,
+}
+
+/// Which pack holds every chunk, stored encrypted so the store learns nothing about the chunks.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct PackIndex {
+    /// Size of every pack, including chunks deleted since it was written
+    packs: BTreeMap<ContentId, u64>,
+    chunks: BTreeMap<ContentId, Location>,
+}
+
+impl PackIndex {
+    /// Bytes of chunks still in use in every pack.
+    fn live_bytes(&self) -> BTreeMap<ContentId, u64> {
+        let mut live: BTreeMap<_, _> = self.packs.keys().map(|pack| (*pack, 0)).collect();
+        for location in self.chunks.values() {
+            *live.entry(location.pack).or_default() += location.len;
+        }
+        live
+    }
+}
+
+struct State {
+    index: PackIndex,
+    /// Pack being filled, starting with its header
+    open: Vec<u8>,
+    /// Chunks in the open pack, by offset and length
+    pending: BTreeMap<ContentId, (usize, usize)>,
+    /// Last pack read, since chunks tend to be read in the order they were written
+    cached: Option<(ContentId, Vec<u8>)>,
+    /// Packs written since the last index was written
+    unflushed: PackIndex,
+    /// Chunks deleted since the last index was written
+    deleted: BTreeSet<ContentId>,
+}
+
+/// Outcome of [`PackStore::repack`].
+#[derive(Debug, PartialEq)]
+pub(crate) struct Repack {
+    /// Index to open the store with from now on
+    pub index: ContentId,
+    /// Packs no longer referenced by the new index, to remove once the index has been recorded
+    pub obsolete: Vec<ContentId>,
+    /// Bytes of deleted chunks in the obsolete packs
+    pub reclaimed: u64,
+}
+
+/// Chunk store bundling chunks into pack objects in another store, so that stores are not
+/// swamped with one object per small chunk.
+///
+/// Packs are a short header followed by the chunks, which are already encrypted. Where every
+/// chunk lives is recorded in a pack index, stored as an encrypted blob in the same store.
+/// Chunks put are only written once a pack fills up or the store is flushed, and the content id
+/// of the index returned by [`PackStore::flush`] is needed to open the store again, which
+/// [`PackedStore`] records. Superseded indexes are left in the store.
+///
+/// Deleting a chunk only removes it from the index, leaving the space it takes up in its pack
+/// until the pack is rewritten by [`PackStore::repack`].
+pub(crate) struct PackStore<S> {
+    inner: S,
+    prk: Zeroing<[u8; 32]>,
+    pack_size: usize,
+    state: Mutex<State>,
+}
+
+impl<S: ChunkStore> PackStore<S> {
+    /// Starts an empty store in `inner`, writing packs once they reach `pack_size` bytes.
+    pub fn new(inner: S, prk: Zeroing<[u8; 32]>, pack_size: usize) -> Self {
+        Self::with_index(inner, prk, pack_size, PackIndex::default())
+    }
+
+    /// Opens a store in `inner` with the index returned by an earlier flush.
+    #[cfg(test)]
+    pub fn open(
+        inner: S,
+        prk: Zeroing<[u8; 32]>,
+        pack_size: usize,
+        index: &ContentId,
+    ) -> Result<Self> {
+        let index = bincode::deserialize(&get_blob(&inner, &prk, index)?)?;
+        Ok(Self::with_index(inner, prk, pack_size, index))
+    }
+
+    fn with_index(inner: S, prk: Zeroing<[u8; 32]>, pack_size: usize, index: PackIndex) -> Self {
+        Self {
+            inner,
+            prk,
+            pack_size,
+            state: Mutex::new(State {
+                index,
+                open: new_pack(),
+                pending: BTreeMap::new(),
+                cached: None,
+                unflushed: PackIndex::default(),
+                deleted: BTreeSet::new(),
+            }),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes the open pack and the index, returning the content id of the index.
+    pub fn flush(&self) -> Result<ContentId> {
+        let mut state = self.lock();
+        self.write_pack(&mut state)?;
+        self.write_index(&mut state)
+    }
+
+    fn write_index(&self, state: &mut State) -> Result<ContentId> {
+        let index = put_blob(&self.inner, &self.prk, &bincode::serialize(&state.index)?)?;
+        state.unflushed = PackIndex::default();
+        state.deleted.clear();
+        Ok(index)
+    }
+
+    /// Replaces the index with the union of `indexes`, such as those written by other writers
+    /// since the store was opened, keeping the packs written and chunks deleted since the last
+    /// flush.
+    pub fn reload(&self, indexes: &[ContentId]) -> Result<()> {
+        let mut merged = PackIndex::default();
+        for id in indexes {
+            let index: PackIndex = bincode::deserialize(&get_blob(&self.inner, &self.prk, id)?)?;
+            merged.packs.extend(index.packs);
+            merged.chunks.extend(index.chunks);
+        }
+
+        let mut state = self.lock();
+        merged.packs.extend(&state.unflushed.packs);
+        merged.chunks.extend(&state.unflushed.chunks);
+        for id in &state.deleted {
+            merged.chunks.remove(id);
+        }
+        state.index = merged;
+        Ok(())
+    }
+
+    /// Number of packs and their total size in bytes, excluding the open pack.
+    #[cfg(test)]
+    pub fn pack_stats(&self) -> (usize, u64) {
+        let state = self.lock();
+        (state.index.packs.len(), state.index.packs.values().sum())
+    }
+
+    fn write_pack(&self, state: &mut State) -> Result<()> {
+        if state.pending.is_empty() {
+            state.open = new_pack();
+            return Ok(());
+        }
+        let pack = std::mem::replace(&mut state.open, new_pack());
+        let id: ContentId = sha2::Sha256::digest(&pack).into();
+        self.inner.put(&id, &pack)?;
+
+        state.index.packs.insert(id, pack.len() as u64);
+        state.unflushed.packs.insert(id, pack.len() as u64);
+        for (chunk, (offset, len)) in std::mem::take(&mut state.pending) {
+            let location = Location {
+                pack: id,
+                offset: offset as u64,
+                len: len as u64,
+            };
+            state.index.chunks.insert(chunk, location);
+            state.unflushed.chunks.insert(chunk, location);
+        }
+        Ok(())
+    }
+
+    fn append(&self, state: &mut State, id: &ContentId, data: &[u8]) -> Result<()> {
+        if state.open.len() > HEADER_LEN && state.open.len() + data.len() > self.pack_size {
+            self.write_pack(state)?;
+        }
+        state.pending.insert(*id, (state.open.len(), data.len()));
+        state.open.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Reads a pack, checking it against its content id.
+    fn read_pack(&self, state: &mut State, pack: &ContentId) -> Result<()> {
+        if state
+            .cached
+            .as_ref()
+            .is_some_and(|(cached, _)| cached == pack)
+        {
+            return Ok(());
+        }
+        let data = self.inner.get(pack)?;
+        if sha2::Sha256::digest(&data)[..] != pack[..] {
+            return Err(Error::CorruptChunk(*pack));
+        }
+        if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidPack("missing pack header"));
+        }
+        if data[MAGIC.len()] != VERSION {
+            return Err(Error::InvalidPack("unsupported pack version"));
+        }
+        state.cached = Some((*pack, data));
+        Ok(())
+    }
+
+    fn read_chunk(&self, state: &mut State, location: &Location) -> Result<Vec<u8>> {
+        self.read_pack(state, &location.pack)?;
+        let (_, pack) = state.cached.as_ref().expect("pack was read");
+        let range = location.offset as usize..(location.offset + location.len) as usize;
+        pack.get(range)
+            .map(<[u8]>::to_vec)
+            .ok_or(Error::InvalidPack("chunk extends past the end of its pack"))
+    }
+
+    /// Rewrites the chunks still in use from every pack less than `min_live` full (as a
+    /// fraction of its size) into new packs, and writes a new index.
+    ///
+    /// The obsolete packs are left in the inner store, since the index the store was opened with
+    /// still refers to them. They should be removed with [`PackStore::remove_packs`] once the new
+    /// index has been recorded.
+    pub fn repack(&self, min_live: f64) -> Result<Repack> {
+        let mut state = self.lock();
+        let sparse: Vec<_> = state
+            .index
+            .live_bytes()
+            .into_iter()
+            .filter(|(pack, live)| (*live as f64) < min_live * state.index.packs[pack] as f64)
+            .map(|(pack, _)| pack)
+            .collect();
+
+        let mut reclaimed = 0;
+        for pack in &sparse {
+            let chunks: Vec<_> = state
+                .index
+                .chunks
+                .iter()
+                .filter(|(_, location)| location.pack == *pack)
+                .map(|(id, location)| (*id, *location))
+                .collect();
+            let mut live = 0;
+            for (id, location) in chunks {
+                let data = self.read_chunk(&mut state, &location)?;
+                state.index.chunks.remove(&id);
+                self.append(&mut state, &id, &data)?;
+                live += location.len;
+            }
+            let size = state.index.packs.remove(pack).unwrap_or_default();
+            reclaimed += size.saturating_sub(HEADER_LEN as u64 + live);
+        }
+        state.cached = None;
+
+        self.write_pack(&mut state)?;
+        let index = self.write_index(&mut state)?;
+        Ok(Repack {
+            index,
+            obsolete: sparse,
+            reclaimed,
+        })
+    }
+
+    /// Deletes packs made obsolete by [`PackStore::repack`] from the inner store.
+    pub fn remove_packs(&self, packs: &[ContentId]) -> Result<()> {
+        let state = self.lock();
+        for pack in packs {
+            if !state.index.packs.contains_key(pack) {
+                self.inner.delete(pack)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn new_pack() -> Vec<u8> {
+    let mut pack = Vec::with_capacity(HEADER_LEN);
+    pack.extend_from_slice(MAGIC);
+    pack.push(VERSION);
+    pack
+}
+
+impl<S: ChunkStore> ChunkStore for PackStore<S> {
+    fn put(&self, id: &ContentId, data: &[u8]) -> Result<()> {
+        let mut state = self.lock();
+        if state.index.chunks.contains_key(id) || state.pending.contains_key(id) {
+            return Ok(());
+        }
+        state.deleted.remove(id);
+        self.append(&mut state, id, data)
+    }
+
+    fn get(&self, id: &ContentId) -> Result<Vec<u8>> {
+        let mut state = self.lock();
+        if let Some((offset, len)) = state.pending.get(id) {
+            return Ok(state.open[*offset..offset + len].to_vec());
+        }
+        let location = *state
+            .index
+            .chunks
+            .get(id)
+            .ok_or(Error::ChunkNotFound(*id))?;
+        self.read_chunk(&mut state, &location)
+    }
+
+    fn has(&self, id: &ContentId) -> Result<bool> {
+        let state = self.lock();
+        Ok(state.index.chunks.contains_key(id) || state.pending.contains_key(id))
+    }
+
+    fn delete(&self, id: &ContentId) -> Result<()> {
+        let mut state = self.lock();
+        state.index.chunks.remove(id);
+        state.unflushed.chunks.remove(id);
+        state.pending.remove(id);
+        state.deleted.insert(*id);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<ContentId>> {
+        let state = self.lock();
+        let mut ids: Vec<_> = state.index.chunks.keys().copied().collect();
+        ids.extend(state.pending.keys());
+        Ok(ids)
+    }
+}
+
+/// [`PackStore`] recording the content ids of its current indexes as the names of the files in a
+/// directory, such as the `packs` directory of a vault.
+///
+/// Flushing writes an index of everything in the indexes recorded when the store last looked,
+/// then replaces their records with its own. Writers flushing at the same time each leave a
+/// record, and whoever opens the store next merges them. Chunks recorded by others since are
+/// picked up whenever a chunk is missing.
+pub(crate) struct PackedStore<S> {
+    packs: PackStore<S>,
+    dir: PathBuf,
+    /// Recorded indexes the pack store holds everything of, sorted
+    merged: Mutex<Vec<ContentId>>,
+}
+
+impl<S: ChunkStore> PackedStore<S> {
+    pub fn open(
+        inner: S,
+        prk: Zeroing<[u8; 32]>,
+        pack_size: usize,
+        dir: impl Into<PathBuf>,
+    ) -> Result<Self> {
+        let store = Self {
+            packs: PackStore::new(inner, prk, pack_size),
+            dir: dir.into(),
+            merged: Mutex::new(Vec::new()),
+        };
+        store.reload()?;
+        Ok(store)
+    }
+
+    fn merged(&self) -> std::sync::MutexGuard<'_, Vec<ContentId>> {
+        self.merged.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn recorded(&self) -> Result<Vec<ContentId>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut indexes = Vec::new();
+        for entry in entries {
+            if let Some(id) = entry?.file_name().to_str().and_then(parse_hex) {
+                indexes.push(id);
+            }
+        }
+        indexes.sort();
+        Ok(indexes)
+    }
+
+    /// Merges the indexes recorded since the store last looked. Returns whether there were any.
+    fn reload(&self) -> Result<bool> {
+        let recorded = self.recorded()?;
+        let mut merged = self.merged();
+        if recorded == *merged {
+            return Ok(false);
+        }
+        self.packs.reload(&recorded)?;
+        *merged = recorded;
+        Ok(true)
+    }
+
+    /// Records `index`, which holds everything in the indexes merged so far, in their place.
+    fn record(&self, index: ContentId) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.join(hex(&index)), b"")?;
+        let mut merged = self.merged();
+        for superseded in merged.drain(..).filter(|id| *id != index) {
+            match fs::remove_file(self.dir.join(hex(&superseded))) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        merged.push(index);
+        Ok(())
+    }
+
+    /// Writes the open pack and records a new index.
+    pub fn flush(&self) -> Result<()> {
+        self.reload()?;
+        self.record(self.packs.flush()?)
+    }
+
+    /// Repacks the packs less than `min_live` full as [`PackStore::repack`] does, records the new
+    /// index and removes the obsolete packs.
+    pub fn repack(&self, min_live: f64) -> Result<Repack> {
+        self.reload()?;
+        let repack = self.packs.repack(min_live)?;
+        self.record(repack.index)?;
+        self.packs.remove_packs(&repack.obsolete)?;
+        Ok(repack)
+    }
+}
+
+impl<S: ChunkStore> ChunkStore for PackedStore<S> {
+    fn put(&self, id: &ContentId, data: &[u8]) -> Result<()> {
+        self.packs.put(id, data)
+    }
+
+    fn get(&self, id: &ContentId) -> Result<Vec<u8>> {
+        match self.packs.get(id) {
+            Err(Error::ChunkNotFound(_)) if self.reload()? => self.packs.get(id),
+            result => result,
+        }
+    }
+
+    fn has(&self, id: &ContentId) -> Result<bool> {
+        Ok(self.packs.has(id)? || (self.reload()? && self.packs.has(id)?))
+    }
+
+    fn delete(&self, id: &ContentId) -> Result<()> {
+        self.packs.delete(id)
+    }
+
+    fn list(&self) -> Result<Vec<ContentId>> {
+        self.reload()?;
+        self.packs.list()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::{aead, tests::PRK},
+        store::{
+            get_file,
+            local::LocalStore,
+            memory::{Fault, MemoryStore, Operation},
+            put_file,
+            tests::check_store,
+        },
+    };
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    fn chunk(i: u8) -> (ContentId, Vec<u8>) {
+        let chunk = aead::encrypt_blob(&Box::pin(PRK), &[i; 16]).unwrap();
+        (chunk.content_id(), chunk.to_bytes())
+    }
+
+    #[test]
+    fn satisfies_store_contract() {
+        check_store(&PackStore::new(MemoryStore::new(), Box::pin(PRK), 256));
+
+        let store = PackStore::new(MemoryStore::new(), Box::pin(PRK), 256);
+        store.flush().unwrap();
+        check_store(&store);
+    }
+
+    #[test]
+    fn bundles_chunks_into_packs() {
+        let store = PackStore::new(MemoryStore::new(), Box::pin(PRK), 256);
+        let contents = std::fs::read(PATH).unwrap();
+        let manifest = put_file(&store, &Box::pin(PRK), &contents[..]).unwrap();
+        let index = store.flush().unwrap();
+
+        let (packs, size) = store.pack_stats();
+        assert!(packs > 1 && packs < manifest.content_ids().len());
+        assert!(size <= (packs * 256) as u64);
+        let inner = store.into_inner();
+        assert_eq!(inner.list().unwrap().len(), packs + 1);
+
+        let store = PackStore::open(inner, Box::pin(PRK), 256, &index).unwrap();
+        let mut data = Vec::new();
+        get_file(&store, &Box::pin(PRK), &manifest, &mut data).unwrap();
+        assert_eq!(data, contents);
+        assert_eq!(store.list().unwrap().len(), manifest.content_ids().len());
+    }
+
+    #[test]
+    fn index_needs_the_key() {
+        let store = PackStore::new(MemoryStore::new(), Box::pin(PRK), 256);
+        let (id, data) = chunk(1);
+        store.put(&id, &data).unwrap();
+        let index = store.flush().unwrap();
+
+        let inner = store.into_inner();
+        assert!(PackStore::open(inner, Box::pin([7; 32]), 256, &index).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupt_packs() {
+        let store = PackStore::new(MemoryStore::new(), Box::pin(PRK), 256);
+        let (id, data) = chunk(1);
+        store.put(&id, &data).unwrap();
+        store.flush().unwrap();
+
+        store.inner.inject(Operation::Get, Fault::Corrupt, Some(1));
+        assert!(matches!(store.get(&id), Err(Error::CorruptChunk(pack)) if pack != id));
+        assert_eq!(store.get(&id).unwrap(), data);
+    }
+
+    #[test]
+    fn merges_indexes_of_concurrent_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let records = dir.path().join("packs");
+        let open = || {
+            let inner = LocalStore::new(dir.path().join("chunks")).unwrap();
+            PackedStore::open(inner, Box::pin(PRK), 256, &records).unwrap()
+        };
+        let chunks: Vec<_> = (0..3).map(chunk).collect();
+        let (a, b) = (open(), open());
+
+        a.put(&chunks[0].0, &chunks[0].1).unwrap();
+        a.flush().unwrap();
+        assert_eq!(b.get(&chunks[0].0).unwrap(), chunks[0].1);
+        b.put(&chunks[1].0, &chunks[1].1).unwrap();
+        b.flush().unwrap();
+        assert_eq!(fs::read_dir(&records).unwrap().count(), 1);
+
+        // Flushed without seeing the index of `b`
+        let inner = LocalStore::new(dir.path().join("chunks")).unwrap();
+        let c = PackStore::new(inner, Box::pin(PRK), 256);
+        c.put(&chunks[2].0, &chunks[2].1).unwrap();
+        fs::write(records.join(hex(&c.flush().unwrap())), b"").unwrap();
+
+        let merged = open();
+        for (id, data) in &chunks {
+            assert_eq!(&merged.get(id).unwrap(), data);
+        }
+        merged.delete(&chunks[0].0).unwrap();
+        merged.flush().unwrap();
+        assert_eq!(fs::read_dir(&records).unwrap().count(), 1);
+        let reopened = open();
+        assert!(!reopened.has(&chunks[0].0).unwrap());
+        assert_eq!(reopened.list().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn repacks_sparse_packs() {
+        let store = PackStore::new(MemoryStore::new(), Box::pin(PRK), 1024);
+        let chunks: Vec<_> = (0..20).map(chunk).collect();
+        for (id, data) in &chunks {
+            store.put(id, data).unwrap();
+        }
+        store.flush().unwrap();
+        let (packs, size) = store.pack_stats();
+        assert!(packs >= 2);
+
+        // Leaves the first pack mostly empty
+        for (id, _) in &chunks[..12] {
+            store.delete(id).unwrap();
+        }
+        let repack = store.repack(0.5).unwrap();
+        assert_eq!(repack.obsolete.len(), 1);
+        assert!(repack.reclaimed > 0);
+        let (_, repacked_size) = store.pack_stats();
+        assert!(repacked_size < size);
+
+        store.remove_packs(&repack.obsolete).unwrap();
+        let inner = store.into_inner();
+        for pack in &repack.obsolete {
+            assert!(!inner.has(pack).unwrap());
+        }
+
+        let store = PackStore::open(inner, Box::pin(PRK), 1024, &repack.index).unwrap();
+        for (id, data) in &chunks[12..] {
+            assert_eq!(&store.get_chunk(id).unwrap().to_bytes(), data);
+        }
+        for (id, _) in &chunks[..12] {
+            assert!(!store.has(id).unwrap());
+        }
+
+        let repack = store.repack(0.5).unwrap();
+        assert!(repack.obsolete.is_empty());
+        assert_eq!(repack.reclaimed, 0);
+    }
+}
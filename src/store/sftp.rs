@@ -0,0 +1,330 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session, Sftp};
+use uuid::Uuid;
+
+use super::{hex, parse_hex, ChunkStore, ContentId};
+use crate::error::{Error, Result};
+
+/// `LIBSSH2_FX_NO_SUCH_FILE`
+const NO_SUCH_FILE: i32 = 2;
+/// `LIBSSH2_FX_OP_UNSUPPORTED`, as answered by servers lacking an extension such as
+/// `fsync@openssh.com`
+const OP_UNSUPPORTED: i32 = 8;
+const TMP_DIR: &str = "tmp";
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SftpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub private_key: PathBuf,
+    pub passphrase: Option<String>,
+    /// OpenSSH `known_hosts` file the server's host key must be listed in
+    pub known_hosts: PathBuf,
+    /// Remote directory to store chunks in, which must already exist
+    pub root: PathBuf,
+}
+
+impl SftpConfig {
+    /// Configuration using the user's OpenSSH `known_hosts` file.
+    pub fn new(host: &str, username: &str, private_key: &Path, root: &Path) -> Self {
+        let home = std::env::var_os("HOME").unwrap_or_default();
+        Self {
+            host: host.to_owned(),
+            port: 22,
+            username: username.to_owned(),
+            private_key: private_key.to_owned(),
+            passphrase: None,
+            known_hosts: Path::new(&home).join(".ssh/known_hosts"),
+            root: root.to_owned(),
+        }
+    }
+}
+
+/// Chunk store in a directory on an SSH server, accessed over SFTP.
+///
+/// The layout matches [`super::local::LocalStore`]: chunks are uploaded to a temporary file and
+/// renamed to `<root>/<first byte in hex>/<content id in hex>`. One SSH session is shared by all
+/// operations and re-established if it breaks.
+pub(crate) struct SftpStore {
+    config: SftpConfig,
+    sftp: Mutex<Option<Sftp>>,
+}
+
+impl SftpStore {
+    /// Connects to the server, authenticating with the configured key.
+    pub fn new(config: SftpConfig) -> Result<Self> {
+        let sftp = connect(&config)?;
+        Ok(Self {
+            config,
+            sftp: Mutex::new(Some(sftp)),
+        })
+    }
+
+    fn fan_out_dir(&self, id: &ContentId) -> PathBuf {
+        self.config.root.join(format!("{:02x}", id[0]))
+    }
+
+    fn path(&self, id: &ContentId) -> PathBuf {
+        self.fan_out_dir(id).join(hex(id))
+    }
+
+    /// Runs `operation`, reconnecting and running it once more if the session broke.
+    fn with_sftp<T>(&self, operation: impl Fn(&Sftp) -> Result<T>) -> Result<T> {
+        let mut sftp = self.sftp.lock().unwrap_or_else(|e| e.into_inner());
+        let result = Self::connected(&mut sftp, &self.config).and_then(&operation);
+        match result {
+            Err(e) if is_connection_error(&e) => {
+                *sftp = None;
+                operation(Self::connected(&mut sftp, &self.config)?)
+            }
+            result => result,
+        }
+    }
+
+    fn connected<'a>(
+        sftp: &'a mut MutexGuard<'_, Option<Sftp>>,
+        config: &SftpConfig,
+    ) -> Result<&'a Sftp> {
+        if sftp.is_none() {
+            **sftp = Some(connect(config)?);
+        }
+        Ok(sftp.as_ref().expect("connected above"))
+    }
+}
+
+fn connect(config: &SftpConfig) -> Result<Sftp> {
+    let mut session = Session::new()?;
+    session.set_tcp_stream(TcpStream::connect((config.host.as_str(), config.port))?);
+    session.handshake()?;
+
+    let mut known_hosts = session.known_hosts()?;
+    known_hosts.read_file(&config.known_hosts, KnownHostFileKind::OpenSSH)?;
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| Error::Remote("server sent no host key".to_owned()))?;
+    match known_hosts.check_port(&config.host, config.port, key) {
+        CheckResult::Match => {}
+        CheckResult::Mismatch => {
+            return Err(Error::Remote(format!(
+                "host key of {} does not match known_hosts",
+                config.host
+            )))
+        }
+        _ => {
+            return Err(Error::Remote(format!(
+                "host key of {} is not in known_hosts",
+                config.host
+            )))
+        }
+    }
+
+    session.userauth_pubkey_file(
+        &config.username,
+        None,
+        &config.private_key,
+        config.passphrase.as_deref(),
+    )?;
+    Ok(session.sftp()?)
+}
+
+fn is_not_found(error: &Error) -> bool {
+    matches!(error, Error::Ssh(e) if e.code() == ErrorCode::SFTP(NO_SUCH_FILE))
+}
+
+fn is_unsupported(error: &ssh2::Error) -> bool {
+    error.code() == ErrorCode::SFTP(OP_UNSUPPORTED)
+}
+
+/// Whether the session itself failed, rather than the server rejecting an operation.
+fn is_connection_error(error: &Error) -> bool {
+    match error {
+        Error::Ssh(e) => matches!(e.code(), ErrorCode::Session(_)),
+        Error::Io { .. } => true,
+        _ => false,
+    }
+}
+
+fn ensure_dir(sftp: &Sftp, dir: &Path) -> Result<()> {
+    match sftp.stat(dir) {
+        Ok(_) => Ok(()),
+        Err(_) => match sftp.mkdir(dir, 0o755) {
+            // Another client may have created it in the meantime
+            Err(e) if sftp.stat(dir).is_err() => Err(e.into()),
+            _ => Ok(()),
+        },
+    }
+}
+
+impl ChunkStore for SftpStore {
+    fn put(&self, id: &ContentId, data: &[u8]) -> Result<()> {
+        let path = self.path(id);
+        self.with_sftp(|sftp| {
+            if sftp.stat(&path).is_ok() {
+                return Ok(());
+            }
+            let tmp_dir = self.config.root.join(TMP_DIR);
+            ensure_dir(sftp, &tmp_dir)?;
+            ensure_dir(sftp, &self.fan_out_dir(id))?;
+
+            let tmp = tmp_dir.join(Uuid::now_v7().to_string());
+            let result = (|| {
+                let mut file = sftp.create(&tmp)?;
+                file.write_all(data)?;
+                match file.fsync() {
+                    // Without fsync@openssh.com the chunk is only as durable as the server makes
+                    // the rename, which still never exposes a partial chunk
+                    Err(e) if is_unsupported(&e) => {}
+                    result => result?,
+                }
+                drop(file);
+                match sftp.rename(&tmp, &path, None) {
+                    // Servers that cannot overwrite fail if the chunk was stored concurrently
+                    Err(e) if sftp.stat(&path).is_err() => Err(Error::from(e)),
+                    _ => Ok(()),
+                }
+            })();
+            let _ = sftp.unlink(&tmp);
+            result
+        })
+    }
+
+    fn get(&self, id: &ContentId) -> Result<Vec<u8>> {
+        let path = self.path(id);
+        self.with_sftp(|sftp| {
+            let mut data = Vec::new();
+            sftp.open(&path)?.read_to_end(&mut data)?;
+            Ok(data)
+        })
+        .map_err(|e| match is_not_found(&e) {
+            true => Error::ChunkNotFound(*id),
+            false => e,
+        })
+    }
+
+    fn has(&self, id: &ContentId) -> Result<bool> {
+        let path = self.path(id);
+        match self.with_sftp(|sftp| Ok(sftp.stat(&path)?)) {
+            Ok(_) => Ok(true),
+            Err(e) if is_not_found(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn delete(&self, id: &ContentId) -> Result<()> {
+        let path = self.path(id);
+        match self.with_sftp(|sftp| Ok(sftp.unlink(&path)?)) {
+            Err(e) if !is_not_found(&e) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<ContentId>> {
+        self.with_sftp(|sftp| {
+            let mut ids = Vec::new();
+            for (dir, stat) in sftp.readdir(&self.config.root)? {
+                let name = dir.file_name().and_then(|name| name.to_str());
+                if !stat.is_dir() || name.is_none_or(|name| name.len() != 2) {
+                    continue;
+                }
+                ids.extend(
+                    sftp.readdir(&dir)?
+                        .iter()
+                        .filter_map(|(chunk, _)| parse_hex(chunk.file_name()?.to_str()?)),
+                );
+            }
+            Ok(ids)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::check_store;
+
+    fn config(root: &str) -> SftpConfig {
+        SftpConfig::new(
+            "localhost",
+            "user",
+            Path::new("id_ed25519"),
+            Path::new(root),
+        )
+    }
+
+    #[test]
+    fn lays_out_chunks_like_local_store() {
+        let store = SftpStore {
+            config: config("/srv/pigeonhole"),
+            sftp: Mutex::new(None),
+        };
+        assert_eq!(
+            store.path(&[0xab; 32]),
+            Path::new("/srv/pigeonhole/ab").join(hex(&[0xab; 32]))
+        );
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let not_found = Error::Ssh(ssh2::Error::new(ErrorCode::SFTP(NO_SUCH_FILE), "missing"));
+        assert!(is_not_found(&not_found));
+        assert!(!is_connection_error(&not_found));
+
+        let disconnected = Error::Ssh(ssh2::Error::new(ErrorCode::Session(-13), "disconnected"));
+        assert!(!is_not_found(&disconnected));
+        assert!(is_connection_error(&disconnected));
+
+        let unsupported = ssh2::Error::new(ErrorCode::SFTP(OP_UNSUPPORTED), "no fsync");
+        assert!(is_unsupported(&unsupported));
+        assert!(!is_unsupported(&ssh2::Error::new(
+            ErrorCode::SFTP(NO_SUCH_FILE),
+            "missing"
+        )));
+    }
+
+    #[test]
+    fn fails_to_connect_to_closed_port() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let config = SftpConfig {
+            host: "127.0.0.1".to_owned(),
+            port,
+            ..config("/")
+        };
+        assert!(SftpStore::new(config).is_err());
+    }
+
+    /// Runs against a real SSH server, such as a local sshd with the user's key in its
+    /// `authorized_keys` and its host key in the user's `known_hosts`:
+    ///
+    /// ```sh
+    /// PIGEONHOLE_SFTP_HOST=localhost PIGEONHOLE_SFTP_USER=$USER \
+    /// PIGEONHOLE_SFTP_KEY=~/.ssh/id_ed25519 PIGEONHOLE_SFTP_ROOT=/tmp \
+    /// cargo test sftp -- --ignored
+    /// ```
+    #[test]
+    #[ignore = "needs an SSH server"]
+    fn satisfies_store_contract_against_server() {
+        let env = |name: &str| std::env::var(format!("PIGEONHOLE_SFTP_{name}")).unwrap();
+        let root = Path::new(&env("ROOT")).join(Uuid::now_v7().to_string());
+        let config = SftpConfig::new(&env("HOST"), &env("USER"), Path::new(&env("KEY")), &root);
+        connect(&config).unwrap().mkdir(&root, 0o755).unwrap();
+
+        let store = SftpStore::new(config).unwrap();
+        check_store(&store);
+
+        // Many uploads share one session
+        for i in 0..50u8 {
+            store.put(&[i; 32], &[i]).unwrap();
+        }
+        assert_eq!(store.list().unwrap().len(), 52);
+    }
+}
@@ -0,0 +1,414 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use super::{
+    hex,
+    http::{self, HttpClient, Request, Response, RetryPolicy},
+    parse_hex, ChunkStore, ContentId,
+};
+use crate::error::{Error, Result};
+
+const PROPFIND_BODY: &[u8] = br#"<?xml version="1.0" encoding="utf-8"?><propfind xmlns="DAV:"><prop><resourcetype/></prop></propfind>"#;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct WebDavConfig {
+    /// URL of the collection to store chunks in, such as
+    /// `https://cloud.example.com/remote.php/dav/files/user/pigeonhole`
+    pub url: String,
+    pub username: String,
+    pub password: String,
+    pub retry: RetryPolicy,
+}
+
+impl WebDavConfig {
+    pub fn new(url: &str, username: &str, password: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            username: username.to_owned(),
+            password: password.to_owned(),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// Chunk store in a WebDAV collection, such as Nextcloud storage.
+///
+/// Chunks are stored as `<url>/chunks/<first byte in hex>/<content id in hex>`, with the fan-out
+/// collections created on first use. Servers are expected to only make a resource visible once
+/// its `PUT` completes, as Apache `mod_dav` and Nextcloud do.
+pub(crate) struct WebDavStore {
+    url: String,
+    authorization: String,
+    client: HttpClient,
+    /// Collections known to exist, to avoid a `MKCOL` before every upload
+    collections: Mutex<HashSet<String>>,
+}
+
+impl WebDavStore {
+    pub fn new(config: WebDavConfig) -> Self {
+        let credentials = format!("{}:{}", config.username, config.password);
+        Self {
+            url: config.url.trim_end_matches('/').to_owned(),
+            authorization: format!("Basic {}", STANDARD.encode(credentials)),
+            client: HttpClient::new(config.retry),
+            collections: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn chunks_url(&self) -> String {
+        format!("{}/chunks/", self.url)
+    }
+
+    fn fan_out_url(&self, id: &ContentId) -> String {
+        format!("{}{:02x}/", self.chunks_url(), id[0])
+    }
+
+    fn chunk_url(&self, id: &ContentId) -> String {
+        format!("{}{}", self.fan_out_url(id), hex(id))
+    }
+
+    fn send<'a>(
+        &self,
+        method: &'a str,
+        url: String,
+        headers: &[(&str, &str)],
+        body: &'a [u8],
+    ) -> Result<(Request<'a>, Response)> {
+        let mut request = Request {
+            method,
+            url,
+            headers: vec![("authorization".to_owned(), self.authorization.clone())],
+            body,
+        };
+        request.headers.extend(
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        );
+        let response = self.client.send(&request)?;
+        Ok((request, response))
+    }
+
+    /// Creates the collection at `url` and its parents below the store's URL.
+    fn create_collection(&self, url: &str) -> Result<()> {
+        if self.collections.lock().unwrap().contains(url) {
+            return Ok(());
+        }
+        for parent in [self.chunks_url(), url.to_owned()] {
+            match self.send("MKCOL", parent.clone(), &[], &[])? {
+                // 405 Method Not Allowed is returned for collections that already exist
+                (_, response) if response.is_success() || response.status == 405 => {
+                    self.collections.lock().unwrap().insert(parent);
+                }
+                (request, response) => return Err(response.error(&request)),
+            }
+        }
+        Ok(())
+    }
+
+    /// URLs of the members of the collection at `url`, or nothing if it does not exist.
+    fn list_collection(&self, url: &str) -> Result<Vec<String>> {
+        let (request, response) = self.send(
+            "PROPFIND",
+            url.to_owned(),
+            &[("depth", "1"), ("content-type", "application/xml")],
+            PROPFIND_BODY,
+        )?;
+        match response.status {
+            404 => return Ok(Vec::new()),
+            207 => {}
+            _ => return Err(response.error(&request)),
+        }
+
+        // The collection itself is listed along with its members, as an absolute path or URL
+        let path = url_path(url);
+        Ok(
+            http::xml_text(&String::from_utf8_lossy(&response.body), "href")
+                .into_iter()
+                .map(|href| http::uri_decode(url_path(&href)))
+                .filter(|href| {
+                    href.trim_end_matches('/') != http::uri_decode(path).trim_end_matches('/')
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Path of a URL, or the URL itself if it is already a path.
+fn url_path(url: &str) -> &str {
+    match url.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |start| &rest[start..]),
+        None => url,
+    }
+}
+
+fn last_segment(path: &str) -> &str {
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+}
+
+impl ChunkStore for WebDavStore {
+    fn put(&self, id: &ContentId, data: &[u8]) -> Result<()> {
+        let url = self.chunk_url(id);
+        let (request, response) = self.send("PUT", url.clone(), &[], data)?;
+        if response.is_success() {
+            return Ok(());
+        }
+        // 409 Conflict is returned when the parent collection does not exist
+        if response.status != 409 {
+            return Err(response.error(&request));
+        }
+
+        self.create_collection(&self.fan_out_url(id))?;
+        match self.send("PUT", url, &[], data)? {
+            (_, response) if response.is_success() => Ok(()),
+            (request, response) => Err(response.error(&request)),
+        }
+    }
+
+    fn get(&self, id: &ContentId) -> Result<Vec<u8>> {
+        match self.send("GET", self.chunk_url(id), &[], &[])? {
+            (_, response) if response.status == 404 => Err(Error::ChunkNotFound(*id)),
+            (_, response) if response.is_success() => Ok(response.body),
+            (request, response) => Err(response.error(&request)),
+        }
+    }
+
+    fn has(&self, id: &ContentId) -> Result<bool> {
+        match self.send("HEAD", self.chunk_url(id), &[], &[])? {
+            (_, response) if response.status == 404 => Ok(false),
+            (_, response) if response.is_success() => Ok(true),
+            (request, response) => Err(response.error(&request)),
+        }
+    }
+
+    fn delete(&self, id: &ContentId) -> Result<()> {
+        match self.send("DELETE", self.chunk_url(id), &[], &[])? {
+            (_, response) if response.status == 404 || response.is_success() => Ok(()),
+            (request, response) => Err(response.error(&request)),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<ContentId>> {
+        let mut ids = Vec::new();
+        for fan_out in self.list_collection(&self.chunks_url())? {
+            if last_segment(&fan_out).len() != 2 {
+                continue;
+            }
+            let url = format!("{}{}/", self.chunks_url(), last_segment(&fan_out));
+            ids.extend(
+                self.list_collection(&url)?
+                    .iter()
+                    .filter_map(|href| parse_hex(last_segment(href))),
+            );
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::store::{
+        test_server::{self, TestServer},
+        tests::check_store,
+    };
+
+    /// Resources of a fake WebDAV server by decoded path, with `None` for collections.
+    type Resources = Arc<Mutex<BTreeMap<String, Option<Vec<u8>>>>>;
+
+    fn fake_webdav(
+        resources: Resources,
+        failures: Arc<AtomicUsize>,
+    ) -> impl Fn(test_server::Request) -> test_server::Response {
+        let authorization = format!("Basic {}", STANDARD.encode("user:password"));
+        move |request| {
+            if request.header("authorization") != Some(&authorization) {
+                return test_server::Response::status(401);
+            }
+            if failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return test_server::Response::status(503);
+            }
+
+            let path = http::uri_decode(&request.path);
+            let path = path.trim_end_matches('/').to_owned();
+            let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            let mut resources = resources.lock().unwrap();
+            let parent_exists = parent.is_empty() || resources.get(parent) == Some(&None);
+
+            let status = match request.method.as_str() {
+                "MKCOL" if resources.contains_key(&path) => 405,
+                "MKCOL" | "PUT" if !parent_exists => 409,
+                "MKCOL" => {
+                    resources.insert(path, None);
+                    201
+                }
+                "PUT" => {
+                    resources.insert(path, Some(request.body));
+                    201
+                }
+                "GET" | "HEAD" => match resources.get(&path) {
+                    Some(Some(data)) => return test_server::Response::ok(data.clone()),
+                    _ => 404,
+                },
+                "DELETE" => match resources.remove(&path) {
+                    Some(_) => 204,
+                    None => 404,
+                },
+                "PROPFIND" if request.header("depth") == Some("1") => {
+                    if resources.get(&path) != Some(&None) {
+                        return test_server::Response::status(404);
+                    }
+                    let mut body =
+                        String::from(r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">"#);
+                    let members = resources.keys().filter(|member| {
+                        member.rsplit_once('/').map(|(parent, _)| parent) == Some(path.as_str())
+                    });
+                    for member in std::iter::once(&path).chain(members) {
+                        body.push_str(&format!(
+                            "<d:response><d:href>{}</d:href></d:response>",
+                            http::uri_encode(member, false)
+                        ));
+                    }
+                    body.push_str("</d:multistatus>");
+                    return test_server::Response {
+                        status: 207,
+                        ..test_server::Response::ok(body.into_bytes())
+                    };
+                }
+                _ => 405,
+            };
+            test_server::Response::status(status)
+        }
+    }
+
+    fn fake_store() -> (WebDavStore, Resources, Arc<AtomicUsize>) {
+        let resources: Resources = Arc::default();
+        resources
+            .lock()
+            .unwrap()
+            .insert("/dav/my files".to_owned(), None);
+        let failures = Arc::new(AtomicUsize::new(0));
+        let server = TestServer::start(fake_webdav(resources.clone(), failures.clone()));
+
+        let store = WebDavStore::new(WebDavConfig {
+            retry: RetryPolicy {
+                attempts: 3,
+                backoff: Duration::from_millis(1),
+                ..RetryPolicy::default()
+            },
+            ..WebDavConfig::new(&server.url("/dav/my%20files/"), "user", "password")
+        });
+        (store, resources, failures)
+    }
+
+    #[test]
+    fn satisfies_store_contract() {
+        let (store, _, _) = fake_store();
+        check_store(&store);
+    }
+
+    #[test]
+    fn creates_fan_out_collections() {
+        let (store, resources, _) = fake_store();
+        store.put(&[0xab; 32], b"data").unwrap();
+        store.put(&[0xab; 32], b"data").unwrap();
+        store.put(&[0xcd; 32], b"data").unwrap();
+
+        let resources = resources.lock().unwrap();
+        assert_eq!(resources.get("/dav/my files/chunks"), Some(&None));
+        assert_eq!(resources.get("/dav/my files/chunks/ab"), Some(&None));
+        assert_eq!(
+            resources.get(&format!("/dav/my files/chunks/ab/{}", hex(&[0xab; 32]))),
+            Some(&Some(b"data".to_vec()))
+        );
+        assert!(resources.contains_key(&format!("/dav/my files/chunks/cd/{}", hex(&[0xcd; 32]))));
+    }
+
+    #[test]
+    fn lists_chunks_across_collections() {
+        let (store, resources, _) = fake_store();
+        assert!(store.list().unwrap().is_empty());
+
+        let mut ids = vec![[0x01; 32], [0x02; 32], [0xff; 32]];
+        for id in &ids {
+            store.put(id, b"data").unwrap();
+        }
+        resources.lock().unwrap().insert(
+            "/dav/my files/chunks/01/not-a-chunk".to_owned(),
+            Some(vec![]),
+        );
+
+        let mut listed = store.list().unwrap();
+        listed.sort();
+        ids.sort();
+        assert_eq!(listed, ids);
+    }
+
+    #[test]
+    fn retries_unavailable_server() {
+        let (store, _, failures) = fake_store();
+        failures.store(2, Ordering::SeqCst);
+        store.put(&[1; 32], b"data").unwrap();
+
+        failures.store(2, Ordering::SeqCst);
+        assert_eq!(store.get(&[1; 32]).unwrap(), b"data");
+
+        failures.store(3, Ordering::SeqCst);
+        assert!(matches!(store.get(&[1; 32]), Err(Error::Remote(_))));
+    }
+
+    #[test]
+    fn rejects_wrong_credentials() {
+        let (store, _, _) = fake_store();
+        let store = WebDavStore::new(WebDavConfig::new(&store.url, "user", "wrong"));
+        assert!(matches!(
+            store.put(&[1; 32], b"data"),
+            Err(Error::Remote(_))
+        ));
+    }
+
+    #[test]
+    fn parses_hrefs() {
+        assert_eq!(url_path("https://host:8080/a/b/"), "/a/b/");
+        assert_eq!(url_path("https://host"), "/");
+        assert_eq!(url_path("/a/b"), "/a/b");
+        assert_eq!(last_segment("/a/b/"), "b");
+    }
+
+    /// Runs against a real WebDAV server, such as one started with
+    /// `rclone serve webdav /tmp/webdav --user user --pass password`:
+    ///
+    /// ```sh
+    /// PIGEONHOLE_WEBDAV_URL=http://localhost:8080 PIGEONHOLE_WEBDAV_USERNAME=user \
+    /// PIGEONHOLE_WEBDAV_PASSWORD=password cargo test webdav -- --ignored
+    /// ```
+    #[test]
+    #[ignore = "needs a WebDAV server"]
+    fn satisfies_store_contract_against_server() {
+        let env = |name: &str| std::env::var(format!("PIGEONHOLE_WEBDAV_{name}")).unwrap();
+        let url = format!(
+            "{}/{}",
+            env("URL").trim_end_matches('/'),
+            uuid::Uuid::now_v7()
+        );
+        let store = WebDavStore::new(WebDavConfig::new(&url, &env("USERNAME"), &env("PASSWORD")));
+        store.send("MKCOL", format!("{url}/"), &[], &[]).unwrap();
+        check_store(&store);
+    }
+}
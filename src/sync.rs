@@ -0,0 +1,1465 @@
+//! Two-way sync between a local folder and the snapshots in a vault.
+//!
+//! Every path is looked at in three states: the local folder, the newest sync snapshot in the
+//! vault (the remote), and the base, which is what both looked like when this device last synced.
+//! The base is kept in a state file outside the folder, one per device and folder. A side changed
+//! a path if it differs from the base. Changes made on one side only are applied to the other:
+//! local changes are uploaded and recorded in a new sync snapshot, remote changes are downloaded
+//! into the folder. A path both sides changed in different ways is a conflict and left alone on
+//! both sides, and its base is kept so it is reported again on the next sync.
+//!
+//! Local files are compared by the hash of their contents, which is only computed again for files
+//! a [`FileIndex`] does not know unchanged, and remote files by their [`FileManifest`], which stays
+//! the same until the file is uploaded again. A file deleted in one place and added with the same
+//! contents in another is a rename, and is moved instead of being transferred again.
+//!
+//! The remote is the head of the sync snapshots, the one no other names as a parent, so it does not
+//! depend on the clocks of the devices. Every sync snapshot names the heads it was made from, and
+//! if devices committed concurrently the newest head is synced with while the changes only the
+//! others have are uploaded again by the devices that made them.
+//!
+//! Every entry in a sync snapshot carries a [`Version`], so a remote that went back to a version
+//! this device has already seen, such as after the newest snapshot was forgotten, is not mistaken
+//! for a remote change. Sync snapshots also record the paths deleted with the version of the
+//! deletion, and a path missing from the remote without one is taken for such an older remote as
+//! well, so it is uploaded again rather than deleted.
+//!
+//! Conflicts are resolved as the [`ConflictResolution`] in the options says, except that an edit
+//! always wins over a deletion and a directory conflicting with anything else is left alone.
+//! Uploads resolving a conflict get a version including both sides, so other devices take them as
+//! ordinary changes. Text files can also be merged with the base version first, see
+//! [`crate::merge`].
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    error::{Error, Result},
+    file::FileManifest,
+    index::FileIndex,
+    lock::VaultLock,
+    merge,
+    metadata::{Metadata, MetadataPolicy, Timestamp},
+    path, retention,
+    snapshot::{Snapshot, SnapshotOptions},
+    store,
+    tree::{DeletedEntry, TreeEntry, TreeEntryKind, TreeManifest},
+    vault::Vault,
+    version::{Causality, Version},
+};
+
+/// Tag of the snapshots made by sync, as opposed to snapshots taken for other purposes
+pub(crate) const SYNC_TAG: &str = "sync";
+
+/// Prefix of files being downloaded, which are never synced themselves
+const DOWNLOAD_PREFIX: &str = ".pigeonhole-download-";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Content {
+    /// SHA-256 of the file's contents
+    File([u8; 32]),
+    Directory,
+    Symlink(#[serde(with = "crate::path")] PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct BaseEntry {
+    content: Content,
+    /// Manifest the file is stored under, `None` for anything but files
+    manifest: Option<FileManifest>,
+    /// Remote version the entry was last in sync with
+    version: Option<Version>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SyncState {
+    /// Sync snapshot the folder was last in sync with
+    snapshot: Option<Uuid>,
+    entries: Vec<StateEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateEntry {
+    #[serde(with = "crate::path")]
+    path: PathBuf,
+    base: BaseEntry,
+}
+
+/// How a device syncs a folder.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct SyncOptions {
+    /// Name of this device, recorded in the snapshots it makes
+    pub device: String,
+    /// Metadata uploaded with local changes and applied to downloaded ones
+    pub policy: MetadataPolicy,
+    pub resolution: ConflictResolution,
+    /// Merges edits both sides made to a text file when they do not overlap, before resorting to
+    /// `resolution`
+    pub merge_text: bool,
+}
+
+/// Which version of a file both sides changed is kept.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) enum ConflictResolution {
+    /// Keeps the remote version at the path and moves the local version to a conflict copy next
+    /// to it, which is uploaded too
+    #[default]
+    KeepBoth,
+    /// Keeps the version modified last, or both if the times are equal or unknown
+    PreferNewest,
+    /// Keeps the version made on the named device, or both if neither was
+    PreferDevice(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RemoteChange {
+    Unchanged,
+    Changed,
+    /// Back at a version the base includes
+    Older,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Local,
+    Remote,
+}
+
+/// Something a sync did, with paths relative to the folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SyncAction {
+    Upload(PathBuf),
+    Download(PathBuf),
+    DeleteLocal(PathBuf),
+    DeleteRemote(PathBuf),
+    RenameLocal {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    RenameRemote {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Changed on both sides and merged, with the result uploaded
+    Merge(PathBuf),
+    /// Changed on both sides, with the local version moved to `copy`
+    ConflictCopy {
+        path: PathBuf,
+        copy: PathBuf,
+    },
+    /// Changed on both sides and left alone
+    Conflict(PathBuf),
+}
+
+impl SyncAction {
+    /// The path the action leaves something at, or removes it from
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Upload(path)
+            | Self::Download(path)
+            | Self::DeleteLocal(path)
+            | Self::DeleteRemote(path)
+            | Self::Merge(path)
+            | Self::Conflict(path) => path,
+            Self::RenameLocal { to, .. } | Self::RenameRemote { to, .. } => to,
+            Self::ConflictCopy { copy, .. } => copy,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct SyncReport {
+    /// Sync snapshot the folder is now in sync with, `None` while nothing was ever synced
+    pub snapshot: Option<Uuid>,
+    /// Ordered by path
+    pub actions: Vec<SyncAction>,
+}
+
+type Base = BTreeMap<PathBuf, BaseEntry>;
+
+impl SyncState {
+    fn load(path: &Path) -> Result<(Option<Uuid>, Base)> {
+        let state: Self = match fs::read(path) {
+            Ok(data) => bincode::deserialize(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let base = state
+            .entries
+            .into_iter()
+            .map(|entry| (entry.path, entry.base))
+            .collect();
+        Ok((state.snapshot, base))
+    }
+
+    fn save(path: &Path, snapshot: Option<Uuid>, base: &Base) -> Result<()> {
+        let state = Self {
+            snapshot,
+            entries: base
+                .iter()
+                .map(|(path, base)| StateEntry {
+                    path: path.clone(),
+                    base: base.clone(),
+                })
+                .collect(),
+        };
+        crate::vault::write_atomic(path, &bincode::serialize(&state)?)
+    }
+}
+
+/// The sync snapshots among `snapshots` that no other sync snapshot names as a parent, in the same
+/// order. There is more than one only if devices committed concurrently.
+pub(crate) fn sync_heads(snapshots: Vec<Snapshot>) -> Vec<Snapshot> {
+    let synced = snapshots
+        .into_iter()
+        .filter(|snapshot| snapshot.tags.iter().any(|tag| tag == SYNC_TAG))
+        .collect();
+    Snapshot::heads(synced)
+}
+
+/// The sync snapshot devices sync with, the newest head if there are several, if there is any.
+#[cfg(test)]
+pub(crate) fn latest_sync_snapshot(vault: &Vault) -> Result<Option<Snapshot>> {
+    Ok(sync_heads(Snapshot::list(vault)?).pop())
+}
+
+/// Syncs the folder `root` with `vault`, keeping this device's base in the file `state_path`,
+/// which must not be inside `root`.
+///
+/// Remote changes are applied to the folder first. If another device made a sync snapshot while
+/// local changes were uploaded, nothing is committed and [`Error::ConcurrentSync`] is returned;
+/// syncing again merges both.
+///
+/// Fails for a vault keeping its chunks in another store, which only serves a single device, see
+/// [`crate::vault`].
+pub(crate) fn sync(
+    vault: &Vault,
+    root: &Path,
+    state_path: &Path,
+    options: &SyncOptions,
+) -> Result<SyncReport> {
+    if vault.config().store.is_some() {
+        return Err(Error::NeedsSharedVault("sync"));
+    }
+    let _lock = VaultLock::shared(vault)?;
+    let (synced, mut base) = SyncState::load(state_path)?;
+    let heads: Vec<Uuid> = sync_heads(Snapshot::list(vault)?)
+        .iter()
+        .map(|head| head.id)
+        .collect();
+    let head = heads.last().copied();
+    let remote_tree = match head {
+        Some(id) => Snapshot::load(vault, &id)?.tree(vault)?,
+        None => TreeManifest::default(),
+    };
+    let remote: BTreeMap<PathBuf, TreeEntry> = remote_tree
+        .entries()
+        .iter()
+        .map(|entry| (entry.path.clone(), entry.clone()))
+        .collect();
+    let deleted: BTreeMap<PathBuf, Version> = remote_tree
+        .deleted()
+        .iter()
+        .map(|entry| (entry.path.clone(), entry.version.clone()))
+        .collect();
+    // Version of the remote entry or deletion at a path
+    let remote_version = |path: &Path| {
+        remote
+            .get(path)
+            .and_then(|entry| entry.version.as_ref())
+            .or_else(|| deleted.get(path))
+    };
+    let mut index = FileIndex::open(vault, root)?;
+    let mut local = scan(root, &mut index)?;
+    index.retain(|path| local.contains_key(path));
+    index.save()?;
+
+    let paths: BTreeSet<PathBuf> = base
+        .keys()
+        .chain(local.keys())
+        .chain(remote.keys())
+        .cloned()
+        .collect();
+    let mut report = SyncReport::default();
+    let (mut uploads, mut remote_deletes, mut downloads, mut local_deletes) =
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let mut conflicts = Vec::new();
+    for path in paths {
+        path::check_relative(&path)?;
+        let change = remote_change(remote.get(&path), deleted.get(&path), base.get(&path));
+        // An older remote version is replaced by the one this device has
+        let local_changed = local.get(&path) != base.get(&path).map(|entry| &entry.content)
+            || change == RemoteChange::Older;
+        let remote_changed = change == RemoteChange::Changed;
+        match (local_changed, remote_changed) {
+            (false, false) => {}
+            (true, false) if local.contains_key(&path) => uploads.push(path),
+            (true, false) => remote_deletes.push(path),
+            (false, true) if remote.contains_key(&path) => downloads.push(path),
+            (false, true) => local_deletes.push(path),
+            (true, true) => match local.get(&path) {
+                Some(content) if same_content(vault, content, remote.get(&path))? => {
+                    base.insert(path.clone(), base_entry(content, &remote[&path]));
+                }
+                None if !remote.contains_key(&path) => {
+                    base.remove(&path);
+                }
+                _ => conflicts.push(path),
+            },
+        }
+    }
+
+    let mut unresolved = Vec::new();
+    for path in conflicts {
+        if options.merge_text {
+            let (ours, ancestor) = (local.get(&path), base.get(&path));
+            let merged = merge_text(vault, root, &path, ours, ancestor, &remote)?;
+            if let Some(merged) = merged {
+                fs::write(root.join(&path), &merged)?;
+                local.insert(path.clone(), Content::File(Sha256::digest(&merged).into()));
+                report.actions.push(SyncAction::Merge(path.clone()));
+                uploads.push(path);
+                continue;
+            }
+        }
+        let remote_entry = remote.get(&path);
+        match (
+            local.get(&path).cloned(),
+            remote_entry.map(|entry| &entry.kind),
+        ) {
+            (Some(Content::Directory), _) | (_, Some(TreeEntryKind::Directory)) => {
+                unresolved.push(path)
+            }
+            (Some(_), None) => uploads.push(path),
+            (None, _) => downloads.push(path),
+            (Some(content), Some(_)) => match winner(root, &path, &remote[&path], options)? {
+                Some(Side::Local) => uploads.push(path),
+                Some(Side::Remote) => downloads.push(path),
+                None => {
+                    let copy = conflict_copy(&path, &options.device, |copy| {
+                        local.contains_key(copy)
+                            || remote.contains_key(copy)
+                            || base.contains_key(copy)
+                            || fs::symlink_metadata(root.join(copy)).is_ok()
+                    });
+                    fs::rename(root.join(&path), root.join(&copy))?;
+                    local.remove(&path);
+                    local.insert(copy.clone(), content);
+                    report.actions.push(SyncAction::ConflictCopy {
+                        path: path.clone(),
+                        copy: copy.clone(),
+                    });
+                    uploads.push(copy);
+                    downloads.push(path);
+                }
+            },
+        }
+    }
+    // Nothing below a conflict left alone is touched either, as it may not fit either side
+    for list in [
+        &mut uploads,
+        &mut remote_deletes,
+        &mut downloads,
+        &mut local_deletes,
+    ] {
+        list.retain(|path| !unresolved.iter().any(|conflict| path.starts_with(conflict)));
+    }
+    report
+        .actions
+        .extend(unresolved.into_iter().map(SyncAction::Conflict));
+
+    let local_renames = match_renames(&mut local_deletes, &mut downloads, &base, |from, to| {
+        remote_unchanged(remote.get(to), Some(from))
+    });
+    let remote_renames = match_renames(&mut remote_deletes, &mut uploads, &base, |from, to| {
+        local.get(to) == Some(&from.content)
+    });
+
+    // Remote changes, creating parents before their contents and deleting contents before their
+    // parents
+    let mut created: BTreeMap<&PathBuf, Option<&PathBuf>> =
+        downloads.iter().map(|to| (to, None)).collect();
+    created.extend(local_renames.iter().map(|(from, to)| (to, Some(from))));
+    for (&path, from) in &created {
+        // Earlier downloads may have put symlinks where the remote has directories
+        path::check_no_symlinks(root, path)?;
+        if let Some(from) = from {
+            path::check_no_symlinks(root, from)?;
+        }
+        let entry = &remote[path];
+        let target = root.join(path);
+        let content = match *from {
+            Some(from) => {
+                fs::rename(root.join(from), &target)?;
+                report.actions.push(SyncAction::RenameLocal {
+                    from: from.clone(),
+                    to: path.clone(),
+                });
+                base.remove(from).map(|entry| entry.content)
+            }
+            None => None,
+        };
+        let content = match content {
+            Some(content) => content,
+            None => {
+                report.actions.push(SyncAction::Download(path.clone()));
+                download(vault, &entry.kind, &target)?
+            }
+        };
+        base.insert(path.clone(), base_entry(&content, entry));
+    }
+    for path in local_deletes.iter().rev() {
+        path::check_no_symlinks(root, path)?;
+        match remove(&root.join(path)) {
+            Err(Error::Io { source }) if source.kind() == io::ErrorKind::DirectoryNotEmpty => {
+                // Holds something new, so the directory is uploaded again instead
+                uploads.push(path.clone());
+                continue;
+            }
+            result => result?,
+        }
+        base.remove(path);
+        report.actions.push(SyncAction::DeleteLocal(path.clone()));
+    }
+    for &path in created.keys().rev() {
+        remote[path]
+            .metadata
+            .apply(&root.join(path), &options.policy)?;
+    }
+    if uploads.is_empty() && remote_deletes.is_empty() && remote_renames.is_empty() {
+        SyncState::save(state_path, head, &base)?;
+        report.snapshot = head;
+        report.actions.sort_by(|a, b| a.path().cmp(b.path()));
+        return Ok(report);
+    }
+    // The folder has the remote changes now, whether or not committing succeeds
+    SyncState::save(state_path, synced, &base)?;
+
+    // Local changes, recorded in the base only once committed
+    let mut tree = remote.clone();
+    let mut tree_deleted = deleted.clone();
+    let mut settled = Vec::new();
+    for (from, to) in &remote_renames {
+        let manifest = base[from]
+            .manifest
+            .clone()
+            .ok_or(Error::IncompleteManifest)?;
+        let entry = TreeEntry {
+            path: to.clone(),
+            kind: TreeEntryKind::File(manifest),
+            metadata: Metadata::capture(&root.join(to), &options.policy)?,
+            version: Some(Version::next(
+                base[from].version.as_ref(),
+                remote_version(to),
+                &options.device,
+            )),
+        };
+        tree.remove(from);
+        tree_deleted.insert(
+            from.clone(),
+            Version::next(
+                base[from].version.as_ref(),
+                remote_version(from),
+                &options.device,
+            ),
+        );
+        tree_deleted.remove(to);
+        settled.push((from.clone(), None));
+        settled.push((to.clone(), Some(base_entry(&local[to], &entry))));
+        tree.insert(to.clone(), entry);
+        report.actions.push(SyncAction::RenameRemote {
+            from: from.clone(),
+            to: to.clone(),
+        });
+    }
+    for path in &uploads {
+        let content = &local[path];
+        let kind = match content {
+            Content::File(_) => TreeEntryKind::File(store::put_file(
+                vault.store(),
+                vault.data_prk(),
+                io::BufReader::new(fs::File::open(root.join(path))?),
+            )?),
+            Content::Directory => TreeEntryKind::Directory,
+            Content::Symlink(target) => TreeEntryKind::Symlink(target.clone()),
+        };
+        let entry = TreeEntry {
+            path: path.clone(),
+            kind,
+            metadata: Metadata::capture(&root.join(path), &options.policy)?,
+            version: Some(Version::next(
+                base.get(path).and_then(|entry| entry.version.as_ref()),
+                remote_version(path),
+                &options.device,
+            )),
+        };
+        tree_deleted.remove(path);
+        settled.push((path.clone(), Some(base_entry(content, &entry))));
+        tree.insert(path.clone(), entry);
+        report.actions.push(SyncAction::Upload(path.clone()));
+    }
+    for path in remote_deletes.iter().rev() {
+        // A directory still holding something, such as a conflicting file, has to stay
+        if tree
+            .range(path.clone()..)
+            .nth(1)
+            .is_some_and(|(child, _)| child.starts_with(path))
+        {
+            continue;
+        }
+        tree.remove(path);
+        let version = Version::next(
+            base.get(path).and_then(|entry| entry.version.as_ref()),
+            remote_version(path),
+            &options.device,
+        );
+        tree_deleted.insert(path.clone(), version);
+        settled.push((path.clone(), None));
+        report.actions.push(SyncAction::DeleteRemote(path.clone()));
+    }
+
+    if !sync_heads(Snapshot::list(vault)?)
+        .iter()
+        .map(|head| head.id)
+        .eq(heads.iter().copied())
+    {
+        return Err(Error::ConcurrentSync);
+    }
+    let deleted_entries = tree_deleted
+        .into_iter()
+        .map(|(path, version)| DeletedEntry { path, version });
+    let snapshot = Snapshot::commit(
+        vault,
+        &TreeManifest::from_entries(tree.into_values()).with_deleted(deleted_entries),
+        SnapshotOptions {
+            device: options.device.clone(),
+            parents: heads,
+            tags: vec![SYNC_TAG.to_owned()],
+        },
+    )?;
+    for (path, entry) in settled {
+        match entry {
+            Some(entry) => base.insert(path, entry),
+            None => base.remove(&path),
+        };
+    }
+    SyncState::save(state_path, Some(snapshot.id), &base)?;
+    report.snapshot = Some(snapshot.id);
+    report.actions.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(report)
+}
+
+/// Pairs up deleted files with added ones that `same_file` says have the same contents, removing
+/// both from their lists and returning them as `(from, to)`.
+fn match_renames(
+    deleted: &mut Vec<PathBuf>,
+    added: &mut Vec<PathBuf>,
+    base: &Base,
+    same_file: impl Fn(&BaseEntry, &PathBuf) -> bool,
+) -> Vec<(PathBuf, PathBuf)> {
+    let mut renames = Vec::new();
+    deleted.retain(|from| {
+        let Some(
+            entry @ BaseEntry {
+                content: Content::File(_),
+                ..
+            },
+        ) = base.get(from)
+        else {
+            return true;
+        };
+        let found = added
+            .iter()
+            .position(|to| !base.contains_key(to) && same_file(entry, to));
+        match found {
+            Some(index) => {
+                renames.push((from.clone(), added.remove(index)));
+                false
+            }
+            None => true,
+        }
+    });
+    renames
+}
+
+fn base_entry(content: &Content, remote: &TreeEntry) -> BaseEntry {
+    BaseEntry {
+        content: content.clone(),
+        manifest: match &remote.kind {
+            TreeEntryKind::File(manifest) => Some(manifest.clone()),
+            _ => None,
+        },
+        version: remote.version.clone(),
+    }
+}
+
+/// How the remote `remote` or deletion `deleted` of a path changed since `base`.
+fn remote_change(
+    remote: Option<&TreeEntry>,
+    deleted: Option<&Version>,
+    base: Option<&BaseEntry>,
+) -> RemoteChange {
+    if remote_unchanged(remote, base) {
+        return RemoteChange::Unchanged;
+    }
+    let remote_version = match (remote, deleted) {
+        (Some(entry), _) => entry.version.as_ref(),
+        (None, Some(deleted)) => Some(deleted),
+        // Missing without having been deleted, as in a remote from before the path was added
+        (None, None) => return RemoteChange::Older,
+    };
+    let versions = (
+        remote_version,
+        base.and_then(|entry| entry.version.as_ref()),
+    );
+    match versions {
+        (Some(remote), Some(base))
+            if matches!(
+                remote.vector.compare(&base.vector),
+                Causality::Before | Causality::Equal
+            ) =>
+        {
+            RemoteChange::Older
+        }
+        _ => RemoteChange::Changed,
+    }
+}
+
+/// Merges the local and remote versions of a text file with the version both were last in sync
+/// with, if all three are text and the changes do not overlap.
+fn merge_text(
+    vault: &Vault,
+    root: &Path,
+    path: &Path,
+    local: Option<&Content>,
+    base: Option<&BaseEntry>,
+    remote: &BTreeMap<PathBuf, TreeEntry>,
+) -> Result<Option<Vec<u8>>> {
+    let (Some(Content::File(_)), Some(ancestor), Some(TreeEntryKind::File(theirs))) = (
+        local,
+        base.and_then(|entry| entry.manifest.as_ref()),
+        remote.get(path).map(|entry| &entry.kind),
+    ) else {
+        return Ok(None);
+    };
+    let ours = root.join(path);
+    if [ancestor.size(), theirs.size(), fs::metadata(&ours)?.len()]
+        .into_iter()
+        .any(|size| size > merge::MAX_MERGE_SIZE)
+    {
+        return Ok(None);
+    }
+
+    let mut ancestor_data = Vec::new();
+    // Chunks no snapshot references any more may have been collected, which leaves nothing to
+    // merge with
+    if store::get_file(
+        vault.store(),
+        vault.data_prk(),
+        ancestor,
+        &mut ancestor_data,
+    )
+    .is_err()
+    {
+        return Ok(None);
+    }
+    let mut theirs_data = Vec::new();
+    store::get_file(vault.store(), vault.data_prk(), theirs, &mut theirs_data)?;
+    Ok(merge::merge(&ancestor_data, &fs::read(ours)?, &theirs_data))
+}
+
+/// Which side of a conflict between two files `resolution` keeps, `None` for both.
+fn winner(
+    root: &Path,
+    path: &Path,
+    remote: &TreeEntry,
+    options: &SyncOptions,
+) -> Result<Option<Side>> {
+    Ok(match &options.resolution {
+        ConflictResolution::KeepBoth => None,
+        ConflictResolution::PreferNewest => {
+            let metadata = fs::symlink_metadata(root.join(path))?;
+            let local = Timestamp::from(FileTime::from_last_modification_time(&metadata));
+            match remote.metadata.modified.map(|remote| local.cmp(&remote)) {
+                Some(std::cmp::Ordering::Greater) => Some(Side::Local),
+                Some(std::cmp::Ordering::Less) => Some(Side::Remote),
+                _ => None,
+            }
+        }
+        ConflictResolution::PreferDevice(device) if *device == options.device => Some(Side::Local),
+        ConflictResolution::PreferDevice(device) => remote
+            .version
+            .as_ref()
+            .is_some_and(|version| version.device == *device)
+            .then_some(Side::Remote),
+    })
+}
+
+/// The first name next to `path` that is not `taken`, for the local version of a conflicting
+/// file, such as `notes (conflict from laptop 2024-05-01).txt`.
+fn conflict_copy(path: &Path, device: &str, taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let (year, month, day) = retention::civil_date(retention::days(Timestamp::now()));
+    (1..)
+        .map(|attempt| {
+            let mut name = path.file_stem().unwrap_or_default().to_owned();
+            name.push(format!(
+                " (conflict from {device} {year:04}-{month:02}-{day:02}"
+            ));
+            if attempt > 1 {
+                name.push(format!(" {attempt}"));
+            }
+            name.push(")");
+            if let Some(extension) = path.extension() {
+                name.push(".");
+                name.push(extension);
+            }
+            path.with_file_name(name)
+        })
+        .find(|copy| !taken(copy))
+        .expect("some name is free")
+}
+
+fn remote_unchanged(remote: Option<&TreeEntry>, base: Option<&BaseEntry>) -> bool {
+    let (Some(remote), Some(base)) = (remote, base) else {
+        return remote.is_none() && base.is_none();
+    };
+    match (&remote.kind, &base.content) {
+        (TreeEntryKind::File(manifest), Content::File(_)) => {
+            base.manifest.as_ref() == Some(manifest)
+        }
+        (TreeEntryKind::Directory, Content::Directory) => true,
+        (TreeEntryKind::Symlink(remote), Content::Symlink(base)) => remote == base,
+        _ => false,
+    }
+}
+
+/// Whether both sides changed a path to the same thing, fetching the remote file to compare it.
+fn same_content(vault: &Vault, local: &Content, remote: Option<&TreeEntry>) -> Result<bool> {
+    Ok(match (local, remote.map(|entry| &entry.kind)) {
+        (Content::File(hash), Some(TreeEntryKind::File(manifest))) => {
+            let mut hasher = Sha256::new();
+            store::get_file(vault.store(), vault.data_prk(), manifest, &mut hasher)?;
+            <[u8; 32]>::from(hasher.finalize()) == *hash
+        }
+        (Content::Directory, Some(TreeEntryKind::Directory)) => true,
+        (Content::Symlink(local), Some(TreeEntryKind::Symlink(remote))) => local == remote,
+        _ => false,
+    })
+}
+
+fn scan(root: &Path, index: &mut FileIndex) -> Result<BTreeMap<PathBuf, Content>> {
+    let mut contents = BTreeMap::new();
+    scan_dir(root, Path::new(""), index, &mut contents)?;
+    Ok(contents)
+}
+
+fn scan_dir(
+    root: &Path,
+    relative: &Path,
+    index: &mut FileIndex,
+    contents: &mut BTreeMap<PathBuf, Content>,
+) -> Result<()> {
+    for entry in path::read_dir_sorted(&root.join(relative))? {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(DOWNLOAD_PREFIX)
+        {
+            continue;
+        }
+        let path = relative.join(entry.file_name());
+        if let Some(content) = scan_entry(root, &path, index)? {
+            if content == Content::Directory {
+                scan_dir(root, &path, index, contents)?;
+            }
+            contents.insert(path, content);
+        }
+    }
+    Ok(())
+}
+
+/// What is at `path` below `root`, or `None` for special files such as sockets, which are not
+/// synced.
+fn scan_entry(root: &Path, path: &Path, index: &mut FileIndex) -> Result<Option<Content>> {
+    let full_path = root.join(path);
+    let file_type = fs::symlink_metadata(&full_path)?.file_type();
+    Ok(if file_type.is_dir() {
+        Some(Content::Directory)
+    } else if file_type.is_symlink() {
+        Some(Content::Symlink(fs::read_link(&full_path)?))
+    } else if file_type.is_file() {
+        Some(Content::File(index.hash(root, path)?))
+    } else {
+        None
+    })
+}
+
+fn hash_file(path: &Path) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Creates what `kind` describes at `target`, replacing whatever is in the way, and returns what
+/// is there now. Files are written next to `target` and renamed into place, so an interrupted
+/// download leaves the old file.
+fn download(vault: &Vault, kind: &TreeEntryKind, target: &Path) -> Result<Content> {
+    match kind {
+        TreeEntryKind::Directory => {
+            if fs::symlink_metadata(target).is_ok_and(|metadata| !metadata.is_dir()) {
+                remove(target)?;
+            }
+            fs::create_dir_all(target)?;
+            Ok(Content::Directory)
+        }
+        TreeEntryKind::Symlink(link_target) => {
+            remove(target)?;
+            std::os::unix::fs::symlink(link_target, target)?;
+            Ok(Content::Symlink(link_target.clone()))
+        }
+        TreeEntryKind::File(manifest) => {
+            let name = format!("{DOWNLOAD_PREFIX}{}", Uuid::now_v7());
+            let tmp = target.with_file_name(name);
+            let result = (|| -> Result<()> {
+                let mut writer = io::BufWriter::new(fs::File::create(&tmp)?);
+                store::get_file(vault.store(), vault.data_prk(), manifest, &mut writer)?;
+                writer.flush()?;
+                if fs::symlink_metadata(target).is_ok_and(|metadata| metadata.is_dir()) {
+                    remove(target)?;
+                }
+                Ok(fs::rename(&tmp, target)?)
+            })();
+            if result.is_err() {
+                let _ = fs::remove_file(&tmp);
+            }
+            result?;
+            Ok(Content::File(hash_file(target)?))
+        }
+    }
+}
+
+/// Removes the file, symlink or empty directory at `path`, if there is anything.
+fn remove(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::VaultOptions;
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    struct Device {
+        vault: Vault,
+        root: PathBuf,
+        state: PathBuf,
+        options: SyncOptions,
+    }
+
+    impl Device {
+        fn new(dir: &tempfile::TempDir, name: &str) -> Self {
+            let vault_root = dir.path().join("vault");
+            if !vault_root.exists() {
+                Vault::init(&vault_root, "password", &VaultOptions::default()).unwrap();
+            }
+            let root = dir.path().join(name);
+            fs::create_dir_all(&root).unwrap();
+            Self {
+                vault: Vault::open(&vault_root, "password").unwrap(),
+                root,
+                state: dir.path().join(format!("{name}.state")),
+                options: SyncOptions {
+                    device: name.to_owned(),
+                    ..SyncOptions::default()
+                },
+            }
+        }
+
+        fn sync(&self) -> SyncReport {
+            sync(&self.vault, &self.root, &self.state, &self.options).unwrap()
+        }
+
+        fn write(&self, path: &str, contents: &[u8]) {
+            let path = self.root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        fn read(&self, path: &str) -> Option<Vec<u8>> {
+            fs::read(self.root.join(path)).ok()
+        }
+
+        fn chunks(&self) -> usize {
+            self.vault.store().list().unwrap().len()
+        }
+
+        fn remote_entry(&self, path: &str) -> TreeEntry {
+            let snapshot = latest_sync_snapshot(&self.vault).unwrap().unwrap();
+            let tree = snapshot.tree(&self.vault).unwrap();
+            tree.get(Path::new(path)).unwrap().clone()
+        }
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    fn uploads(paths: &[&str]) -> Vec<SyncAction> {
+        self::paths(paths)
+            .into_iter()
+            .map(SyncAction::Upload)
+            .collect()
+    }
+
+    fn downloads(paths: &[&str]) -> Vec<SyncAction> {
+        self::paths(paths)
+            .into_iter()
+            .map(SyncAction::Download)
+            .collect()
+    }
+
+    #[test]
+    fn syncs_new_files_between_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let desktop = Device::new(&dir, "desktop");
+        assert_eq!(laptop.sync(), SyncReport::default());
+
+        laptop.write("docs/lorem_ipsum", &fs::read(PATH).unwrap());
+        laptop.write("top", b"top");
+        std::os::unix::fs::symlink("docs/lorem_ipsum", laptop.root.join("link")).unwrap();
+        let report = laptop.sync();
+        assert_eq!(
+            report.actions,
+            uploads(&["docs", "docs/lorem_ipsum", "link", "top"])
+        );
+        let snapshot = Snapshot::load(&laptop.vault, &report.snapshot.unwrap()).unwrap();
+        assert_eq!(snapshot.device, "laptop");
+        assert_eq!(snapshot.tags, [SYNC_TAG]);
+
+        let report = desktop.sync();
+        assert_eq!(
+            report.actions,
+            downloads(&["docs", "docs/lorem_ipsum", "link", "top"])
+        );
+        assert_eq!(report.snapshot, Some(snapshot.id));
+        assert_eq!(
+            desktop.read("docs/lorem_ipsum"),
+            laptop.read("docs/lorem_ipsum")
+        );
+        assert_eq!(desktop.read("top").unwrap(), b"top");
+        assert_eq!(
+            fs::read_link(desktop.root.join("link")).unwrap(),
+            Path::new("docs/lorem_ipsum")
+        );
+
+        // Nothing changed, so nothing is done and no snapshot is made
+        assert_eq!(laptop.sync().actions, []);
+        assert_eq!(desktop.sync().actions, []);
+        assert_eq!(Snapshot::list(&laptop.vault).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn syncs_modifications_and_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let desktop = Device::new(&dir, "desktop");
+        laptop.write("docs/notes/todo", b"first");
+        laptop.write("docs/keep", b"keep");
+        laptop.write("top", b"top");
+        laptop.sync();
+        desktop.sync();
+
+        laptop.write("docs/keep", b"changed on the laptop");
+        fs::remove_dir_all(laptop.root.join("docs/notes")).unwrap();
+        let first = laptop.sync();
+        assert_eq!(
+            first.actions,
+            [
+                SyncAction::Upload("docs/keep".into()),
+                SyncAction::DeleteRemote("docs/notes".into()),
+                SyncAction::DeleteRemote("docs/notes/todo".into()),
+            ]
+        );
+
+        desktop.write("top", b"changed on the desktop");
+        let second = desktop.sync();
+        assert_eq!(
+            second.actions,
+            [
+                SyncAction::Download("docs/keep".into()),
+                SyncAction::DeleteLocal("docs/notes".into()),
+                SyncAction::DeleteLocal("docs/notes/todo".into()),
+                SyncAction::Upload("top".into()),
+            ]
+        );
+        assert_eq!(
+            Snapshot::load(&desktop.vault, &second.snapshot.unwrap())
+                .unwrap()
+                .parents,
+            [first.snapshot.unwrap()]
+        );
+        assert_eq!(desktop.read("docs/keep").unwrap(), b"changed on the laptop");
+        assert!(!desktop.root.join("docs/notes").exists());
+
+        assert_eq!(laptop.sync().actions, downloads(&["top"]));
+        assert_eq!(laptop.read("top").unwrap(), b"changed on the desktop");
+    }
+
+    #[test]
+    fn moves_renamed_files_without_transferring_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let desktop = Device::new(&dir, "desktop");
+        laptop.write("draft", &fs::read(PATH).unwrap());
+        laptop.sync();
+        desktop.sync();
+
+        fs::create_dir(laptop.root.join("final")).unwrap();
+        fs::rename(
+            laptop.root.join("draft"),
+            laptop.root.join("final/lorem_ipsum"),
+        )
+        .unwrap();
+        let chunks = laptop.chunks();
+        assert_eq!(
+            laptop.sync().actions,
+            [
+                SyncAction::Upload("final".into()),
+                SyncAction::RenameRemote {
+                    from: "draft".into(),
+                    to: "final/lorem_ipsum".into()
+                },
+            ]
+        );
+        // Only the new tree manifest was stored
+        assert_eq!(laptop.chunks(), chunks + 1);
+
+        assert_eq!(
+            desktop.sync().actions,
+            [
+                SyncAction::Download("final".into()),
+                SyncAction::RenameLocal {
+                    from: "draft".into(),
+                    to: "final/lorem_ipsum".into()
+                },
+            ]
+        );
+        assert_eq!(desktop.read("draft"), None);
+        assert_eq!(
+            desktop.read("final/lorem_ipsum"),
+            Some(fs::read(PATH).unwrap())
+        );
+        assert_eq!(laptop.sync().actions, []);
+    }
+
+    #[test]
+    fn merges_folders_that_start_out_with_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let desktop = Device::new(&dir, "desktop");
+        laptop.write("shared", b"same everywhere");
+        laptop.write("laptop only", b"laptop");
+        desktop.write("shared", b"same everywhere");
+        desktop.write("desktop only", b"desktop");
+
+        laptop.sync();
+        let chunks = desktop.chunks();
+        assert_eq!(
+            desktop.sync().actions,
+            [
+                SyncAction::Upload("desktop only".into()),
+                SyncAction::Download("laptop only".into()),
+            ]
+        );
+        assert_eq!(desktop.chunks(), chunks + 2);
+        assert_eq!(laptop.sync().actions, downloads(&["desktop only"]));
+        for device in [&laptop, &desktop] {
+            assert_eq!(
+                scan(
+                    &device.root,
+                    &mut FileIndex::open(&device.vault, &device.root).unwrap()
+                )
+                .unwrap()
+                .len(),
+                3
+            );
+        }
+    }
+
+    /// Edits a file on both devices after they were in sync on it, syncing the laptop's edit first
+    fn concurrent_edits(dir: &tempfile::TempDir) -> (Device, Device) {
+        let laptop = Device::new(dir, "laptop");
+        let desktop = Device::new(dir, "desktop");
+        laptop.write("notes.txt", b"original");
+        laptop.sync();
+        desktop.sync();
+
+        laptop.write("notes.txt", b"laptop edit");
+        laptop.sync();
+        desktop.write("notes.txt", b"desktop edit");
+        (laptop, desktop)
+    }
+
+    #[test]
+    fn keeps_both_versions_of_concurrent_edits() {
+        let dir = tempfile::tempdir().unwrap();
+        let (laptop, desktop) = concurrent_edits(&dir);
+
+        let report = desktop.sync();
+        let copy = report
+            .actions
+            .iter()
+            .find_map(|action| match action {
+                SyncAction::ConflictCopy { copy, .. } => Some(copy.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let name = copy.to_str().unwrap();
+        assert!(name.starts_with("notes (conflict from desktop "), "{name}");
+        assert!(name.ends_with(").txt"), "{name}");
+        assert_eq!(
+            report.actions,
+            [
+                SyncAction::ConflictCopy {
+                    path: "notes.txt".into(),
+                    copy: copy.clone()
+                },
+                SyncAction::Upload(copy.clone()),
+                SyncAction::Download("notes.txt".into()),
+            ]
+        );
+        assert_eq!(desktop.read("notes.txt").unwrap(), b"laptop edit");
+        assert_eq!(desktop.read(name).unwrap(), b"desktop edit");
+
+        assert_eq!(laptop.sync().actions, [SyncAction::Download(copy.clone())]);
+        assert_eq!(laptop.read(name).unwrap(), b"desktop edit");
+        assert_eq!(desktop.sync().actions, []);
+
+        let version = desktop.remote_entry("notes.txt").version.unwrap();
+        assert_eq!(version.device, "laptop");
+        assert_eq!(version.vector.get("laptop"), 2);
+        assert_eq!(version.vector.get("desktop"), 0);
+    }
+
+    #[test]
+    fn merges_separate_edits_to_text_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let mut desktop = Device::new(&dir, "desktop");
+        desktop.options.merge_text = true;
+        laptop.write("notes.txt", b"shopping\n- milk\n\nchores\n- dishes\n");
+        laptop.sync();
+        desktop.sync();
+
+        laptop.write(
+            "notes.txt",
+            b"shopping\n- milk\n- eggs\n\nchores\n- dishes\n",
+        );
+        laptop.sync();
+        desktop.write("notes.txt", b"shopping\n- milk\n\nchores\n- laundry\n");
+        let merged = b"shopping\n- milk\n- eggs\n\nchores\n- laundry\n";
+        assert_eq!(
+            desktop.sync().actions,
+            [
+                SyncAction::Merge("notes.txt".into()),
+                SyncAction::Upload("notes.txt".into()),
+            ]
+        );
+        assert_eq!(desktop.read("notes.txt").unwrap(), merged);
+        assert_eq!(laptop.sync().actions, downloads(&["notes.txt"]));
+        assert_eq!(laptop.read("notes.txt").unwrap(), merged);
+
+        // Overlapping edits fall back to a conflict copy
+        laptop.write("notes.txt", b"shopping\n- bread\n");
+        laptop.sync();
+        desktop.write("notes.txt", b"shopping\n- butter\n");
+        let actions = desktop.sync().actions;
+        assert!(matches!(actions[0], SyncAction::ConflictCopy { .. }));
+        assert_eq!(desktop.read("notes.txt").unwrap(), b"shopping\n- bread\n");
+    }
+
+    #[test]
+    fn keeps_edits_over_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let desktop = Device::new(&dir, "desktop");
+        laptop.write("a", b"a");
+        laptop.write("b", b"b");
+        laptop.sync();
+        desktop.sync();
+
+        fs::remove_file(laptop.root.join("a")).unwrap();
+        laptop.write("b", b"laptop edit");
+        laptop.sync();
+        desktop.write("a", b"desktop edit");
+        fs::remove_file(desktop.root.join("b")).unwrap();
+
+        assert_eq!(
+            desktop.sync().actions,
+            [
+                SyncAction::Upload("a".into()),
+                SyncAction::Download("b".into()),
+            ]
+        );
+        assert_eq!(laptop.sync().actions, downloads(&["a"]));
+        for device in [&laptop, &desktop] {
+            assert_eq!(device.read("a").unwrap(), b"desktop edit");
+            assert_eq!(device.read("b").unwrap(), b"laptop edit");
+        }
+    }
+
+    #[test]
+    fn prefers_the_newest_edit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (laptop, mut desktop) = concurrent_edits(&dir);
+        desktop.options.resolution = ConflictResolution::PreferNewest;
+        let laptop_time = desktop.remote_entry("notes.txt").metadata.modified.unwrap();
+        let set_time = |device: &Device, seconds| {
+            let time = FileTime::from_unix_time(laptop_time.seconds + seconds, 0);
+            filetime::set_file_mtime(device.root.join("notes.txt"), time).unwrap();
+        };
+
+        set_time(&desktop, -60);
+        assert_eq!(desktop.sync().actions, downloads(&["notes.txt"]));
+        assert_eq!(desktop.read("notes.txt").unwrap(), b"laptop edit");
+
+        laptop.write("notes.txt", b"second laptop edit");
+        set_time(&laptop, 0);
+        laptop.sync();
+        desktop.write("notes.txt", b"second desktop edit");
+        set_time(&desktop, 60);
+        assert_eq!(desktop.sync().actions, uploads(&["notes.txt"]));
+        assert_eq!(laptop.sync().actions, downloads(&["notes.txt"]));
+        assert_eq!(laptop.read("notes.txt").unwrap(), b"second desktop edit");
+    }
+
+    #[test]
+    fn prefers_edits_from_the_chosen_device() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut laptop, mut desktop) = concurrent_edits(&dir);
+        let resolution = ConflictResolution::PreferDevice("laptop".to_owned());
+        desktop.options.resolution = resolution.clone();
+        assert_eq!(desktop.sync().actions, downloads(&["notes.txt"]));
+        assert_eq!(desktop.read("notes.txt").unwrap(), b"laptop edit");
+
+        laptop.options.resolution = resolution;
+        desktop.write("notes.txt", b"second desktop edit");
+        desktop.sync();
+        let desktop_version = desktop.remote_entry("notes.txt").version.unwrap();
+        laptop.write("notes.txt", b"second laptop edit");
+        assert_eq!(laptop.sync().actions, uploads(&["notes.txt"]));
+
+        // The resolved version follows both edits, so the desktop takes it without a conflict
+        let version = laptop.remote_entry("notes.txt").version.unwrap();
+        assert_eq!(
+            version.vector.compare(&desktop_version.vector),
+            Causality::After
+        );
+        assert_eq!(desktop.sync().actions, downloads(&["notes.txt"]));
+        assert_eq!(desktop.read("notes.txt").unwrap(), b"second laptop edit");
+    }
+
+    #[test]
+    fn brings_an_older_remote_up_to_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let desktop = Device::new(&dir, "desktop");
+        laptop.write("notes", b"first");
+        laptop.sync();
+        laptop.write("notes", b"second");
+        let newest = laptop.sync().snapshot.unwrap();
+
+        // Going back to the first snapshot is not taken for an edit to undo
+        Snapshot::forget(&laptop.vault, &newest).unwrap();
+        assert_eq!(laptop.sync().actions, uploads(&["notes"]));
+        assert_eq!(laptop.read("notes").unwrap(), b"second");
+        assert_eq!(desktop.sync().actions, downloads(&["notes"]));
+        assert_eq!(desktop.read("notes").unwrap(), b"second");
+    }
+
+    #[test]
+    fn refuses_to_download_through_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let desktop = Device::new(&dir, "desktop");
+        laptop.write("notes", b"notes");
+        let head = laptop.sync().snapshot.unwrap();
+
+        // A remote with a file inside a symlink leading out of the folder
+        let outside = dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let notes = laptop.remote_entry("notes");
+        let link = TreeEntry {
+            path: "link".into(),
+            kind: TreeEntryKind::Symlink(outside.clone()),
+            ..notes.clone()
+        };
+        let escape = TreeEntry {
+            path: "link/escape".into(),
+            ..notes.clone()
+        };
+        let options = SnapshotOptions {
+            device: "laptop".to_owned(),
+            parents: vec![head],
+            tags: vec![SYNC_TAG.to_owned()],
+        };
+        let tree = TreeManifest::from_entries([notes, link, escape]);
+        Snapshot::commit(&laptop.vault, &tree, options).unwrap();
+
+        let result = sync(
+            &desktop.vault,
+            &desktop.root,
+            &desktop.state,
+            &desktop.options,
+        );
+        assert!(matches!(result, Err(Error::UnsafePath(_))));
+        assert!(!outside.join("escape").exists());
+    }
+
+    #[test]
+    fn keeps_files_missing_from_an_older_remote() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let desktop = Device::new(&dir, "desktop");
+        laptop.write("notes", b"notes");
+        laptop.sync();
+        laptop.write("added", b"added");
+        let newest = laptop.sync().snapshot.unwrap();
+
+        // Missing from the remote now, but never deleted
+        Snapshot::forget(&laptop.vault, &newest).unwrap();
+        assert_eq!(laptop.sync().actions, uploads(&["added"]));
+        assert_eq!(laptop.read("added").unwrap(), b"added");
+        assert_eq!(desktop.sync().actions, downloads(&["added", "notes"]));
+
+        // Deletions are still synced
+        fs::remove_file(desktop.root.join("added")).unwrap();
+        assert_eq!(
+            desktop.sync().actions,
+            [SyncAction::DeleteRemote("added".into())]
+        );
+        assert_eq!(
+            laptop.sync().actions,
+            [SyncAction::DeleteLocal("added".into())]
+        );
+        assert_eq!(laptop.read("added"), None);
+    }
+
+    #[test]
+    fn merges_concurrently_committed_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let desktop = Device::new(&dir, "desktop");
+        laptop.write("notes", b"notes");
+        laptop.sync();
+        desktop.sync();
+        laptop.write("laptop", b"laptop");
+        let first = laptop.sync().snapshot.unwrap();
+
+        // The desktop commits without seeing the laptop's snapshot, as if both had checked for
+        // concurrent syncs at once
+        let path = laptop.vault.snapshots_dir().join(first.to_string());
+        let hidden = dir.path().join("hidden");
+        fs::rename(&path, &hidden).unwrap();
+        desktop.write("desktop", b"desktop");
+        let second = desktop.sync().snapshot.unwrap();
+        fs::rename(&hidden, &path).unwrap();
+
+        // The laptop's file is missing from the newest head, but was never deleted
+        let report = laptop.sync();
+        assert_eq!(
+            report.actions,
+            [
+                SyncAction::Download("desktop".into()),
+                SyncAction::Upload("laptop".into())
+            ]
+        );
+        let merged = Snapshot::load(&laptop.vault, &report.snapshot.unwrap()).unwrap();
+        assert_eq!(merged.parents, [first, second]);
+        assert_eq!(desktop.sync().actions, downloads(&["laptop"]));
+        assert_eq!(laptop.sync().actions, []);
+    }
+
+    #[test]
+    fn leaves_conflicts_with_directories_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let desktop = Device::new(&dir, "desktop");
+        laptop.write("notes", b"file");
+        laptop.sync();
+        desktop.sync();
+
+        fs::remove_file(laptop.root.join("notes")).unwrap();
+        laptop.write("notes/inner", b"inner");
+        laptop.sync();
+        desktop.write("notes", b"edited");
+
+        let conflict = [SyncAction::Conflict("notes".into())];
+        assert_eq!(desktop.sync().actions, conflict);
+        assert_eq!(desktop.read("notes").unwrap(), b"edited");
+        // Still in conflict, rather than settled in favor of either side
+        assert_eq!(desktop.sync().actions, conflict);
+        assert_eq!(laptop.sync().actions, []);
+    }
+
+    #[test]
+    fn keeps_directories_with_new_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let laptop = Device::new(&dir, "laptop");
+        let desktop = Device::new(&dir, "desktop");
+        laptop.write("docs/old", b"old");
+        laptop.sync();
+        desktop.sync();
+
+        fs::remove_dir_all(laptop.root.join("docs")).unwrap();
+        laptop.sync();
+        desktop.write("docs/new", b"new");
+        assert_eq!(
+            desktop.sync().actions,
+            [
+                SyncAction::Upload("docs".into()),
+                SyncAction::Upload("docs/new".into()),
+                SyncAction::DeleteLocal("docs/old".into()),
+            ]
+        );
+        assert_eq!(laptop.sync().actions, downloads(&["docs", "docs/new"]));
+    }
+
+    #[test]
+    fn refuses_vaults_keeping_chunks_elsewhere() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = VaultOptions {
+            store: Some(format!("local:{}", dir.path().join("chunks").display())),
+            ..VaultOptions::default()
+        };
+        let vault = Vault::init(&dir.path().join("vault"), "password", &options).unwrap();
+        let root = dir.path().join("folder");
+        fs::create_dir(&root).unwrap();
+        assert!(matches!(
+            sync(
+                &vault,
+                &root,
+                &dir.path().join("state"),
+                &SyncOptions::default()
+            ),
+            Err(Error::NeedsSharedVault("sync"))
+        ));
+    }
+}
@@ -0,0 +1,532 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto::aead::{self, EncryptedChunk},
+    error::{Error, Result},
+    file::FileManifest,
+    metadata::{Metadata, MetadataPolicy},
+    path,
+    version::Version,
+    zeroize_allocator::Zeroing,
+};
+
+/// Manifest of a directory tree.
+///
+/// Entries are stored with paths relative to the root of the tree, ordered so every directory
+/// comes before its contents.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TreeManifest {
+    entries: Vec<TreeEntry>,
+    /// Paths sync deleted, ordered like the entries
+    deleted: Vec<DeletedEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TreeEntry {
+    #[serde(with = "crate::path")]
+    pub path: PathBuf,
+    pub kind: TreeEntryKind,
+    pub metadata: Metadata,
+    /// Version of the entry, for trees recorded by sync
+    pub version: Option<Version>,
+}
+
+/// A path deleted by sync, kept so devices still having it delete it too instead of taking its
+/// absence for an older tree.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct DeletedEntry {
+    #[serde(with = "crate::path")]
+    pub path: PathBuf,
+    /// Version of the deletion
+    pub version: Version,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) enum TreeEntryKind {
+    File(FileManifest),
+    Directory,
+    Symlink(#[serde(with = "crate::path")] PathBuf),
+}
+
+impl TreeManifest {
+    /// Walks the tree below `root`, recording directories, symlinks and regular files. Special
+    /// files such as sockets and devices are skipped.
+    ///
+    /// `store_file` is called with the path of every regular file and returns the manifest of the
+    /// file's contents once stored. The metadata allowed by `policy` is recorded for every entry.
+    pub fn build(
+        root: &Path,
+        policy: &MetadataPolicy,
+        mut store_file: impl FnMut(&Path) -> Result<FileManifest>,
+    ) -> Result<Self> {
+        let mut manifest = Self::default();
+        manifest.walk(root, Path::new(""), policy, &mut store_file)?;
+        Ok(manifest)
+    }
+
+    fn walk(
+        &mut self,
+        root: &Path,
+        relative: &Path,
+        policy: &MetadataPolicy,
+        store_file: &mut impl FnMut(&Path) -> Result<FileManifest>,
+    ) -> Result<()> {
+        for entry in path::read_dir_sorted(&root.join(relative))? {
+            let path = relative.join(entry.file_name());
+            let file_type = entry.file_type()?;
+
+            let kind = if file_type.is_dir() {
+                TreeEntryKind::Directory
+            } else if file_type.is_symlink() {
+                TreeEntryKind::Symlink(fs::read_link(entry.path())?)
+            } else if file_type.is_file() {
+                TreeEntryKind::File(store_file(&entry.path())?)
+            } else {
+                continue;
+            };
+
+            self.entries.push(TreeEntry {
+                path: path.clone(),
+                kind,
+                metadata: Metadata::capture(&entry.path(), policy)?,
+                version: None,
+            });
+            if file_type.is_dir() {
+                self.walk(root, &path, policy, store_file)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A manifest of `entries`, which are sorted by path and must not repeat a path.
+    pub fn from_entries(entries: impl IntoIterator<Item = TreeEntry>) -> Self {
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Self {
+            entries,
+            deleted: Vec::new(),
+        }
+    }
+
+    /// The manifest recording `deleted` as deleted, replacing any deleted paths recorded before.
+    /// Paths must not repeat or have entries.
+    pub fn with_deleted(mut self, deleted: impl IntoIterator<Item = DeletedEntry>) -> Self {
+        self.deleted = deleted.into_iter().collect();
+        self.deleted.sort_by(|a, b| a.path.cmp(&b.path));
+        self
+    }
+
+    pub fn entries(&self) -> &[TreeEntry] {
+        &self.entries
+    }
+
+    pub fn deleted(&self) -> &[DeletedEntry] {
+        &self.deleted
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&TreeEntry> {
+        self.entries
+            .binary_search_by(|entry| entry.path.as_path().cmp(path))
+            .ok()
+            .map(|index| &self.entries[index])
+    }
+
+    /// `path` and everything below it, with paths relative to the parent of `path`, or `None` if
+    /// the tree has no entry at `path`. An empty `path` gives the whole tree, and any other `path`
+    /// a tree without deleted paths.
+    pub fn subtree(&self, path: &Path) -> Option<Self> {
+        if path.as_os_str().is_empty() {
+            return Some(self.clone());
+        }
+        self.get(path)?;
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let entries = self
+            .entries
+            .iter()
+            .filter(|entry| entry.path.starts_with(path))
+            .map(|entry| TreeEntry {
+                path: entry
+                    .path
+                    .strip_prefix(parent)
+                    .unwrap_or(&entry.path)
+                    .to_owned(),
+                ..entry.clone()
+            })
+            .collect();
+        Some(Self {
+            entries,
+            deleted: Vec::new(),
+        })
+    }
+
+    /// Recreates the tree below `root`, creating `root` if needed. Fails for entries inside a
+    /// symlink, which could otherwise write outside `root`.
+    ///
+    /// `restore_file` is called with the manifest of every regular file and the newly created file
+    /// to write its contents to. Existing files and symlinks in the way are replaced, anything else
+    /// already below `root` is left alone, so restoring into an empty directory reproduces the tree
+    /// exactly.
+    ///
+    /// Recorded metadata allowed by `policy` is applied once everything is restored, children
+    /// before their parents so writing a directory's contents does not change its times.
+    pub fn restore(
+        &self,
+        root: &Path,
+        policy: &MetadataPolicy,
+        mut restore_file: impl FnMut(&FileManifest, &mut fs::File) -> Result<()>,
+    ) -> Result<()> {
+        fs::create_dir_all(root)?;
+
+        for entry in &self.entries {
+            path::check_relative(&entry.path)?;
+            path::check_no_symlinks(root, &entry.path)?;
+            let target = root.join(&entry.path);
+            let existing = fs::symlink_metadata(&target).ok();
+
+            match &entry.kind {
+                TreeEntryKind::Directory => {
+                    if existing.is_some_and(|metadata| !metadata.is_dir()) {
+                        fs::remove_file(&target)?;
+                    }
+                    fs::create_dir_all(&target)?;
+                }
+                TreeEntryKind::File(manifest) => {
+                    if existing.is_some_and(|metadata| metadata.is_symlink()) {
+                        fs::remove_file(&target)?;
+                    }
+                    restore_file(manifest, &mut fs::File::create(&target)?)?;
+                }
+                TreeEntryKind::Symlink(link_target) => {
+                    if existing.is_some() {
+                        fs::remove_file(&target)?;
+                    }
+                    std::os::unix::fs::symlink(link_target, &target)?;
+                }
+            }
+        }
+
+        for entry in self.entries.iter().rev() {
+            entry.metadata.apply(&root.join(&entry.path), policy)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let manifest: Self = bincode::deserialize(bytes)?;
+        let sorted = |paths: Vec<&PathBuf>| paths.windows(2).all(|pair| pair[0] < pair[1]);
+        if !sorted(manifest.entries.iter().map(|entry| &entry.path).collect())
+            || !sorted(manifest.deleted.iter().map(|entry| &entry.path).collect())
+        {
+            return Err(Error::ParseManifest);
+        }
+        Ok(manifest)
+    }
+
+    /// Encrypts the whole manifest as a single blob, so storage sees none of its paths, symlink
+    /// targets or metadata.
+    pub fn encrypt(&self, prk: &Zeroing<[u8; 32]>) -> Result<EncryptedChunk> {
+        aead::encrypt_blob(prk, &self.to_bytes()?)
+    }
+
+    pub fn decrypt(prk: &Zeroing<[u8; 32]>, encrypted: &EncryptedChunk) -> Result<Self> {
+        Self::parse(&aead::decrypt_blob(prk, encrypted)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Write, os::unix::fs::PermissionsExt};
+
+    use super::*;
+    use crate::{
+        crypto::{aead::AesGcmKey, aead::ChunkKey, tests::PRK},
+        decrypting_reader::DecryptingReader,
+        encrypting_writer::EncryptingWriter,
+    };
+
+    /// Stores encrypted file contents by their serialized manifest.
+    #[derive(Default)]
+    struct Contents(HashMap<Vec<u8>, Vec<u8>>);
+
+    impl Contents {
+        fn store(&mut self, path: &Path) -> Result<FileManifest> {
+            let key = AesGcmKey::generate(Box::pin(PRK), uuid::Uuid::now_v7())?;
+            let mut writer = EncryptingWriter::new(Vec::new(), key);
+            writer.write_all(&fs::read(path)?)?;
+            let (encrypted, manifest) = writer.finish()?;
+            self.0.insert(manifest.to_bytes(), encrypted);
+            Ok(manifest)
+        }
+
+        fn restore(&self, manifest: &FileManifest, file: &mut fs::File) -> Result<()> {
+            let encrypted = &self.0[&manifest.to_bytes()];
+            std::io::copy(
+                &mut DecryptingReader::new(&encrypted[..], Box::pin(PRK)),
+                file,
+            )?;
+            Ok(())
+        }
+    }
+
+    fn tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("a/b/c")).unwrap();
+        fs::create_dir(root.join("empty")).unwrap();
+        fs::copy("test/lorem_ipsum", root.join("a/lorem_ipsum")).unwrap();
+        fs::write(root.join("a/b/c/deep"), b"deep").unwrap();
+        fs::write(root.join("a-b"), b"sorts after a/").unwrap();
+        fs::write(root.join("empty_file"), b"").unwrap();
+        std::os::unix::fs::symlink("a/lorem_ipsum", root.join("link")).unwrap();
+        std::os::unix::fs::symlink("../outside", root.join("a/dangling")).unwrap();
+        std::os::unix::fs::symlink("b", root.join("a/dir_link")).unwrap();
+        dir
+    }
+
+    fn build(root: &Path, contents: &mut Contents) -> TreeManifest {
+        TreeManifest::build(root, &MetadataPolicy::default(), |path| {
+            contents.store(path)
+        })
+        .unwrap()
+    }
+
+    fn paths(manifest: &TreeManifest) -> Vec<&str> {
+        manifest
+            .entries()
+            .iter()
+            .map(|entry| entry.path.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn records_nested_entries_in_order() {
+        let tree = tree();
+        let manifest = build(tree.path(), &mut Contents::default());
+
+        assert_eq!(
+            paths(&manifest),
+            [
+                "a",
+                "a/b",
+                "a/b/c",
+                "a/b/c/deep",
+                "a/dangling",
+                "a/dir_link",
+                "a/lorem_ipsum",
+                "a-b",
+                "empty",
+                "empty_file",
+                "link"
+            ]
+        );
+    }
+
+    #[test]
+    fn records_entry_kinds() {
+        let tree = tree();
+        let manifest = build(tree.path(), &mut Contents::default());
+
+        assert_eq!(
+            manifest.get(Path::new("empty")).unwrap().kind,
+            TreeEntryKind::Directory
+        );
+        assert_eq!(
+            manifest.get(Path::new("a/dir_link")).unwrap().kind,
+            TreeEntryKind::Symlink(PathBuf::from("b"))
+        );
+        let TreeEntryKind::File(file) = &manifest.get(Path::new("a/lorem_ipsum")).unwrap().kind
+        else {
+            panic!("expected a file");
+        };
+        assert_eq!(file.size(), 77);
+        assert!(manifest.get(Path::new("missing")).is_none());
+    }
+
+    #[test]
+    fn restores_tree_exactly() {
+        let tree = tree();
+        let mut contents = Contents::default();
+        let manifest = build(tree.path(), &mut contents);
+
+        let target = tempfile::tempdir().unwrap();
+        let root = target.path().join("restored");
+        manifest
+            .restore(&root, &MetadataPolicy::default(), |manifest, file| {
+                contents.restore(manifest, file)
+            })
+            .unwrap();
+
+        let mut rebuilt_contents = Contents::default();
+        let rebuilt = build(&root, &mut rebuilt_contents);
+        assert_eq!(paths(&rebuilt), paths(&manifest));
+        for (entry, rebuilt) in manifest.entries().iter().zip(rebuilt.entries()) {
+            match (&entry.kind, &rebuilt.kind) {
+                (TreeEntryKind::File(_), TreeEntryKind::File(_)) => assert_eq!(
+                    fs::read(tree.path().join(&entry.path)).unwrap(),
+                    fs::read(root.join(&entry.path)).unwrap()
+                ),
+                (kind, rebuilt_kind) => assert_eq!(kind, rebuilt_kind),
+            }
+            assert_eq!(entry.metadata, rebuilt.metadata, "{:?}", entry.path);
+        }
+    }
+
+    #[test]
+    fn restores_metadata() {
+        let tree = tree();
+        fs::set_permissions(
+            tree.path().join("a/b/c/deep"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        let modified = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(tree.path().join("a/b"), modified).unwrap();
+        let mut contents = Contents::default();
+        let manifest = build(tree.path(), &mut contents);
+
+        let target = tempfile::tempdir().unwrap();
+        manifest
+            .restore(
+                target.path(),
+                &MetadataPolicy::default(),
+                |manifest, file| contents.restore(manifest, file),
+            )
+            .unwrap();
+
+        let deep = fs::metadata(target.path().join("a/b/c/deep")).unwrap();
+        assert_eq!(deep.permissions().mode() & 0o777, 0o755);
+        let dir = fs::metadata(target.path().join("a/b")).unwrap();
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&dir),
+            modified
+        );
+    }
+
+    #[test]
+    fn restore_ignores_metadata_excluded_by_policy() {
+        let tree = tree();
+        fs::set_permissions(
+            tree.path().join("a/b/c/deep"),
+            fs::Permissions::from_mode(0o700),
+        )
+        .unwrap();
+        let mut contents = Contents::default();
+        let manifest = build(tree.path(), &mut contents);
+
+        let target = tempfile::tempdir().unwrap();
+        manifest
+            .restore(target.path(), &MetadataPolicy::none(), |manifest, file| {
+                contents.restore(manifest, file)
+            })
+            .unwrap();
+
+        let deep = fs::metadata(target.path().join("a/b/c/deep")).unwrap();
+        assert_ne!(deep.permissions().mode() & 0o777, 0o700);
+    }
+
+    #[test]
+    fn restore_replaces_conflicting_entries() {
+        let tree = tree();
+        let mut contents = Contents::default();
+        let manifest = build(tree.path(), &mut contents);
+
+        let target = tempfile::tempdir().unwrap();
+        fs::write(target.path().join("empty"), b"file in place of a directory").unwrap();
+        fs::write(target.path().join("link"), b"file in place of a symlink").unwrap();
+        std::os::unix::fs::symlink("elsewhere", target.path().join("empty_file")).unwrap();
+        manifest
+            .restore(
+                target.path(),
+                &MetadataPolicy::default(),
+                |manifest, file| contents.restore(manifest, file),
+            )
+            .unwrap();
+
+        assert!(target.path().join("empty").is_dir());
+        assert_eq!(
+            fs::read_link(target.path().join("link")).unwrap(),
+            Path::new("a/lorem_ipsum")
+        );
+        assert!(!target.path().join("empty_file").is_symlink());
+    }
+
+    #[test]
+    fn restore_refuses_to_follow_symlinks() {
+        let tree = tree();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), tree.path().join("escape")).unwrap();
+        let mut contents = Contents::default();
+        let manifest = build(tree.path(), &mut contents);
+        let mut entries = manifest.entries().to_vec();
+        entries.push(TreeEntry {
+            path: "escape/lorem_ipsum".into(),
+            ..manifest.get(Path::new("a/lorem_ipsum")).unwrap().clone()
+        });
+        let manifest = TreeManifest::from_entries(entries);
+
+        let target = tempfile::tempdir().unwrap();
+        let result = manifest.restore(target.path(), &MetadataPolicy::default(), |m, file| {
+            contents.restore(m, file)
+        });
+        assert!(matches!(result, Err(Error::UnsafePath(_))));
+        assert!(!outside.path().join("lorem_ipsum").exists());
+    }
+
+    #[test]
+    fn round_trips_bytes() {
+        let tree = tree();
+        let manifest = build(tree.path(), &mut Contents::default());
+        assert_eq!(
+            TreeManifest::parse(&manifest.to_bytes().unwrap()).unwrap(),
+            manifest
+        );
+    }
+
+    #[test]
+    fn extracts_subtrees() {
+        let tree = tree();
+        let manifest = build(tree.path(), &mut Contents::default());
+
+        let subtree = manifest.subtree(Path::new("a/b")).unwrap();
+        assert_eq!(paths(&subtree), ["b", "b/c", "b/c/deep"]);
+        let file = manifest.subtree(Path::new("a/lorem_ipsum")).unwrap();
+        assert_eq!(paths(&file), ["lorem_ipsum"]);
+        assert_eq!(manifest.subtree(Path::new("")).unwrap(), manifest);
+        assert!(manifest.subtree(Path::new("a/missing")).is_none());
+    }
+
+    #[test]
+    fn encrypted_manifest_hides_names() {
+        let tree = tree();
+        fs::write(tree.path().join("日本語 ünïcödé"), b"").unwrap();
+        fs::write(tree.path().join("n".repeat(255)), b"").unwrap();
+        let manifest = build(tree.path(), &mut Contents::default());
+
+        let encrypted = manifest.encrypt(&Box::pin(PRK)).unwrap().to_bytes();
+        for name in ["lorem_ipsum", "日本語", &"n".repeat(255)] {
+            assert!(!encrypted
+                .windows(name.len())
+                .any(|window| window == name.as_bytes()));
+        }
+
+        let decrypted =
+            TreeManifest::decrypt(&Box::pin(PRK), &EncryptedChunk::parse(&encrypted).unwrap())
+                .unwrap();
+        assert_eq!(decrypted, manifest);
+        assert!(decrypted.get(Path::new("日本語 ünïcödé")).is_some());
+        assert!(TreeManifest::decrypt(
+            &Box::pin([0u8; 32]),
+            &EncryptedChunk::parse(&encrypted).unwrap()
+        )
+        .is_err());
+    }
+}
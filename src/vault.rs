@@ -0,0 +1,578 @@
+//! Vault on disk: the home of a set of keys and everything encrypted with them.
+//!
+//! ```text
+//! <root>/
+//!     config          format version, vault id and where the chunks are stored
+//!     keys            master key, encrypted with a key derived from the password
+//!     snapshots/      signed snapshots, see [`crate::snapshot`]
+//!     locks/          locks held by writers and garbage collection, see [`crate::lock`]
+//!     chunks/         chunk store, unless the config names another store
+//!     packs/          current pack indexes, if the config bundles chunks into packs
+//!     index/          local state that can be rebuilt, such as caches
+//! ```
+//!
+//! The password only wraps a random master key, so changing it does not touch the data. All other
+//! keys are derived from the master key.
+//!
+//! Devices share a vault by sharing its whole root, for instance on a network file system. When
+//! the config names another store only the chunks go there: snapshots, locks and pack indexes stay
+//! in the root. Such a vault serves a single device, since other devices could neither see its
+//! snapshots nor be kept from sweeping each other's chunks by its locks, so sync refuses it. Its
+//! root has to be kept as safe as the store, because the chunks cannot be found again without it.
+
+use std::{
+    fs,
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    archive::{ArchiveEntryKind, ArchiveReader, IMPORT_TAG},
+    container::{ContainerReader, ContainerWriter},
+    crypto::{
+        self,
+        aead::{self, AesGcmKey, ChunkKey, EncryptedChunk},
+    },
+    decrypting_reader::DecryptingReader,
+    encrypting_writer::EncryptingWriter,
+    error::{Error, Result},
+    lock::VaultLock,
+    metadata::Metadata,
+    snapshot::{Snapshot, SnapshotOptions},
+    store::{
+        self,
+        local::LocalStore,
+        pack::{PackedStore, Repack, DEFAULT_PACK_SIZE},
+        ChunkStore,
+    },
+    tree::{TreeEntry, TreeEntryKind, TreeManifest},
+    zeroize_allocator::Zeroing,
+};
+
+/// Version of the on-disk layout written by this build
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+const CONFIG_FILE: &str = "config";
+const KEYS_FILE: &str = "keys";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const LOCKS_DIR: &str = "locks";
+const CHUNKS_DIR: &str = "chunks";
+const PACKS_DIR: &str = "packs";
+const INDEX_DIR: &str = "index";
+
+const WRAPPING_KEY_NAME: &str = "key wrapping";
+const DATA_KEY_NAME: &str = "data";
+const PACK_KEY_NAME: &str = "packs";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct VaultConfig {
+    /// Always first, so it can be read whatever the rest of the config looks like
+    pub format_version: u32,
+    pub id: Uuid,
+    /// Location of the chunk store as understood by [`store::open`], or `None` for the `chunks`
+    /// directory of the vault. Only single device vaults keep their chunks elsewhere.
+    pub store: Option<String>,
+    /// Size of the packs chunks are bundled into, see [`PackedStore`], or `None` to store every
+    /// chunk as an object of its own
+    pub pack_size: Option<u64>,
+}
+
+/// Choices made when creating a vault.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct VaultOptions {
+    /// Location of the chunk store as understood by [`store::open`], or `None` for the `chunks`
+    /// directory of the vault
+    pub store: Option<String>,
+    pub pack_size: Option<u64>,
+}
+
+impl Default for VaultOptions {
+    fn default() -> Self {
+        Self {
+            store: None,
+            pack_size: Some(DEFAULT_PACK_SIZE as u64),
+        }
+    }
+}
+
+enum VaultStore {
+    Chunks(Box<dyn ChunkStore>),
+    Packed(Box<PackedStore<Box<dyn ChunkStore>>>),
+}
+
+/// An unlocked vault, holding its keys until it is locked or dropped.
+pub(crate) struct Vault {
+    root: PathBuf,
+    config: VaultConfig,
+    master: Zeroing<[u8; 32]>,
+    data_prk: Zeroing<[u8; 32]>,
+    store: VaultStore,
+}
+
+impl Vault {
+    /// Creates a vault in `root`, which must not exist yet or be empty.
+    pub fn init(root: &Path, password: &str, options: &VaultOptions) -> Result<Self> {
+        if root.exists() && fs::read_dir(root)?.next().is_some() {
+            return Err(Error::InvalidVault("directory is not empty"));
+        }
+        for dir in [SNAPSHOTS_DIR, LOCKS_DIR, CHUNKS_DIR, PACKS_DIR, INDEX_DIR] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+
+        let config = VaultConfig {
+            format_version: FORMAT_VERSION,
+            id: Uuid::now_v7(),
+            store: options.store.clone(),
+            pack_size: options.pack_size,
+        };
+        let mut master = Box::pin([0u8; 32]);
+        rand::thread_rng().fill_bytes(&mut *master);
+
+        write_atomic(&root.join(KEYS_FILE), &wrap(&config, password, &master)?)?;
+        // Written last, since a directory with a config is taken to be a complete vault
+        write_atomic(&root.join(CONFIG_FILE), &bincode::serialize(&config)?)?;
+        Self::unlocked(root, config, master)
+    }
+
+    /// Unlocks the vault in `root` with `password`.
+    pub fn open(root: &Path, password: &str) -> Result<Self> {
+        let config = read_config(root)?;
+        let wrapped = EncryptedChunk::parse(&fs::read(root.join(KEYS_FILE))?)?;
+        let master = aead::decrypt_blob(&wrapping_key(&config, password)?, &wrapped)
+            .map_err(|_| Error::WrongPassword)?;
+        let master: [u8; 32] = master
+            .try_into()
+            .map_err(|_| Error::InvalidVault("master key has the wrong length"))?;
+        Self::unlocked(root, config, Box::pin(master))
+    }
+
+    fn unlocked(root: &Path, config: VaultConfig, master: Zeroing<[u8; 32]>) -> Result<Self> {
+        let chunks: Box<dyn ChunkStore> = match &config.store {
+            Some(location) => store::open(location)?,
+            None => Box::new(LocalStore::new(root.join(CHUNKS_DIR))?),
+        };
+        let store = match config.pack_size {
+            Some(pack_size) => VaultStore::Packed(Box::new(PackedStore::open(
+                chunks,
+                crypto::derive_prk(&master, PACK_KEY_NAME)?,
+                pack_size as usize,
+                root.join(PACKS_DIR),
+            )?)),
+            None => VaultStore::Chunks(chunks),
+        };
+        Ok(Self {
+            root: root.to_owned(),
+            data_prk: crypto::derive_prk(&master, DATA_KEY_NAME)?,
+            config,
+            master,
+            store,
+        })
+    }
+
+    /// Forgets the keys. Dropping the vault does the same, this only makes it explicit.
+    pub fn lock(self) {}
+
+    /// Wraps the master key with a new password.
+    pub fn change_password(&self, password: &str) -> Result<()> {
+        write_atomic(
+            &self.root.join(KEYS_FILE),
+            &wrap(&self.config, password, &self.master)?,
+        )
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.config.id
+    }
+
+    pub fn config(&self) -> &VaultConfig {
+        &self.config
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn snapshots_dir(&self) -> PathBuf {
+        self.root.join(SNAPSHOTS_DIR)
+    }
+
+    pub fn locks_dir(&self) -> PathBuf {
+        self.root.join(LOCKS_DIR)
+    }
+
+    /// Directory for local state that can be rebuilt from the store, such as caches.
+    pub fn index_dir(&self) -> PathBuf {
+        self.root.join(INDEX_DIR)
+    }
+
+    pub fn store(&self) -> &dyn ChunkStore {
+        match &self.store {
+            VaultStore::Chunks(store) => store,
+            VaultStore::Packed(store) => &**store,
+        }
+    }
+
+    /// Writes out any chunks the store holds back, which must be done before anything refers to
+    /// them from outside the store.
+    pub fn flush_store(&self) -> Result<()> {
+        match &self.store {
+            VaultStore::Chunks(_) => Ok(()),
+            VaultStore::Packed(store) => store.flush(),
+        }
+    }
+
+    /// Rewrites the packs less than `min_live` full of chunks still in use, if the store bundles
+    /// chunks into packs.
+    pub fn repack_store(&self, min_live: f64) -> Result<Option<Repack>> {
+        match &self.store {
+            VaultStore::Chunks(_) => Ok(None),
+            VaultStore::Packed(store) => store.repack(min_live).map(Some),
+        }
+    }
+
+    /// Key for file contents stored in the chunk store.
+    pub fn data_prk(&self) -> &Zeroing<[u8; 32]> {
+        &self.data_prk
+    }
+
+    /// Derives a key for `name`, for purposes other than file contents and manifests.
+    pub fn derive_prk(&self, name: &str) -> Result<Zeroing<[u8; 32]>> {
+        crypto::derive_prk(&self.master, name)
+    }
+
+    /// Starts a stream encrypted with this vault's keys, which only this vault can decrypt.
+    pub fn encrypting_writer<W: Write>(&self, writer: W) -> Result<EncryptingWriter<W>> {
+        let key = AesGcmKey::generate(Box::pin(*self.data_prk), Uuid::now_v7())?;
+        Ok(EncryptingWriter::new(writer, key))
+    }
+
+    /// Decrypts a stream written by [`Vault::encrypting_writer`].
+    pub fn decrypting_reader<R: Read>(&self, reader: R) -> DecryptingReader<R> {
+        DecryptingReader::new(reader, Box::pin(*self.data_prk))
+    }
+
+    /// Starts a container of a single file for this vault, see [`crate::container`].
+    pub fn container_writer<W: Write>(&self, writer: W) -> Result<ContainerWriter<W>> {
+        ContainerWriter::new(writer, Box::pin(*self.data_prk), self.config.id)
+    }
+
+    /// Opens a container written for this vault.
+    pub fn container_reader<R: Read>(&self, reader: R) -> Result<ContainerReader<R>> {
+        let container = ContainerReader::new(reader, Box::pin(*self.data_prk))?;
+        if container.header().vault_id != self.config.id {
+            return Err(Error::InvalidContainer(
+                "container belongs to another vault",
+            ));
+        }
+        Ok(container)
+    }
+
+    /// Imports the archive read from `reader`, unlocked with its `password`, into a new snapshot
+    /// tagged [`IMPORT_TAG`]. Every file is decrypted with the archive's key and stored again with
+    /// the keys of this vault. Archives record no metadata, so neither does the snapshot.
+    pub fn import_archive<R: Read + Seek>(
+        &self,
+        reader: R,
+        password: &str,
+        device: &str,
+    ) -> Result<Snapshot> {
+        let _lock = VaultLock::shared(self)?;
+        let mut archive = ArchiveReader::new(reader, password)?;
+        let mut entries = Vec::new();
+        for entry in archive.entries().to_vec() {
+            let kind = match &entry.kind {
+                ArchiveEntryKind::Directory => TreeEntryKind::Directory,
+                ArchiveEntryKind::File { .. } => TreeEntryKind::File(store::put_file(
+                    self.store(),
+                    self.data_prk(),
+                    archive.open_file(&entry)?,
+                )?),
+            };
+            entries.push(TreeEntry {
+                path: entry.path,
+                kind,
+                metadata: Metadata::default(),
+                version: None,
+            });
+        }
+
+        Snapshot::commit(
+            self,
+            &TreeManifest::from_entries(entries),
+            SnapshotOptions {
+                device: device.to_owned(),
+                parents: Vec::new(),
+                tags: vec![IMPORT_TAG.to_owned()],
+            },
+        )
+    }
+}
+
+fn read_config(root: &Path) -> Result<VaultConfig> {
+    let data = match fs::read(root.join(CONFIG_FILE)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::InvalidVault("no vault config"))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let format_version: u32 = bincode::deserialize(&data)?;
+    if format_version != FORMAT_VERSION {
+        return Err(Error::InvalidVault("unsupported format version"));
+    }
+    Ok(bincode::deserialize(&data)?)
+}
+
+/// Key wrapping the master key, bound to the vault so equal passwords give different keys.
+fn wrapping_key(config: &VaultConfig, password: &str) -> Result<Zeroing<[u8; 32]>> {
+    let prk = crypto::generate_prk(password.to_owned())?;
+    crypto::derive_prk(&prk, &format!("{WRAPPING_KEY_NAME} {}", config.id))
+}
+
+fn wrap(config: &VaultConfig, password: &str, master: &Zeroing<[u8; 32]>) -> Result<Vec<u8>> {
+    let encrypted = aead::encrypt_blob(&wrapping_key(config, password)?, &**master)?;
+    Ok(encrypted.to_bytes())
+}
+
+/// Replaces `path` with `data` so that readers see either the old or the new contents.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    // The parent of a bare file name is empty
+    let dir = Some(path.parent().unwrap_or(Path::new(".")))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let tmp = dir.join(format!(".tmp-{}", Uuid::now_v7()));
+    let result = (|| {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result?;
+    Ok(fs::File::open(dir)?.sync_all()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        archive::ArchiveWriter,
+        store::{get_file, put_file},
+    };
+
+    const PATH: &str = "test/lorem_ipsum";
+
+    #[test]
+    fn initializes_and_reopens() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("vault");
+        let vault = Vault::init(&root, "password", &VaultOptions::default()).unwrap();
+        let id = vault.id();
+        let contents = fs::read(PATH).unwrap();
+        let manifest = put_file(vault.store(), vault.data_prk(), &contents[..]).unwrap();
+        vault.flush_store().unwrap();
+        vault.lock();
+
+        for dir in [SNAPSHOTS_DIR, CHUNKS_DIR, INDEX_DIR] {
+            assert!(root.join(dir).is_dir());
+        }
+        let vault = Vault::open(&root, "password").unwrap();
+        assert_eq!(vault.id(), id);
+        let mut data = Vec::new();
+        get_file(vault.store(), vault.data_prk(), &manifest, &mut data).unwrap();
+        assert_eq!(data, contents);
+    }
+
+    #[test]
+    fn rejects_wrong_password() {
+        let dir = tempfile::tempdir().unwrap();
+        Vault::init(dir.path(), "password", &VaultOptions::default()).unwrap();
+        assert!(matches!(
+            Vault::open(dir.path(), "guess"),
+            Err(Error::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn changes_password_without_changing_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::init(dir.path(), "password", &VaultOptions::default()).unwrap();
+        vault.change_password("new password").unwrap();
+        let data_prk = **vault.data_prk();
+        vault.lock();
+
+        assert!(matches!(
+            Vault::open(dir.path(), "password"),
+            Err(Error::WrongPassword)
+        ));
+        let vault = Vault::open(dir.path(), "new password").unwrap();
+        assert_eq!(**vault.data_prk(), data_prk);
+    }
+
+    #[test]
+    fn vaults_with_the_same_password_have_different_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = Vault::init(&dir.path().join("a"), "password", &VaultOptions::default()).unwrap();
+        let b = Vault::init(&dir.path().join("b"), "password", &VaultOptions::default()).unwrap();
+        assert_ne!(a.id(), b.id());
+        assert_ne!(**a.data_prk(), **b.data_prk());
+        assert_ne!(
+            fs::read(a.root().join(KEYS_FILE)).unwrap(),
+            fs::read(b.root().join(KEYS_FILE)).unwrap()
+        );
+    }
+
+    #[test]
+    fn refuses_to_init_over_existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes"), "keep me").unwrap();
+        assert!(matches!(
+            Vault::init(dir.path(), "password", &VaultOptions::default()),
+            Err(Error::InvalidVault(_))
+        ));
+        assert!(matches!(
+            Vault::open(dir.path(), "password"),
+            Err(Error::InvalidVault("no vault config"))
+        ));
+    }
+
+    #[test]
+    fn rejects_other_format_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::init(dir.path(), "password", &VaultOptions::default()).unwrap();
+        let config = VaultConfig {
+            format_version: FORMAT_VERSION + 1,
+            ..vault.config().clone()
+        };
+        fs::write(
+            dir.path().join(CONFIG_FILE),
+            bincode::serialize(&config).unwrap(),
+        )
+        .unwrap();
+        assert!(matches!(
+            Vault::open(dir.path(), "password"),
+            Err(Error::InvalidVault("unsupported format version"))
+        ));
+    }
+
+    #[test]
+    fn keeps_chunks_in_configured_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = format!("local:{}", dir.path().join("elsewhere").display());
+        let options = VaultOptions {
+            store: Some(location.clone()),
+            ..VaultOptions::default()
+        };
+        let vault = Vault::init(&dir.path().join("vault"), "password", &options).unwrap();
+        put_file(vault.store(), vault.data_prk(), &b"contents"[..]).unwrap();
+        vault.flush_store().unwrap();
+        vault.lock();
+
+        let vault = Vault::open(&dir.path().join("vault"), "password").unwrap();
+        assert_eq!(vault.config().store.as_deref(), Some(location.as_str()));
+        assert!(!vault.store().list().unwrap().is_empty());
+        assert_eq!(
+            fs::read_dir(dir.path().join("vault").join(CHUNKS_DIR))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn streams_decrypt_only_in_their_vault() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault =
+            Vault::init(&dir.path().join("a"), "password", &VaultOptions::default()).unwrap();
+        let mut writer = vault.encrypting_writer(Vec::new()).unwrap();
+        writer.write_all(b"piped data").unwrap();
+        let (encrypted, _) = writer.finish().unwrap();
+
+        let mut data = Vec::new();
+        vault
+            .decrypting_reader(&encrypted[..])
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"piped data");
+
+        let other =
+            Vault::init(&dir.path().join("b"), "password", &VaultOptions::default()).unwrap();
+        assert!(other
+            .decrypting_reader(&encrypted[..])
+            .read_to_end(&mut Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn containers_belong_to_their_vault() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault =
+            Vault::init(&dir.path().join("a"), "password", &VaultOptions::default()).unwrap();
+        let mut writer = vault.container_writer(Vec::new()).unwrap();
+        writer.write_all(b"contents").unwrap();
+        let (container, _) = writer.finish().unwrap();
+
+        let mut data = Vec::new();
+        vault
+            .container_reader(&container[..])
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"contents");
+
+        let other =
+            Vault::init(&dir.path().join("b"), "password", &VaultOptions::default()).unwrap();
+        assert!(matches!(
+            other.container_reader(&container[..]),
+            Err(Error::InvalidContainer(_))
+        ));
+    }
+
+    #[test]
+    fn imports_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        fs::create_dir_all(tree.join("docs")).unwrap();
+        fs::copy("test/lorem_ipsum", tree.join("docs/lorem_ipsum")).unwrap();
+        fs::write(tree.join("top"), b"top").unwrap();
+
+        let mut writer = ArchiveWriter::new(Vec::new(), "archive password").unwrap();
+        writer.add_tree(&tree).unwrap();
+        let archive = writer.finish().unwrap();
+
+        let target = Vault::init(
+            &dir.path().join("vault"),
+            "password",
+            &VaultOptions::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            target.import_archive(io::Cursor::new(&archive), "password", "laptop"),
+            Err(Error::WrongPassword)
+        ));
+        let snapshot = target
+            .import_archive(io::Cursor::new(&archive), "archive password", "laptop")
+            .unwrap();
+        assert_eq!(snapshot.tags, [IMPORT_TAG]);
+
+        let restored = dir.path().join("restored");
+        snapshot
+            .restore(&target, Path::new(""), &restored, &Default::default())
+            .unwrap();
+        for file in ["docs/lorem_ipsum", "top"] {
+            assert_eq!(
+                fs::read(restored.join(file)).unwrap(),
+                fs::read(tree.join(file)).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Version vectors, recording which changes made on which devices a version of a file includes.
+//!
+//! Every device counts the changes it makes to a path. A version includes another if it has seen
+//! at least as many changes from every device, and two versions neither of which includes the
+//! other were made concurrently, without either device knowing of the other's change.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct VersionVector {
+    /// Changes seen from each device, leaving out devices with none
+    counters: BTreeMap<String, u64>,
+}
+
+/// How one version relates to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Causality {
+    Equal,
+    /// Included in the other version
+    Before,
+    /// Includes the other version
+    After,
+    Concurrent,
+}
+
+impl VersionVector {
+    /// Number of changes made on `device` this version includes
+    pub fn get(&self, device: &str) -> u64 {
+        self.counters.get(device).copied().unwrap_or(0)
+    }
+
+    /// Records a change made on `device`.
+    pub fn increment(&mut self, device: &str) {
+        *self.counters.entry(device.to_owned()).or_insert(0) += 1;
+    }
+
+    /// Includes every change `other` includes, for a version resolving two concurrent ones.
+    pub fn merge(&mut self, other: &Self) {
+        for (device, &count) in &other.counters {
+            let counter = self.counters.entry(device.clone()).or_insert(0);
+            *counter = (*counter).max(count);
+        }
+    }
+
+    pub fn compare(&self, other: &Self) -> Causality {
+        let devices = self.counters.keys().chain(other.counters.keys());
+        let (mut ahead, mut behind) = (false, false);
+        for device in devices {
+            ahead |= self.get(device) > other.get(device);
+            behind |= self.get(device) < other.get(device);
+        }
+        match (ahead, behind) {
+            (false, false) => Causality::Equal,
+            (false, true) => Causality::Before,
+            (true, false) => Causality::After,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+}
+
+/// A version of a path as recorded by sync.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Version {
+    pub vector: VersionVector,
+    /// Device that made the last change
+    pub device: String,
+}
+
+impl Version {
+    /// The version following `previous` after a change on `device`, also including `merged` when
+    /// the change resolves a conflict with it.
+    pub fn next(previous: Option<&Self>, merged: Option<&Self>, device: &str) -> Self {
+        let mut vector = previous.map(|v| v.vector.clone()).unwrap_or_default();
+        if let Some(merged) = merged {
+            vector.merge(&merged.vector);
+        }
+        vector.increment(device);
+        Self {
+            vector,
+            device: device.to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(counters: &[(&str, u64)]) -> VersionVector {
+        let mut vector = VersionVector::default();
+        for &(device, count) in counters {
+            for _ in 0..count {
+                vector.increment(device);
+            }
+        }
+        vector
+    }
+
+    #[test]
+    fn orders_versions_by_causality() {
+        let base = vector(&[("laptop", 1)]);
+        let laptop = vector(&[("laptop", 2)]);
+        let desktop = vector(&[("laptop", 1), ("desktop", 1)]);
+
+        assert_eq!(base.compare(&base.clone()), Causality::Equal);
+        assert_eq!(base.compare(&laptop), Causality::Before);
+        assert_eq!(desktop.compare(&base), Causality::After);
+        assert_eq!(laptop.compare(&desktop), Causality::Concurrent);
+        assert_eq!(VersionVector::default().compare(&base), Causality::Before);
+    }
+
+    #[test]
+    fn merged_versions_include_both() {
+        let laptop = vector(&[("laptop", 2)]);
+        let desktop = vector(&[("laptop", 1), ("desktop", 3)]);
+        let mut merged = laptop.clone();
+        merged.merge(&desktop);
+        assert_eq!(merged, vector(&[("laptop", 2), ("desktop", 3)]));
+        assert_eq!(merged.compare(&laptop), Causality::After);
+        assert_eq!(merged.compare(&desktop), Causality::After);
+
+        let previous = Version {
+            vector: laptop,
+            device: "laptop".to_owned(),
+        };
+        let theirs = Version {
+            vector: desktop,
+            device: "desktop".to_owned(),
+        };
+        let next = Version::next(Some(&previous), Some(&theirs), "phone");
+        assert_eq!(next.device, "phone");
+        assert_eq!(next.vector.get("phone"), 1);
+        assert_eq!(next.vector.compare(&merged), Causality::After);
+    }
+}
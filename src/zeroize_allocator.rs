@@ -38,7 +38,6 @@ impl<T: GlobalAlloc> ZeroizeAllocator<T> {
     /// This function is unsafe because undefined behavior can result if the caller does not ensure all of the following:
     /// ptr must denote a block of memory currently allocated via this allocator,
     /// layout must be the same layout that was used to allocate that block of memory.
-
     #[cfg(test)]
     unsafe fn enable_dealloc(
         &mut self,
@@ -160,7 +159,7 @@ mod tests {
 
         // Zeroize the memory location
         unsafe {
-            allocator.dealloc(ptr as *mut u8, layout);
+            allocator.dealloc(ptr, layout);
         }
 
         // Memory has been zeroized
@@ -171,7 +170,7 @@ mod tests {
         // Finally deallocate the memory
         unsafe {
             allocator.enable_dealloc(Some(vec![(
-                ptr as *mut u8,
+                ptr,
                 Layout::from_size_align_unchecked(capacity, 1),
             )]));
         }